@@ -1,10 +1,15 @@
 use crate::blockchain::BlockChain;
+use crate::crypto::hash::{Hashable, H256};
 use crate::experiment::performance_counter::PERFORMANCE_COUNTER;
 use crate::experiment::transaction_generator;
+use crate::handler::new_transaction;
 use crate::miner::memory_pool::MemoryPool;
 use crate::miner::Handle as MinerHandle;
+use crate::network::relay::Handle as RelayHandle;
 use crate::network::server::Handle as ServerHandle;
-use crate::utxodb::UtxoDatabase;
+use crate::transaction::Transaction;
+use crate::utxodb::{Execution, UtxoDatabase};
+use crate::validation::TransactionResult;
 use crate::wallet::Wallet;
 
 use log::info;
@@ -23,6 +28,8 @@ pub struct Server {
     wallet: Arc<Wallet>,
     utxodb: Arc<UtxoDatabase>,
     blockchain: Arc<BlockChain>,
+    mempool: Arc<Mutex<MemoryPool>>,
+    relay: RelayHandle,
 }
 
 #[derive(Serialize)]
@@ -41,6 +48,28 @@ struct UtxoSnapshotResponse {
     checksum: String,
 }
 
+#[derive(Serialize)]
+struct TransactionSubmitResponse {
+    hash: String,
+    result: TransactionResult,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct TransactionStatusResponse {
+    hash: String,
+    status: Vec<TransactionCopyStatus>,
+}
+
+#[derive(Serialize)]
+struct TransactionCopyStatus {
+    block: String,
+    index: u32,
+    level: u64,
+    reference: u32,
+    execution: Execution,
+}
+
 #[derive(Serialize)]
 struct BlockchainSnapshotResponse {
     leaders: Vec<String>,
@@ -76,7 +105,8 @@ impl Server {
         utxodb: &Arc<UtxoDatabase>,
         _server: &ServerHandle,
         miner: &MinerHandle,
-        _mempool: &Arc<Mutex<MemoryPool>>,
+        mempool: &Arc<Mutex<MemoryPool>>,
+        relay: &RelayHandle,
         txgen_control_chan: crossbeam::Sender<transaction_generator::ControlSignal>,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
@@ -87,6 +117,8 @@ impl Server {
             wallet: Arc::clone(wallet),
             utxodb: Arc::clone(utxodb),
             blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            relay: relay.clone(),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
@@ -95,6 +127,8 @@ impl Server {
                 let wallet = Arc::clone(&server.wallet);
                 let utxodb = Arc::clone(&server.utxodb);
                 let blockchain = Arc::clone(&server.blockchain);
+                let mempool = Arc::clone(&server.mempool);
+                let relay = server.relay.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -170,6 +204,100 @@ impl Server {
                             miner.start(lambda, lazy);
                             respond_result!(req, true, "ok");
                         }
+                        "/transaction/submit" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let tx = match params.get("tx") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx");
+                                    return;
+                                }
+                            };
+                            let raw = match base64::decode(tx) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error decoding tx: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let transaction: Transaction = match bincode::deserialize(&raw) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing tx: {}", e));
+                                    return;
+                                }
+                            };
+                            let hash = transaction.hash();
+                            let result = new_transaction(transaction, &mempool, &utxodb, &relay);
+                            let resp = TransactionSubmitResponse {
+                                hash: hash.to_string(),
+                                result,
+                                message: result.to_string(),
+                            };
+                            respond_json!(req, resp);
+                        }
+                        "/transaction/status" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let hash: [u8; 32] = match hex::decode(hash) {
+                                Ok(ref v) if v.len() == 32 => {
+                                    let mut bytes = [0u8; 32];
+                                    bytes.copy_from_slice(v);
+                                    bytes
+                                }
+                                Ok(_) => {
+                                    respond_result!(req, false, "hash must be 32 bytes");
+                                    return;
+                                }
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error decoding hash: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let hash: H256 = hash.into();
+                            let status = match utxodb.status(&hash) {
+                                Ok(status) => status,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error reading transaction status: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let status = status
+                                .into_iter()
+                                .map(|s| TransactionCopyStatus {
+                                    block: s.position.block.to_string(),
+                                    index: s.position.index,
+                                    level: s.position.level,
+                                    reference: s.position.reference,
+                                    execution: s.execution,
+                                })
+                                .collect();
+                            let resp = TransactionStatusResponse {
+                                hash: hash.to_string(),
+                                status,
+                            };
+                            respond_json!(req, resp);
+                        }
                         "/miner/step" => {
                             miner.step();
                             respond_result!(req, true, "ok");
@@ -1,5 +1,5 @@
 use crate::crypto::hash::{Hashable, H256};
-// TODO: Add the address of the miner
+use crate::transaction::Address;
 
 /// The header of a block.
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Copy)]
@@ -16,6 +16,8 @@ pub struct Header {
     pub extra_content: [u8; 32],
     /// Mining difficulty of this block.
     pub difficulty: H256,
+    /// Address that receives the block reward and, for transaction blocks, the transaction fees.
+    pub miner: Address,
 }
 
 impl Header {
@@ -27,6 +29,7 @@ impl Header {
         content_merkle_root: H256,
         extra_content: [u8; 32],
         difficulty: H256,
+        miner: Address,
     ) -> Self {
         Self {
             parent,
@@ -35,6 +38,7 @@ impl Header {
             content_merkle_root,
             extra_content,
             difficulty,
+            miner,
         }
     }
 }
@@ -93,6 +97,8 @@ pub mod tests {
             0, 20, 10,
         ];
         let difficulty = (&difficulty).into();
+        let miner: H256 =
+            (&hex!("0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d")).into();
         let header = Header::new(
             parent_hash,
             timestamp,
@@ -100,13 +106,14 @@ pub mod tests {
             content_root,
             extra_content,
             difficulty,
+            miner,
         );
         header
     }
 
     pub fn sample_header_hash_should_be() -> H256 {
         let header_hash_should_be =
-            (&hex!("1d6e0f5f11248d070c6b15d103ead72a8802279c600a60b40b38510b582aacbf")).into(); // Calculated on Mar 15, 2019
+            (&hex!("85314d4ae7fd1ab8eb83c11e1591e5df05350fe9de0cc161dc758b2df39e593d")).into();
         header_hash_should_be
     }
 }
@@ -4,6 +4,7 @@ pub mod transaction;
 pub mod voter;
 use crate::crypto::hash::{Hashable, H256};
 use crate::experiment::performance_counter::PayloadSize;
+use crate::transaction::Address;
 
 /// A block in the Prism blockchain.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -20,6 +21,7 @@ pub struct Block {
 
 impl Block {
     /// Create a new block.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         parent: H256,
         timestamp: u128,
@@ -29,6 +31,7 @@ impl Block {
         content: Content,
         extra_content: [u8; 32],
         difficulty: H256,
+        miner: Address,
     ) -> Self {
         let header = header::Header::new(
             parent,
@@ -37,6 +40,7 @@ impl Block {
             content_merkle_root,
             extra_content,
             difficulty,
+            miner,
         );
         Self {
             header,
@@ -67,6 +71,31 @@ impl Hashable for Block {
     }
 }
 
+/// The header of a block, together with the hash of the content and the sortition proof. This is
+/// enough to check the PoW and the sortition proof of the block without its content.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvenHeader {
+    pub header: header::Header,
+    pub content_hash: H256,
+    pub sortition_proof: Vec<H256>,
+}
+
+impl ProvenHeader {
+    pub fn from_block(block: &Block) -> Self {
+        Self {
+            header: block.header,
+            content_hash: block.content.hash(),
+            sortition_proof: block.sortition_proof.clone(),
+        }
+    }
+}
+
+impl Hashable for ProvenHeader {
+    fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+}
+
 impl PayloadSize for Block {
     fn size(&self) -> usize {
         std::mem::size_of::<header::Header>()
@@ -144,6 +173,7 @@ pub mod tests {
             content,
             [0u8; 32],
             *config::DEFAULT_DIFFICULTY,
+            H256::default(),
         )
     }
 
@@ -169,6 +199,7 @@ pub mod tests {
             content,
             [0u8; 32],
             *config::DEFAULT_DIFFICULTY,
+            H256::default(),
         )
     }
 
@@ -188,6 +219,7 @@ pub mod tests {
             content,
             [0u8; 32],
             *config::DEFAULT_DIFFICULTY,
+            H256::default(),
         )
     }
 }
@@ -60,6 +60,7 @@ pub fn genesis() -> Block {
         BlockContent::Proposer(content),
         all_zero,
         *DEFAULT_DIFFICULTY,
+        all_zero.into(),
     )
 }
 
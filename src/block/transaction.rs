@@ -1,3 +1,5 @@
+use super::header::Header;
+use super::{Block, Content as BlockContent};
 use crate::crypto::hash::{Hashable, H256};
 use crate::crypto::merkle::MerkleTree;
 use crate::experiment::performance_counter::PayloadSize;
@@ -34,5 +36,81 @@ impl Hashable for Content {
     }
 }
 
+/// Get the short ID of a transaction, which identifies the transaction in a compact transaction
+/// block. Short IDs may collide, so a block rebuilt from them must be checked against its header.
+pub fn short_id(hash: &H256) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&hash.as_ref()[0..8]);
+    u64::from_le_bytes(raw)
+}
+
+/// A transaction block where the transactions are replaced by their short IDs, so that a peer can
+/// rebuild the block from the transactions that it already has.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    pub sortition_proof: Vec<H256>,
+    pub short_ids: Vec<u64>,
+}
+
+impl CompactBlock {
+    /// Create the compact form of a block. Returns None if it is not a transaction block.
+    pub fn from_block(block: &Block) -> Option<Self> {
+        match &block.content {
+            BlockContent::Transaction(content) => Some(Self {
+                header: block.header,
+                sortition_proof: block.sortition_proof.clone(),
+                short_ids: content
+                    .transactions
+                    .iter()
+                    .map(|t| short_id(&t.hash()))
+                    .collect(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Rebuild the block from the given transactions, which must be in the same order as the short
+    /// IDs.
+    pub fn into_block(self, transactions: Vec<Transaction>) -> Block {
+        Block::from_header(
+            self.header,
+            BlockContent::Transaction(Content::new(transactions)),
+            self.sortition_proof,
+        )
+    }
+}
+
 #[cfg(test)]
-pub mod tests {}
+pub mod tests {
+    use super::CompactBlock;
+    use crate::block::tests::transaction_block;
+    use crate::block::Content as BlockContent;
+    use crate::crypto::hash::{Hashable, H256};
+    use crate::transaction::{Output, Transaction};
+
+    #[test]
+    fn compact_block_roundtrip() {
+        let transactions: Vec<Transaction> = (1..3)
+            .map(|value| Transaction {
+                input: vec![],
+                output: vec![Output {
+                    value,
+                    recipient: H256::default(),
+                }],
+                authorization: vec![],
+                hash: Default::default(),
+            })
+            .collect();
+        let block = transaction_block(H256::default(), 0, transactions.clone());
+        let compact = CompactBlock::from_block(&block).unwrap();
+        assert_eq!(compact.short_ids.len(), 2);
+        let rebuilt = compact.into_block(transactions);
+        assert_eq!(rebuilt.hash(), block.hash());
+        assert_eq!(rebuilt.content.hash(), block.content.hash());
+        match rebuilt.content {
+            BlockContent::Transaction(_) => {}
+            _ => panic!(),
+        }
+    }
+}
@@ -66,6 +66,7 @@ pub fn genesis(chain_num: u16) -> Block {
         BlockContent::Voter(content),
         all_zero,
         *DEFAULT_DIFFICULTY,
+        all_zero.into(),
     )
 }
 
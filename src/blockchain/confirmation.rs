@@ -0,0 +1,263 @@
+use crate::crypto::hash::H256;
+use statrs::distribution::{Discrete, Poisson, Univariate};
+use std::collections::HashMap;
+
+/// The votes cast on the proposer blocks of a level.
+pub struct LevelVotes {
+    /// Number of voter chains.
+    pub voter_chains: u16,
+    /// Proposer blocks on the level, and the depth of each vote cast on them. The depth of a vote
+    /// is the number of voter blocks on the main chain from the vote to the tip, including the
+    /// vote itself.
+    pub blocks: Vec<(H256, Vec<u64>)>,
+    /// Total number of voter blocks mined on the voter chains since the votes were cast.
+    pub vote_blocks: u64,
+}
+
+impl LevelVotes {
+    /// Total number of votes cast on the level.
+    pub fn vote_count(&self) -> u16 {
+        self.blocks.iter().map(|(_, v)| v.len() as u16).sum()
+    }
+}
+
+/// A rule that decides which proposer block of a level, if any, is the leader of the level.
+pub trait ConfirmationPolicy: Send + Sync {
+    /// Select the leader given the votes on the level, or None if no block is confirmed.
+    /// `has_leader` tells whether the level currently has a leader, in which case a policy may
+    /// keep it with less confidence than it takes to confirm a new one.
+    fn leader(&self, votes: &LevelVotes, has_leader: bool) -> Option<H256>;
+}
+
+/// The confirmation policy from https://arxiv.org/abs/1810.08092. It models the adversary's
+/// mining as a Poisson process, estimates a lower confidence bound of the votes each block will
+/// keep using a Gaussian approximation, and confirms a block if no other block can catch up even
+/// with all the votes left.
+pub struct Probabilistic {
+    adversary_ratio: f32,
+    quantile_confirm: f32,
+    quantile_deconfirm: f32,
+}
+
+impl Probabilistic {
+    pub fn new(adversary_ratio: f32, quantile_confirm: f32, quantile_deconfirm: f32) -> Self {
+        Self {
+            adversary_ratio,
+            quantile_confirm,
+            quantile_deconfirm,
+        }
+    }
+}
+
+impl ConfirmationPolicy for Probabilistic {
+    fn leader(&self, votes: &LevelVotes, has_leader: bool) -> Option<H256> {
+        // we confirm with a higher confidence so we don't have false deconfirmation
+        let quantile = if has_leader {
+            self.quantile_deconfirm
+        } else {
+            self.quantile_confirm
+        };
+        let total_vote_count = votes.vote_count();
+        let mut new_leader: Option<H256> = None;
+
+        // no point in going further if less than 3/5 votes are cast
+        if total_vote_count > votes.voter_chains * 3 / 5 {
+            // calculate the average number of voter blocks mined after
+            // a vote is casted. we use this as an estimator of honest mining
+            // rate, and then derive the believed malicious mining rate
+            let avg_vote_blocks = votes.vote_blocks as f32 / f32::from(total_vote_count);
+            // expected voter depth of an adversary
+            let adversary_expected_vote_depth =
+                avg_vote_blocks / (1.0 - self.adversary_ratio) * self.adversary_ratio;
+            let poisson = Poisson::new(f64::from(adversary_expected_vote_depth)).unwrap();
+
+            // for each block calculate the lower bound on the number of votes
+            let mut votes_lcb: HashMap<&H256, f32> = HashMap::new();
+            let mut total_votes_lcb: f32 = 0.0;
+            let mut max_vote_lcb: f32 = 0.0;
+
+            for (block, depths) in &votes.blocks {
+                let mut block_votes_mean: f32 = 0.0; // mean E[X]
+                let mut block_votes_variance: f32 = 0.0; // Var[X]
+                let mut block_votes_lcb: f32 = 0.0;
+                for depth in depths.iter() {
+                    // probability that the adversary will remove this vote
+                    let mut p: f32 = 1.0 - poisson.cdf((*depth as f32 + 1.0).into()) as f32;
+                    for k in 0..(*depth as u64) {
+                        // probability that the adversary has mined k blocks
+                        let p1 = poisson.pmf(k) as f32;
+                        // probability that the adversary will overtake 'depth-k' blocks
+                        let p2 = (self.adversary_ratio / (1.0 - self.adversary_ratio))
+                            .powi((depth - k + 1) as i32);
+                        p += p1 * p2;
+                    }
+                    block_votes_mean += 1.0 - p;
+                    block_votes_variance += p * (1.0 - p);
+                }
+                // using gaussian approximation
+                let tmp = block_votes_mean - (block_votes_variance).sqrt() * quantile;
+                if tmp > 0.0 {
+                    block_votes_lcb += tmp;
+                }
+                votes_lcb.insert(block, block_votes_lcb);
+                total_votes_lcb += block_votes_lcb;
+
+                if max_vote_lcb < block_votes_lcb {
+                    max_vote_lcb = block_votes_lcb;
+                    new_leader = Some(*block);
+                }
+                // In case of a tie, choose block with lower hash.
+                if (max_vote_lcb - block_votes_lcb).abs() < std::f32::EPSILON
+                    && new_leader.is_some()
+                {
+                    // TODO: is_some required?
+                    if *block < new_leader.unwrap() {
+                        new_leader = Some(*block);
+                    }
+                }
+            }
+            // check if the lcb_vote of new_leader is bigger than second best ucb votes
+            let remaining_votes = f32::from(votes.voter_chains) - total_votes_lcb;
+
+            // if max_vote_lcb is lesser than the remaining_votes, then a private block could
+            // get the remaining votes and become the leader block
+            if max_vote_lcb <= remaining_votes || new_leader.is_none() {
+                new_leader = None;
+            } else {
+                for (p_block, _) in &votes.blocks {
+                    // if the below condition is true, then final votes on p_block could overtake new_leader
+                    if max_vote_lcb < votes_lcb.get(p_block).unwrap() + remaining_votes
+                        && *p_block != new_leader.unwrap()
+                    {
+                        new_leader = None;
+                        break;
+                    }
+                    //In case of a tie, choose block with lower hash.
+                    if (max_vote_lcb - (votes_lcb.get(p_block).unwrap() + remaining_votes)).abs()
+                        < std::f32::EPSILON
+                        && *p_block < new_leader.unwrap()
+                    {
+                        new_leader = None;
+                        break;
+                    }
+                }
+            }
+        }
+
+        new_leader
+    }
+}
+
+/// Confirms a block once more than half of the voter chains have voted for it, each with a vote
+/// at least the given number of blocks deep. Since a chain votes for one block on each level, at
+/// most one block can be confirmed.
+pub struct MajorityDeep {
+    depth: u64,
+}
+
+impl MajorityDeep {
+    pub fn new(depth: u64) -> Self {
+        Self { depth }
+    }
+}
+
+impl ConfirmationPolicy for MajorityDeep {
+    fn leader(&self, votes: &LevelVotes, _has_leader: bool) -> Option<H256> {
+        votes
+            .blocks
+            .iter()
+            .find(|(_, depths)| {
+                let deep = depths.iter().filter(|d| **d >= self.depth).count();
+                deep > usize::from(votes.voter_chains) / 2
+            })
+            .map(|(block, _)| *block)
+    }
+}
+
+/// Waits until every voter chain has voted on the level with a vote at least the given number of
+/// blocks deep, and then confirms the block with the most votes. In case of a tie, the block with
+/// the lower hash is chosen.
+pub struct AllChainsDeep {
+    depth: u64,
+}
+
+impl AllChainsDeep {
+    pub fn new(depth: u64) -> Self {
+        Self { depth }
+    }
+}
+
+impl ConfirmationPolicy for AllChainsDeep {
+    fn leader(&self, votes: &LevelVotes, _has_leader: bool) -> Option<H256> {
+        let all_deep = votes
+            .blocks
+            .iter()
+            .flat_map(|(_, depths)| depths)
+            .filter(|d| **d >= self.depth)
+            .count();
+        if all_deep < usize::from(votes.voter_chains) {
+            return None;
+        }
+        votes
+            .blocks
+            .iter()
+            .max_by(|(a, a_votes), (b, b_votes)| {
+                a_votes.len().cmp(&b_votes.len()).then_with(|| b.cmp(a))
+            })
+            .map(|(block, _)| *block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllChainsDeep, ConfirmationPolicy, LevelVotes, MajorityDeep, Probabilistic};
+    use crate::crypto::hash::H256;
+
+    #[test]
+    fn policies() {
+        let a: H256 = [1u8; 32].into();
+        let b: H256 = [2u8; 32].into();
+        let votes = |a_depths: Vec<u64>, b_depths: Vec<u64>| LevelVotes {
+            voter_chains: 5,
+            vote_blocks: a_depths.iter().chain(&b_depths).sum(),
+            blocks: vec![(a, a_depths), (b, b_depths)],
+        };
+
+        let majority = MajorityDeep::new(3);
+        assert_eq!(majority.leader(&votes(vec![3, 3], vec![9]), false), None);
+        assert_eq!(
+            majority.leader(&votes(vec![3, 3, 4], vec![1]), false),
+            Some(a)
+        );
+        assert_eq!(majority.leader(&votes(vec![3, 3, 2], vec![9]), false), None);
+
+        let all_chains = AllChainsDeep::new(3);
+        assert_eq!(
+            all_chains.leader(&votes(vec![3, 3, 4], vec![9]), false),
+            None
+        );
+        assert_eq!(
+            all_chains.leader(&votes(vec![3, 3, 4], vec![9, 2]), false),
+            None
+        );
+        assert_eq!(
+            all_chains.leader(&votes(vec![3, 3], vec![9, 5, 4]), false),
+            Some(b)
+        );
+        assert_eq!(
+            all_chains.leader(&votes(vec![3, 3], vec![9, 5]), false),
+            None
+        );
+
+        // all votes deep on one block leave no room for the adversary
+        let probabilistic = Probabilistic::new(0.1, 1.0, 1.0);
+        assert_eq!(
+            probabilistic.leader(&votes(vec![50; 5], vec![]), false),
+            Some(a)
+        );
+        assert_eq!(
+            probabilistic.leader(&votes(vec![50; 3], vec![]), false),
+            None
+        );
+    }
+}
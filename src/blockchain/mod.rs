@@ -1,12 +1,14 @@
+pub mod confirmation;
+
 use crate::block::{Block, Content};
 use crate::config::*;
 use crate::crypto::hash::{Hashable, H256};
+use confirmation::LevelVotes;
 
 use crate::experiment::performance_counter::PERFORMANCE_COUNTER;
 use bincode::{deserialize, serialize};
 use log::{debug, info, warn};
 use rocksdb::{ColumnFamilyDescriptor, Options, WriteBatch, DB};
-use statrs::distribution::{Discrete, Poisson, Univariate};
 
 use std::collections::{BTreeMap, HashMap, HashSet};
 
@@ -26,6 +28,9 @@ const PROPOSER_LEDGER_ORDER_CF: &str = "PROPOSER_LEDGER_ORDER"; // level (u64) t
                                                                 // by this level, including the leader itself. The list
                                                                 // is in the order that those blocks should live in the ledger.
 const PROPOSER_VOTE_COUNT_CF: &str = "PROPOSER_VOTE_COUNT"; // number of all votes on a block
+const VOTER_LEDGER_TIPS_CF: &str = "VOTER_LEDGER_TIPS"; // chain number (u16) to the voter block whose votes are
+                                                        // applied to PROPOSER_NODE_VOTE_CF (hash)
+const LEDGER_DIFF_CF: &str = "LEDGER_DIFF"; // sequence number (u64) to the change of the ledger (LedgerDiff)
 
 // Column family names for graph neighbors
 const PARENT_NEIGHBOR_CF: &str = "GRAPH_PARENT_NEIGHBOR"; // the proposer parent of a block
@@ -36,6 +41,62 @@ const PROPOSER_REF_NEIGHBOR_CF: &str = "GRAPH_PROPOSER_REF_NEIGHBOR";
 
 pub type Result<T> = std::result::Result<T, rocksdb::Error>;
 
+/// A change of the transaction block ledger. It is persisted together with the new ledger, so
+/// that the ledger manager can bring the UTXO set up to date after a crash. The transaction blocks
+/// confirmed on a level are always added or removed together.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LedgerDiff {
+    /// The level of the proposer ledger tip after this change.
+    pub proposer_ledger_tip: u64,
+    /// The last transaction block in the ledger after this change.
+    pub last_transaction_block: H256,
+    /// Transaction blocks added to the ledger, in ledger order.
+    pub added: Vec<H256>,
+    /// Transaction blocks removed from the ledger, in ledger order.
+    pub removed: Vec<H256>,
+    /// Level of the leader that confirmed each block in `added`.
+    pub added_levels: Vec<u64>,
+    /// Level of the leader that had confirmed each block in `removed`.
+    pub removed_levels: Vec<u64>,
+    /// Leader proposer blocks of the levels added to the ledger, in level order.
+    pub added_leaders: Vec<H256>,
+    /// Leader proposer blocks of the levels removed from the ledger, in level order.
+    pub removed_leaders: Vec<H256>,
+}
+
+/// An error when loading an existing blockchain database.
+#[derive(Debug)]
+pub enum LoadError {
+    DBError(rocksdb::Error),
+    /// The content of the database cannot be decoded, or is inconsistent, e.g. since it is
+    /// truncated or corrupted.
+    Corrupted(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::DBError(e) => e.fmt(f),
+            LoadError::Corrupted(reason) => write!(f, "corrupted database: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::DBError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rocksdb::Error> for LoadError {
+    fn from(err: rocksdb::Error) -> LoadError {
+        LoadError::DBError(err)
+    }
+}
+
 // cf_handle is a lightweight operation, it takes 44000 micro seconds to get 100000 cf handles
 
 pub struct BlockChain {
@@ -47,6 +108,7 @@ pub struct BlockChain {
     unconfirmed_proposers: Mutex<HashSet<H256>>,
     proposer_ledger_tip: Mutex<u64>,
     voter_ledger_tips: Mutex<Vec<H256>>,
+    ledger_diff_seq: Mutex<u64>,
     config: BlockchainConfig,
 }
 
@@ -76,6 +138,8 @@ impl BlockChain {
         add_cf!(VOTER_NODE_VOTED_LEVEL_CF);
         add_cf!(PROPOSER_LEADER_SEQUENCE_CF);
         add_cf!(PROPOSER_LEDGER_ORDER_CF);
+        add_cf!(VOTER_LEDGER_TIPS_CF);
+        add_cf!(LEDGER_DIFF_CF);
         add_cf!(PROPOSER_TREE_LEVEL_CF, h256_vec_append_merge);
         add_cf!(PROPOSER_NODE_VOTE_CF, vote_vec_merge);
         add_cf!(PARENT_NEIGHBOR_CF, h256_vec_append_merge);
@@ -104,6 +168,7 @@ impl BlockChain {
             unconfirmed_proposers: Mutex::new(HashSet::new()),
             proposer_ledger_tip: Mutex::new(0),
             voter_ledger_tips: Mutex::new(vec![H256::default(); config.voter_chains as usize]),
+            ledger_diff_seq: Mutex::new(0),
             config,
         };
 
@@ -129,6 +194,7 @@ impl BlockChain {
         let proposer_ledger_order_cf = db.db.cf_handle(PROPOSER_LEDGER_ORDER_CF).unwrap();
         let proposer_ref_neighbor_cf = db.db.cf_handle(PROPOSER_REF_NEIGHBOR_CF).unwrap();
         let transaction_ref_neighbor_cf = db.db.cf_handle(TRANSACTION_REF_NEIGHBOR_CF).unwrap();
+        let voter_ledger_tips_cf = db.db.cf_handle(VOTER_LEDGER_TIPS_CF).unwrap();
 
         // insert genesis blocks
         let mut wb = WriteBatch::default();
@@ -216,6 +282,11 @@ impl BlockChain {
             voter_best.0 = db.config.voter_genesis[chain_num as usize];
             drop(voter_best);
             voter_ledger_tips[chain_num as usize] = db.config.voter_genesis[chain_num as usize];
+            wb.put_cf(
+                voter_ledger_tips_cf,
+                serialize(&(chain_num as u16)).unwrap(),
+                serialize(&db.config.voter_genesis[chain_num as usize]).unwrap(),
+            )?;
         }
         drop(voter_ledger_tips);
         db.db.write(wb)?;
@@ -223,6 +294,196 @@ impl BlockChain {
         Ok(db)
     }
 
+    /// Open the existing database at the given path, and recover the metadata fields from the
+    /// content of the database.
+    pub fn load<P: AsRef<std::path::Path>>(
+        path: P,
+        config: BlockchainConfig,
+    ) -> std::result::Result<Self, LoadError> {
+        let db = Self::open(&path, config)?;
+        // decode the content of the database, which may be truncated or corrupted
+        macro_rules! decode {
+            ($data:expr, $what:expr) => {{
+                deserialize($data)
+                    .map_err(|e| LoadError::Corrupted(format!("cannot decode {}: {}", $what, e)))?
+            }};
+        }
+        // get cf handles
+        let proposer_node_level_cf = db.db.cf_handle(PROPOSER_NODE_LEVEL_CF).unwrap();
+        let voter_node_level_cf = db.db.cf_handle(VOTER_NODE_LEVEL_CF).unwrap();
+        let voter_node_chain_cf = db.db.cf_handle(VOTER_NODE_CHAIN_CF).unwrap();
+        let proposer_tree_level_cf = db.db.cf_handle(PROPOSER_TREE_LEVEL_CF).unwrap();
+        let parent_neighbor_cf = db.db.cf_handle(PARENT_NEIGHBOR_CF).unwrap();
+        let proposer_ledger_order_cf = db.db.cf_handle(PROPOSER_LEDGER_ORDER_CF).unwrap();
+        let proposer_ref_neighbor_cf = db.db.cf_handle(PROPOSER_REF_NEIGHBOR_CF).unwrap();
+        let transaction_ref_neighbor_cf = db.db.cf_handle(TRANSACTION_REF_NEIGHBOR_CF).unwrap();
+        let voter_ledger_tips_cf = db.db.cf_handle(VOTER_LEDGER_TIPS_CF).unwrap();
+        let ledger_diff_cf = db.db.cf_handle(LEDGER_DIFF_CF).unwrap();
+
+        // proposer best level. the levels of the proposer tree are continuous, so we stop at the
+        // first level that is missing
+        let mut proposer_best_level: u64 = 0;
+        for level in 0u64.. {
+            match db
+                .db
+                .get_pinned_cf(proposer_tree_level_cf, serialize(&level).unwrap())?
+            {
+                Some(_) => proposer_best_level = level,
+                None => break,
+            }
+        }
+        *db.proposer_best_level.lock().unwrap() = proposer_best_level;
+        PERFORMANCE_COUNTER.record_update_proposer_main_chain(proposer_best_level as usize);
+
+        // voter best blocks
+        let mut voter_best: Vec<(H256, u64)> =
+            db.config.voter_genesis.iter().map(|h| (*h, 0)).collect();
+        for (k, v) in db
+            .db
+            .iterator_cf(voter_node_chain_cf, rocksdb::IteratorMode::Start)?
+        {
+            let hash: H256 = decode!(k.as_ref(), "voter block hash");
+            let chain: u16 = decode!(v.as_ref(), "voter chain number");
+            if chain >= db.config.voter_chains {
+                return Err(LoadError::Corrupted(format!(
+                    "voter block {} is on chain {} that does not exist",
+                    hash, chain
+                )));
+            }
+            let level: u64 = match db.db.get_pinned_cf(voter_node_level_cf, k.as_ref())? {
+                Some(d) => decode!(&d, "voter block level"),
+                None => {
+                    return Err(LoadError::Corrupted(format!(
+                        "voter block {} has no level",
+                        hash
+                    )))
+                }
+            };
+            if level > voter_best[chain as usize].1 {
+                voter_best[chain as usize] = (hash, level);
+            }
+        }
+        for (chain_num, best) in voter_best.into_iter().enumerate() {
+            PERFORMANCE_COUNTER.record_update_voter_main_chain(0, best.1 as usize);
+            *db.voter_best[chain_num].lock().unwrap() = best;
+        }
+
+        // voter ledger tips
+        let mut voter_ledger_tips = db.voter_ledger_tips.lock().unwrap();
+        for chain_num in 0..db.config.voter_chains {
+            voter_ledger_tips[chain_num as usize] = match db.db.get_pinned_cf(
+                voter_ledger_tips_cf,
+                serialize(&(chain_num as u16)).unwrap(),
+            )? {
+                Some(d) => decode!(&d, "voter ledger tip"),
+                None => {
+                    return Err(LoadError::Corrupted(format!(
+                        "voter chain {} has no ledger tip",
+                        chain_num
+                    )))
+                }
+            };
+        }
+        drop(voter_ledger_tips);
+
+        // proposer ledger tip and the set of confirmed proposer blocks. the ledger is continuous,
+        // so we stop at the first level that is missing
+        let mut confirmed_proposers: HashSet<H256> = HashSet::new();
+        let mut proposer_ledger_tip: u64 = 0;
+        for level in 0u64.. {
+            match db
+                .db
+                .get_pinned_cf(proposer_ledger_order_cf, serialize(&level).unwrap())?
+            {
+                Some(d) => {
+                    let blocks: Vec<H256> = decode!(&d, "proposer ledger order");
+                    confirmed_proposers.extend(blocks);
+                    proposer_ledger_tip = level;
+                }
+                None => break,
+            }
+        }
+        *db.proposer_ledger_tip.lock().unwrap() = proposer_ledger_tip;
+
+        // unconfirmed and unreferred proposer blocks
+        let mut referred_proposers: HashSet<H256> = HashSet::new();
+        for (_, v) in db
+            .db
+            .iterator_cf(proposer_ref_neighbor_cf, rocksdb::IteratorMode::Start)?
+        {
+            let refs: Vec<H256> = decode!(v.as_ref(), "proposer references");
+            referred_proposers.extend(refs);
+        }
+        let mut unconfirmed_proposers = db.unconfirmed_proposers.lock().unwrap();
+        let mut unreferred_proposers = db.unreferred_proposers.lock().unwrap();
+        for (k, _) in db
+            .db
+            .iterator_cf(proposer_node_level_cf, rocksdb::IteratorMode::Start)?
+        {
+            let hash: H256 = decode!(k.as_ref(), "proposer block hash");
+            if !confirmed_proposers.contains(&hash) {
+                unconfirmed_proposers.insert(hash);
+            }
+            if !referred_proposers.contains(&hash) {
+                unreferred_proposers.insert(hash);
+            }
+        }
+        drop(unconfirmed_proposers);
+        drop(unreferred_proposers);
+
+        // unreferred transaction blocks. transaction blocks are the blocks that have a parent but
+        // are neither proposer nor voter blocks
+        let mut referred_transactions: HashSet<H256> = HashSet::new();
+        for (_, v) in db
+            .db
+            .iterator_cf(transaction_ref_neighbor_cf, rocksdb::IteratorMode::Start)?
+        {
+            let refs: Vec<H256> = decode!(v.as_ref(), "transaction references");
+            referred_transactions.extend(refs);
+        }
+        let mut unreferred_transactions = db.unreferred_transactions.lock().unwrap();
+        for (k, _) in db
+            .db
+            .iterator_cf(parent_neighbor_cf, rocksdb::IteratorMode::Start)?
+        {
+            if db
+                .db
+                .get_pinned_cf(proposer_node_level_cf, k.as_ref())?
+                .is_some()
+                || db
+                    .db
+                    .get_pinned_cf(voter_node_level_cf, k.as_ref())?
+                    .is_some()
+            {
+                continue;
+            }
+            let hash: H256 = decode!(k.as_ref(), "block hash");
+            if !referred_transactions.contains(&hash) {
+                unreferred_transactions.insert(hash);
+            }
+        }
+        drop(unreferred_transactions);
+
+        // sequence number of the last ledger diff. the ledger manager never prunes the last one
+        let mut ledger_diff_seq: u64 = 0;
+        for (k, _) in db
+            .db
+            .iterator_cf(ledger_diff_cf, rocksdb::IteratorMode::Start)?
+        {
+            let seq: u64 = decode!(k.as_ref(), "ledger diff sequence number");
+            if seq > ledger_diff_seq {
+                ledger_diff_seq = seq;
+            }
+        }
+        *db.ledger_diff_seq.lock().unwrap() = ledger_diff_seq;
+
+        info!(
+            "Loaded blockchain database with proposer best level {} and ledger tip {}",
+            proposer_best_level, proposer_ledger_tip
+        );
+        Ok(db)
+    }
+
     /// Insert a new block into the ledger. Returns the list of added transaction blocks and
     /// removed transaction blocks.
     pub fn insert_block(&self, block: &Block) -> Result<()> {
@@ -418,6 +679,8 @@ impl BlockChain {
         let proposer_ledger_order_cf = self.db.cf_handle(PROPOSER_LEDGER_ORDER_CF).unwrap();
         let proposer_ref_neighbor_cf = self.db.cf_handle(PROPOSER_REF_NEIGHBOR_CF).unwrap();
         let transaction_ref_neighbor_cf = self.db.cf_handle(TRANSACTION_REF_NEIGHBOR_CF).unwrap();
+        let voter_ledger_tips_cf = self.db.cf_handle(VOTER_LEDGER_TIPS_CF).unwrap();
+        let ledger_diff_cf = self.db.cf_handle(LEDGER_DIFF_CF).unwrap();
 
         macro_rules! get_value {
             ($cf:expr, $key:expr) => {{
@@ -428,14 +691,29 @@ impl BlockChain {
             }};
         }
 
-        // apply the vote diff while tracking the votes of which proposer levels are affected
+        // the votes, the leaders, the ledger and its diff are committed in a single write batch,
+        // so that a crash never leaves the leaders or the ledger behind the votes
         let mut wb = WriteBatch::default();
         macro_rules! merge_value {
             ($cf:expr, $key:expr, $value:expr) => {{
                 wb.merge_cf($cf, serialize(&$key).unwrap(), serialize(&$value).unwrap())?;
             }};
         }
+        // the votes on the proposer blocks as of the write batch, since the leaders are computed
+        // before the batch is committed
+        let mut votes: HashMap<H256, Vec<(u16, u64)>> = HashMap::new();
+        macro_rules! apply_vote {
+            ($block:expr, $operation:expr) => {{
+                if !votes.contains_key(&$block) {
+                    let existing: Vec<(u16, u64)> =
+                        get_value!(proposer_node_vote_cf, $block).unwrap_or_default();
+                    votes.insert($block, existing);
+                }
+                apply_vote_operation(votes.get_mut(&$block).unwrap(), $operation);
+            }};
+        }
 
+        // apply the vote diff while tracking the votes of which proposer levels are affected
         let mut voter_ledger_tips = self.voter_ledger_tips.lock().unwrap();
         let mut affected_range: Range<u64> = Range {
             start: std::u64::MAX,
@@ -449,6 +727,13 @@ impl BlockChain {
             let to = voter_best.0;
             drop(voter_best);
             voter_ledger_tips[chain_num as usize] = to;
+            // persist the tip together with the votes, so that we know which votes have been
+            // applied when loading the database
+            wb.put_cf(
+                voter_ledger_tips_cf,
+                serialize(&(chain_num as u16)).unwrap(),
+                serialize(&to).unwrap(),
+            )?;
 
             let (added, removed) = self.vote_diff(from, to)?;
 
@@ -459,6 +744,7 @@ impl BlockChain {
                     vote.0,
                     (false, chain_num as u16, vote.1)
                 );
+                apply_vote!(vote.0, (false, chain_num as u16, vote.1));
                 let proposer_level: u64 = get_value!(proposer_node_level_cf, vote.0).unwrap();
                 if proposer_level < affected_range.start {
                     affected_range.start = proposer_level;
@@ -474,6 +760,7 @@ impl BlockChain {
                     vote.0,
                     (true, chain_num as u16, vote.1)
                 );
+                apply_vote!(vote.0, (true, chain_num as u16, vote.1));
                 let proposer_level: u64 = get_value!(proposer_node_level_cf, vote.0).unwrap();
                 if proposer_level < affected_range.start {
                     affected_range.start = proposer_level;
@@ -484,18 +771,8 @@ impl BlockChain {
             }
         }
         drop(voter_ledger_tips);
-        // commit the votes into the database
-        self.db.write(wb)?;
 
         // recompute the leader of each level that was affected
-        let mut wb = WriteBatch::default();
-        /*
-        macro_rules! merge_value {
-            ($cf:expr, $key:expr, $value:expr) => {{
-                wb.merge_cf($cf, serialize(&$key).unwrap(), serialize(&$value).unwrap())?;
-            }};
-        }
-        */
         macro_rules! put_value {
             ($cf:expr, $key:expr, $value:expr) => {{
                 wb.put_cf($cf, serialize(&$key).unwrap(), serialize(&$value).unwrap())?;
@@ -520,23 +797,14 @@ impl BlockChain {
 
         // start actually recomputing the leaders
         let mut change_begin: Option<u64> = None;
+        // leaders that are changed in the write batch
+        let mut new_leaders: HashMap<u64, Option<H256>> = HashMap::new();
 
         for level in affected_range {
             let existing_leader: Option<H256> =
                 get_value!(proposer_leader_sequence_cf, level as u64);
-            let new_leader_confirm: Option<H256> =
-                self.proposer_leader(level as u64, self.config.quantile_epsilon_confirm)?;
-            let new_leader_deconfirm: Option<H256> =
-                self.proposer_leader(level as u64, self.config.quantile_epsilon_deconfirm)?;
-
-            // we confirm with a higher confidence so we don't have false deconfirmation
-            let new_leader = {
-                if existing_leader.is_some() {
-                    new_leader_deconfirm
-                } else {
-                    new_leader_confirm
-                }
-            };
+            let new_leader: Option<H256> =
+                self.proposer_leader(level as u64, existing_leader.is_some(), &votes)?;
 
             if new_leader != existing_leader {
                 match new_leader {
@@ -550,39 +818,21 @@ impl BlockChain {
                 if change_begin.is_none() {
                     change_begin = Some(level);
                 }
+                new_leaders.insert(level, new_leader);
                 match new_leader {
                     None => delete_value!(proposer_leader_sequence_cf, level as u64),
                     Some(new) => put_value!(proposer_leader_sequence_cf, level as u64, new),
                 };
             }
         }
-        // commit the new leaders into the database
-        self.db.write(wb)?;
-
         // recompute the ledger from the first level whose leader changed
         if let Some(change_begin) = change_begin {
             let mut proposer_ledger_tip = self.proposer_ledger_tip.lock().unwrap();
             let mut unconfirmed_proposers = self.unconfirmed_proposers.lock().unwrap();
-            let mut removed: Vec<H256> = vec![];
-            let mut added: Vec<H256> = vec![];
-            let mut wb = WriteBatch::default();
-            /*
-            macro_rules! merge_value {
-                ($cf:expr, $key:expr, $value:expr) => {{
-                    wb.merge_cf($cf, serialize(&$key).unwrap(), serialize(&$value).unwrap())?;
-                }};
-            }
-            */
-            macro_rules! put_value {
-                ($cf:expr, $key:expr, $value:expr) => {{
-                    wb.put_cf($cf, serialize(&$key).unwrap(), serialize(&$value).unwrap())?;
-                }};
-            }
-            macro_rules! delete_value {
-                ($cf:expr, $key:expr) => {{
-                    wb.delete_cf($cf, serialize(&$key).unwrap())?;
-                }};
-            }
+            let mut removed: Vec<(H256, u64)> = vec![];
+            let mut added: Vec<(H256, u64)> = vec![];
+            let mut removed_leaders: Vec<H256> = vec![];
+            let mut added_leaders: Vec<H256> = vec![];
 
             // deconfirm the blocks from change_begin all the way to previous ledger tip
             for level in change_begin..=*proposer_ledger_tip {
@@ -591,21 +841,30 @@ impl BlockChain {
                 delete_value!(proposer_ledger_order_cf, level as u64);
                 for block in &original_ledger {
                     unconfirmed_proposers.insert(*block);
-                    removed.push(*block);
+                    removed.push((*block, level));
                 }
+                // the leader sequence in the database is not updated until the batch is committed
+                let original_leader: H256 =
+                    get_value!(proposer_leader_sequence_cf, level as u64).unwrap();
+                removed_leaders.push(original_leader);
             }
 
             // recompute the ledger from change_begin until the first level where there's no leader
             // make sure that the ledger is continuous
             if change_begin <= *proposer_ledger_tip + 1 {
                 for level in change_begin.. {
-                    let leader: H256 = match get_value!(proposer_leader_sequence_cf, level as u64) {
+                    let leader: Option<H256> = match new_leaders.get(&level) {
+                        Some(leader) => *leader,
+                        None => get_value!(proposer_leader_sequence_cf, level as u64),
+                    };
+                    let leader: H256 = match leader {
                         None => {
                             *proposer_ledger_tip = level - 1;
                             break;
                         }
                         Some(leader) => leader,
                     };
+                    added_leaders.push(leader);
                     // Get the sequence of blocks by doing a depth-first traverse
                     let mut order: Vec<H256> = vec![];
                     let mut stack: Vec<H256> = vec![leader];
@@ -634,29 +893,131 @@ impl BlockChain {
                         .filter(|h| unconfirmed_proposers.remove(h))
                         .collect();
                     put_value!(proposer_ledger_order_cf, level as u64, order);
-                    added.extend(&order);
+                    added.extend(order.iter().map(|h| (*h, level)));
                 }
             }
-            // commit the new ledger into the database
-            self.db.write(wb)?;
 
             let mut removed_transaction_blocks: Vec<H256> = vec![];
             let mut added_transaction_blocks: Vec<H256> = vec![];
-            for block in &removed {
+            let mut removed_levels: Vec<u64> = vec![];
+            let mut added_levels: Vec<u64> = vec![];
+            for (block, level) in &removed {
                 let t: Vec<H256> = get_value!(transaction_ref_neighbor_cf, block).unwrap();
+                removed_levels.extend(t.iter().map(|_| level));
                 removed_transaction_blocks.extend(&t);
             }
-            for block in &added {
+            for (block, level) in &added {
                 let t: Vec<H256> = get_value!(transaction_ref_neighbor_cf, block).unwrap();
+                added_levels.extend(t.iter().map(|_| level));
                 added_transaction_blocks.extend(&t);
             }
+
+            // record the change of the ledger in the same write batch as the new ledger. the
+            // sequence number is only taken once the batch is committed, so that a failed write
+            // leaves no gap in the sequence
+            let mut ledger_diff_seq = self.ledger_diff_seq.lock().unwrap();
+            let mut new_ledger_diff_seq = *ledger_diff_seq;
+            if !(removed_transaction_blocks.is_empty()
+                && added_transaction_blocks.is_empty()
+                && removed_leaders.is_empty()
+                && added_leaders.is_empty())
+            {
+                let last_transaction_block = match added_transaction_blocks.last() {
+                    Some(h) => *h,
+                    None => self.last_transaction_block(change_begin)?,
+                };
+                let diff = LedgerDiff {
+                    proposer_ledger_tip: *proposer_ledger_tip,
+                    last_transaction_block,
+                    added: added_transaction_blocks.clone(),
+                    removed: removed_transaction_blocks.clone(),
+                    added_levels,
+                    removed_levels,
+                    added_leaders,
+                    removed_leaders,
+                };
+                new_ledger_diff_seq += 1;
+                put_value!(ledger_diff_cf, new_ledger_diff_seq, diff);
+            }
+
+            // commit the votes, the leaders and the new ledger into the database
+            self.db.write(wb)?;
+            *ledger_diff_seq = new_ledger_diff_seq;
+            drop(ledger_diff_seq);
             Ok((added_transaction_blocks, removed_transaction_blocks))
         } else {
+            // commit the votes into the database
+            self.db.write(wb)?;
             Ok((vec![], vec![]))
         }
     }
 
-    fn proposer_leader(&self, level: u64, quantile: f32) -> Result<Option<H256>> {
+    /// Get the last transaction block in the ledger below the given proposer level.
+    fn last_transaction_block(&self, below: u64) -> Result<H256> {
+        let proposer_ledger_order_cf = self.db.cf_handle(PROPOSER_LEDGER_ORDER_CF).unwrap();
+        let transaction_ref_neighbor_cf = self.db.cf_handle(TRANSACTION_REF_NEIGHBOR_CF).unwrap();
+        for level in (0..below).rev() {
+            let blocks: Vec<H256> = match self
+                .db
+                .get_pinned_cf(proposer_ledger_order_cf, serialize(&level).unwrap())?
+            {
+                Some(d) => deserialize(&d).unwrap(),
+                None => continue,
+            };
+            for block in blocks.iter().rev() {
+                let refs: Vec<H256> = deserialize(
+                    &self
+                        .db
+                        .get_pinned_cf(transaction_ref_neighbor_cf, serialize(&block).unwrap())?
+                        .unwrap(),
+                )
+                .unwrap();
+                if let Some(h) = refs.last() {
+                    return Ok(*h);
+                }
+            }
+        }
+        Ok(H256::default())
+    }
+
+    /// Get the change of the ledger with the given sequence number.
+    pub fn ledger_diff(&self, seq: u64) -> Result<Option<LedgerDiff>> {
+        let ledger_diff_cf = self.db.cf_handle(LEDGER_DIFF_CF).unwrap();
+        match self
+            .db
+            .get_pinned_cf(ledger_diff_cf, serialize(&seq).unwrap())?
+        {
+            Some(d) => Ok(Some(deserialize(&d).unwrap())),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete the changes of the ledger whose sequence numbers are smaller than the given one.
+    pub fn prune_ledger_diffs(&self, before: u64) -> Result<()> {
+        let ledger_diff_cf = self.db.cf_handle(LEDGER_DIFF_CF).unwrap();
+        let mut wb = WriteBatch::default();
+        for (k, _) in self
+            .db
+            .iterator_cf(ledger_diff_cf, rocksdb::IteratorMode::Start)?
+        {
+            let seq: u64 = deserialize(k.as_ref()).unwrap();
+            if seq < before {
+                wb.delete_cf(ledger_diff_cf, k)?;
+            }
+        }
+        self.db.write(wb)?;
+        Ok(())
+    }
+
+    /// Select the leader of the given level, or None if no block is confirmed, with the
+    /// confirmation policy in the config. The given votes are not committed yet, and take
+    /// precedence over the votes in the database.
+    fn proposer_leader(
+        &self,
+        level: u64,
+        has_leader: bool,
+        pending_votes: &HashMap<H256, Vec<(u16, u64)>>,
+    ) -> Result<Option<H256>> {
         let proposer_node_vote_cf = self.db.cf_handle(PROPOSER_NODE_VOTE_CF).unwrap();
         let proposer_tree_level_cf = self.db.cf_handle(PROPOSER_TREE_LEVEL_CF).unwrap();
 
@@ -669,12 +1030,9 @@ impl BlockChain {
             }};
         }
         let proposer_blocks: Vec<H256> = get_value!(proposer_tree_level_cf, level as u64).unwrap();
-        // compute the new leader of this level
-        // we use the confirmation policy from https://arxiv.org/abs/1810.08092
-        let mut new_leader: Option<H256> = None;
 
         // collect the depth of each vote on each proposer block
-        let mut votes_depth: HashMap<&H256, Vec<u64>> = HashMap::new(); // chain number and vote depth casted on the proposer block
+        let mut votes_depth: Vec<(H256, Vec<u64>)> = vec![]; // vote depth casted on the proposer block
 
         // collect the total votes on all proposer blocks, and the number of
         // voter blocks mined after those votes are casted
@@ -682,9 +1040,9 @@ impl BlockChain {
         let mut total_vote_blocks: u64 = 0;
 
         for block in &proposer_blocks {
-            let votes: Vec<(u16, u64)> = match get_value!(proposer_node_vote_cf, block) {
-                None => vec![],
-                Some(d) => d,
+            let votes: Vec<(u16, u64)> = match pending_votes.get(block) {
+                Some(v) => v.clone(),
+                None => get_value!(proposer_node_vote_cf, block).unwrap_or_default(),
             };
             let mut vote_depth: Vec<u64> = vec![];
             for (chain_num, vote_level) in &votes {
@@ -699,7 +1057,7 @@ impl BlockChain {
                 let this_depth = voter_best_level - vote_level + 1;
                 vote_depth.push(this_depth);
             }
-            votes_depth.insert(block, vote_depth);
+            votes_depth.push((*block, vote_depth));
         }
 
         // For debugging purpose only. This is very important for security.
@@ -711,94 +1069,13 @@ impl BlockChain {
             )
         }
 
-        // no point in going further if less than 3/5 votes are cast
-        if total_vote_count > self.config.voter_chains * 3 / 5 {
-            // calculate the average number of voter blocks mined after
-            // a vote is casted. we use this as an estimator of honest mining
-            // rate, and then derive the believed malicious mining rate
-            let avg_vote_blocks = total_vote_blocks as f32 / f32::from(total_vote_count);
-            // expected voter depth of an adversary
-            let adversary_expected_vote_depth =
-                avg_vote_blocks / (1.0 - self.config.adversary_ratio) * self.config.adversary_ratio;
-            let poisson = Poisson::new(f64::from(adversary_expected_vote_depth)).unwrap();
-
-            // for each block calculate the lower bound on the number of votes
-            let mut votes_lcb: HashMap<&H256, f32> = HashMap::new();
-            let mut total_votes_lcb: f32 = 0.0;
-            let mut max_vote_lcb: f32 = 0.0;
-
-            for block in &proposer_blocks {
-                let votes = votes_depth.get(block).unwrap();
-
-                let mut block_votes_mean: f32 = 0.0; // mean E[X]
-                let mut block_votes_variance: f32 = 0.0; // Var[X]
-                let mut block_votes_lcb: f32 = 0.0;
-                for depth in votes.iter() {
-                    // probability that the adversary will remove this vote
-                    let mut p: f32 = 1.0 - poisson.cdf((*depth as f32 + 1.0).into()) as f32;
-                    for k in 0..(*depth as u64) {
-                        // probability that the adversary has mined k blocks
-                        let p1 = poisson.pmf(k) as f32;
-                        // probability that the adversary will overtake 'depth-k' blocks
-                        let p2 = (self.config.adversary_ratio
-                            / (1.0 - self.config.adversary_ratio))
-                            .powi((depth - k + 1) as i32);
-                        p += p1 * p2;
-                    }
-                    block_votes_mean += 1.0 - p;
-                    block_votes_variance += p * (1.0 - p);
-                }
-                // using gaussian approximation
-                let tmp = block_votes_mean - (block_votes_variance).sqrt() * quantile;
-                if tmp > 0.0 {
-                    block_votes_lcb += tmp;
-                }
-                votes_lcb.insert(block, block_votes_lcb);
-                total_votes_lcb += block_votes_lcb;
-
-                if max_vote_lcb < block_votes_lcb {
-                    max_vote_lcb = block_votes_lcb;
-                    new_leader = Some(*block);
-                }
-                // In case of a tie, choose block with lower hash.
-                if (max_vote_lcb - block_votes_lcb).abs() < std::f32::EPSILON
-                    && new_leader.is_some()
-                {
-                    // TODO: is_some required?
-                    if *block < new_leader.unwrap() {
-                        new_leader = Some(*block);
-                    }
-                }
-            }
-            // check if the lcb_vote of new_leader is bigger than second best ucb votes
-            let remaining_votes = f32::from(self.config.voter_chains) - total_votes_lcb;
-
-            // if max_vote_lcb is lesser than the remaining_votes, then a private block could
-            // get the remaining votes and become the leader block
-            if max_vote_lcb <= remaining_votes || new_leader.is_none() {
-                new_leader = None;
-            } else {
-                for p_block in &proposer_blocks {
-                    // if the below condition is true, then final votes on p_block could overtake new_leader
-                    if max_vote_lcb < votes_lcb.get(p_block).unwrap() + remaining_votes
-                        && *p_block != new_leader.unwrap()
-                    {
-                        new_leader = None;
-                        break;
-                    }
-                    //In case of a tie, choose block with lower hash.
-                    if (max_vote_lcb - (votes_lcb.get(p_block).unwrap() + remaining_votes)).abs()
-                        < std::f32::EPSILON
-                        && *p_block < new_leader.unwrap()
-                    {
-                        new_leader = None;
-                        break;
-                    }
-                }
-            }
-        }
-
-        Ok(new_leader)
+        // compute the new leader of this level using the configured confirmation policy
+        let votes = LevelVotes {
+            voter_chains: self.config.voter_chains,
+            blocks: votes_depth,
+            vote_blocks: total_vote_blocks,
+        };
+        Ok(self.config.confirmation_policy.leader(&votes, has_leader))
     }
 
     fn num_voter_blocks(&self, chain: u16, start_level: u64, end_level: u64) -> Result<u64> {
@@ -899,6 +1176,11 @@ impl BlockChain {
         Ok(blocks[0])
     }
 
+    /// Get the level of the best proposer block.
+    pub fn best_proposer_level(&self) -> u64 {
+        *self.proposer_best_level.lock().unwrap()
+    }
+
     pub fn best_voter(&self, chain_num: usize) -> H256 {
         let voter_best = self.voter_best[chain_num].lock().unwrap();
         let hash = voter_best.0;
@@ -1601,24 +1883,28 @@ fn vote_vec_merge(
     for op in operands {
         // parse the operation as add(true)/remove(false), chain(u16), level(u64)
         let operation: (bool, u16, u64) = deserialize(op).unwrap();
-        match operation.0 {
-            true => {
-                if !existing.contains(&(operation.1, operation.2)) {
-                    existing.push((operation.1, operation.2));
-                }
-            }
-            false => {
-                match existing.iter().position(|&x| x.0 == operation.1) {
-                    Some(p) => existing.swap_remove(p),
-                    None => continue, // TODO: potential bug here - what if we delete a nonexisting item
-                };
-            }
-        }
+        apply_vote_operation(&mut existing, operation);
     }
     let result: Vec<u8> = serialize(&existing).unwrap();
     Some(result)
 }
 
+/// Apply an operation of the vote merge operator to the votes on a proposer block.
+fn apply_vote_operation(existing: &mut Vec<(u16, u64)>, operation: (bool, u16, u64)) {
+    match operation.0 {
+        true => {
+            if !existing.contains(&(operation.1, operation.2)) {
+                existing.push((operation.1, operation.2));
+            }
+        }
+        false => {
+            if let Some(p) = existing.iter().position(|&x| x.0 == operation.1) {
+                existing.swap_remove(p);
+            } // TODO: potential bug here - what if we delete a nonexisting item
+        }
+    }
+}
+
 fn h256_vec_append_merge(
     _: &[u8],
     existing_val: Option<&[u8]>,
@@ -1655,6 +1941,89 @@ fn u64_plus_merge(
     Some(result)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{BlockChain, LoadError, VOTER_LEDGER_TIPS_CF};
+    use crate::block::tests::{proposer_block, transaction_block, voter_block};
+    use crate::blockchain::confirmation::MajorityDeep;
+    use crate::config::BlockchainConfig;
+    use crate::crypto::hash::{Hashable, H256};
+    use bincode::serialize;
+    use std::sync::Arc;
+
+    /// Everything that is recovered from the database when loading it.
+    #[derive(Debug, PartialEq)]
+    struct State {
+        proposer_best_level: u64,
+        voter_best: H256,
+        leaders: Vec<H256>,
+        proposer_ledger_tip: u64,
+        voter_ledger_tips: Vec<H256>,
+        ledger_diff_seq: u64,
+        unreferred_proposers: Vec<H256>,
+        unreferred_transactions: Vec<H256>,
+    }
+
+    fn state(chain: &BlockChain) -> State {
+        let mut unreferred_proposers = chain.unreferred_proposers();
+        unreferred_proposers.sort();
+        let mut unreferred_transactions = chain.unreferred_transactions();
+        unreferred_transactions.sort();
+        State {
+            proposer_best_level: chain.best_proposer_level(),
+            voter_best: chain.best_voter(0),
+            leaders: chain.proposer_leaders().unwrap(),
+            proposer_ledger_tip: *chain.proposer_ledger_tip.lock().unwrap(),
+            voter_ledger_tips: chain.voter_ledger_tips.lock().unwrap().clone(),
+            ledger_diff_seq: *chain.ledger_diff_seq.lock().unwrap(),
+            unreferred_proposers,
+            unreferred_transactions,
+        }
+    }
+
+    #[test]
+    fn load() {
+        let mut config = BlockchainConfig::new(1, 64000, 38, 0.1, 0.1, 0.4, 20.0);
+        config.confirmation_policy = Arc::new(MajorityDeep::new(1));
+        let path = "/tmp/prism_test_blockchain_load.rocksdb";
+        let chain = BlockChain::new(path, config.clone()).unwrap();
+
+        // two proposer levels, both confirmed by the voter chain, and an unreferred transaction
+        // block
+        let genesis = config.proposer_genesis;
+        let t1 = transaction_block(genesis, 1, vec![]);
+        let p1 = proposer_block(genesis, 2, vec![], vec![t1.hash()]);
+        let p2 = proposer_block(p1.hash(), 3, vec![], vec![]);
+        let v1 = voter_block(p1.hash(), 4, 0, config.voter_genesis[0], vec![p1.hash()]);
+        let v2 = voter_block(p2.hash(), 5, 0, v1.hash(), vec![p2.hash()]);
+        let t2 = transaction_block(p2.hash(), 6, vec![]);
+        for block in &[t1, p1, p2, v1, v2, t2] {
+            chain.insert_block(block).unwrap();
+        }
+        chain.update_ledger().unwrap();
+        let before = state(&chain);
+        assert_eq!(before.proposer_best_level, 2);
+        assert_eq!(before.leaders.len(), 3);
+        assert_eq!(before.unreferred_transactions.len(), 1);
+        drop(chain);
+
+        let chain = BlockChain::load(path, config.clone()).unwrap();
+        assert_eq!(state(&chain), before);
+
+        // a corrupted ledger tip is reported instead of panicking
+        let voter_ledger_tips_cf = chain.db.cf_handle(VOTER_LEDGER_TIPS_CF).unwrap();
+        chain
+            .db
+            .put_cf(voter_ledger_tips_cf, serialize(&0u16).unwrap(), [1u8, 2])
+            .unwrap();
+        drop(chain);
+        match BlockChain::load(path, config) {
+            Err(LoadError::Corrupted(_)) => {}
+            _ => panic!("a corrupted database should not load"),
+        }
+    }
+}
+
 /*
 #[cfg(test)]
 mod tests {
@@ -1820,6 +2189,7 @@ mod tests {
             new_proposer_content,
             [0; 32],
             H256::default(),
+            H256::default(),
         );
         db.insert_block(&new_proposer_block).unwrap();
         let new_voter_content = Content::Voter(voter::Content::new(
@@ -1836,6 +2206,7 @@ mod tests {
             new_voter_content,
             [1; 32],
             H256::default(),
+            H256::default(),
         );
         db.insert_block(&new_voter_block).unwrap();
         assert_eq!(db.best_proposer().unwrap(), new_proposer_block.hash());
@@ -1858,6 +2229,7 @@ mod tests {
             new_transaction_content,
             [0; 32],
             H256::default(),
+            H256::default(),
         );
         db.insert_block(&new_transaction_block).unwrap();
         assert_eq!(
@@ -1876,6 +2248,7 @@ mod tests {
             new_proposer_content,
             [1; 32],
             H256::default(),
+            H256::default(),
         );
         db.insert_block(&new_proposer_block_1).unwrap();
         assert_eq!(
@@ -1897,6 +2270,7 @@ mod tests {
             new_proposer_content,
             [2; 32],
             H256::default(),
+            H256::default(),
         );
         db.insert_block(&new_proposer_block_2).unwrap();
         assert_eq!(db.unreferred_transactions(), vec![]);
@@ -1922,6 +2296,7 @@ mod tests {
             new_proposer_content,
             [0; 32],
             H256::default(),
+            H256::default(),
         );
         db.insert_block(&new_proposer_block_1).unwrap();
 
@@ -1935,6 +2310,7 @@ mod tests {
             new_proposer_content,
             [1; 32],
             H256::default(),
+            H256::default(),
         );
         db.insert_block(&new_proposer_block_2).unwrap();
         assert_eq!(
@@ -1957,6 +2333,7 @@ mod tests {
             new_voter_content,
             [2; 32],
             H256::default(),
+            H256::default(),
         );
         db.insert_block(&new_voter_block).unwrap();
 
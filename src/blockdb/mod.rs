@@ -108,6 +108,20 @@ impl BlockDatabase {
         config: BlockchainConfig,
     ) -> Result<Self, rocksdb::Error> {
         let db = Self::open(&path, config)?;
+
+        // recover the block counter from the largest sequence number in the database
+        let block_arrival_order_cf = db.db.cf_handle(BLOCK_ARRIVAL_ORDER_CF).unwrap();
+        let mut counter: u64 = 0;
+        for (k, _) in db
+            .db
+            .iterator_cf(block_arrival_order_cf, rocksdb::IteratorMode::Start)?
+        {
+            let seq = u64::from_ne_bytes(k[0..8].try_into().unwrap());
+            if seq + 1 > counter {
+                counter = seq + 1;
+            }
+        }
+        db.count.store(counter, Ordering::Relaxed);
         Ok(db)
     }
 
@@ -168,6 +182,15 @@ impl BlockDatabase {
         }
     }
 
+    /// Get the sequence number of a block, i.e. the order in which it is inserted.
+    pub fn sequence_number(&self, hash: &H256) -> Result<Option<u64>, rocksdb::Error> {
+        let block_sequence_number_cf = self.db.cf_handle(BLOCK_SEQUENCE_NUMBER_CF).unwrap();
+        match self.db.get_pinned_cf(block_sequence_number_cf, &hash)? {
+            None => Ok(None),
+            Some(s) => Ok(Some(u64::from_ne_bytes(s[0..8].try_into().unwrap()))),
+        }
+    }
+
     pub fn blocks_after(&self, after: &H256, batch_size: u64) -> BlocksInArrivalOrder {
         let block_sequence_number_cf = self.db.cf_handle(BLOCK_SEQUENCE_NUMBER_CF).unwrap();
         let start_seq = u64::from_ne_bytes(
@@ -1,5 +1,7 @@
+use crate::blockchain::confirmation::{ConfirmationPolicy, Probabilistic};
 use crate::crypto::hash::H256;
 use bigint::uint::U256;
+use std::sync::Arc;
 
 const AVG_TX_SIZE: u32 = 168; // average size of a transaction (in Bytes)
 const PROPOSER_TX_REF_HEADROOM: f32 = 10.0;
@@ -11,6 +13,12 @@ pub const PROPOSER_INDEX: u16 = 0;
 pub const TRANSACTION_INDEX: u16 = 1;
 pub const FIRST_VOTER_INDEX: u16 = 2;
 
+// Block rewards
+/// Value of the coin paid to the miner of a proposer block that becomes the leader of its level.
+pub const PROPOSER_BLOCK_REWARD: u64 = 1000;
+/// Value of the coin paid to the miner of a transaction block that is confirmed in the ledger.
+pub const TRANSACTION_BLOCK_REWARD: u64 = 10;
+
 #[derive(Clone)]
 pub struct BlockchainConfig {
     /// Number of voter chains.
@@ -38,6 +46,14 @@ pub struct BlockchainConfig {
     log_epsilon: f32,
     pub quantile_epsilon_confirm: f32,
     pub quantile_epsilon_deconfirm: f32,
+    /// Number of proposer levels between two difficulty adjustments, or 0 to keep the difficulty
+    /// fixed.
+    pub difficulty_epoch: u64,
+    /// Difficulty of the blocks mined on the proposer genesis block.
+    pub initial_difficulty: H256,
+    /// Rule that decides the leaders of the proposer levels. Defaults to the probabilistic policy
+    /// derived from the adversary ratio and the confirmation confidence.
+    pub confirmation_policy: Arc<dyn ConfirmationPolicy>,
 }
 
 impl BlockchainConfig {
@@ -112,6 +128,13 @@ impl BlockchainConfig {
             log_epsilon,
             quantile_epsilon_confirm: quantile_confirm,
             quantile_epsilon_deconfirm: quantile_deconfirm,
+            difficulty_epoch: 0,
+            initial_difficulty: *DEFAULT_DIFFICULTY,
+            confirmation_policy: Arc::new(Probabilistic::new(
+                adv_ratio,
+                quantile_confirm,
+                quantile_deconfirm,
+            )),
         }
     }
 
@@ -59,6 +59,10 @@ pub fn ico(
     for child in workers.drain(..) {
         child.join().unwrap();
     }
+    // the coins are written to the UTXO database without the write-ahead log, so they are lost in
+    // a crash unless they are flushed. the wallet writes through its write-ahead log. the marker
+    // tells a resumed node that the fund is complete
     utxodb.flush()?;
+    utxodb.set_funded()?;
     Ok(())
 }
@@ -1,5 +1,6 @@
 use crate::block::Block;
 use crate::block::Content as BlockContent;
+use crate::network::lane::Lane;
 
 use crate::transaction::Transaction;
 use crate::wallet::WalletError;
@@ -24,6 +25,7 @@ pub struct Counter {
     confirmed_transaction_bytes: AtomicUsize,
     deconfirmed_transactions: AtomicUsize,
     deconfirmed_transaction_bytes: AtomicUsize,
+    skipped_transactions: AtomicUsize,
     confirmed_transaction_blocks: AtomicUsize,
     deconfirmed_transaction_blocks: AtomicUsize,
     processed_proposer_blocks: AtomicUsize,
@@ -48,10 +50,26 @@ pub struct Counter {
     received_voter_blocks: AtomicUsize,
     received_transaction_blocks: AtomicUsize,
     incoming_message_queue: AtomicIsize,
+    consensus_message_queue: AtomicIsize,
+    payload_message_queue: AtomicIsize,
     total_transaction_block_confirmation_latency: AtomicUsize,
     total_transaction_block_squared_confirmation_latency: AtomicUsize,
     proposer_main_chain_length: AtomicUsize,
     voter_main_chain_length_sum: AtomicIsize,
+    oversized_frames: AtomicUsize,
+    outgoing_message_queue_bytes: AtomicIsize,
+    dropped_outgoing_messages: AtomicUsize,
+    disconnected_slow_peers: AtomicUsize,
+    received_compact_blocks: AtomicUsize,
+    compact_block_missing_transactions: AtomicUsize,
+    failed_compact_blocks: AtomicUsize,
+    outstanding_block_requests: AtomicUsize,
+    stuck_block_requests: AtomicUsize,
+    retried_block_requests: AtomicUsize,
+    buffered_blocks: AtomicUsize,
+    buffer_dependencies: AtomicUsize,
+    evicted_buffered_blocks: AtomicUsize,
+    rejected_blocks: AtomicUsize,
 }
 
 #[derive(Serialize)]
@@ -63,6 +81,7 @@ pub struct Snapshot {
     pub confirmed_transaction_bytes: usize,
     pub deconfirmed_transactions: usize,
     pub deconfirmed_transaction_bytes: usize,
+    pub skipped_transactions: usize,
     pub confirmed_transaction_blocks: usize,
     pub deconfirmed_transaction_blocks: usize,
     pub processed_proposer_blocks: usize,
@@ -87,19 +106,103 @@ pub struct Snapshot {
     pub received_voter_blocks: usize,
     pub received_transaction_blocks: usize,
     pub incoming_message_queue: isize,
+    pub consensus_message_queue: isize,
+    pub payload_message_queue: isize,
     pub total_transaction_block_confirmation_latency: usize,
     pub total_transaction_block_squared_confirmation_latency: usize,
     pub proposer_main_chain_length: usize,
     pub voter_main_chain_length_sum: isize,
+    pub oversized_frames: usize,
+    pub outgoing_message_queue_bytes: isize,
+    pub dropped_outgoing_messages: usize,
+    pub disconnected_slow_peers: usize,
+    pub received_compact_blocks: usize,
+    pub compact_block_missing_transactions: usize,
+    pub failed_compact_blocks: usize,
+    pub outstanding_block_requests: usize,
+    pub stuck_block_requests: usize,
+    pub retried_block_requests: usize,
+    pub buffered_blocks: usize,
+    pub buffer_dependencies: usize,
+    pub evicted_buffered_blocks: usize,
+    pub rejected_blocks: usize,
 }
 
 impl Counter {
-    pub fn record_process_message(&self) {
+    pub fn record_process_message(&self, lane: Lane) {
         self.incoming_message_queue.fetch_sub(1, Ordering::Relaxed);
+        self.lane_queue(lane).fetch_sub(1, Ordering::Relaxed);
     }
 
-    pub fn record_receive_message(&self) {
+    pub fn record_receive_message(&self, lane: Lane) {
         self.incoming_message_queue.fetch_add(1, Ordering::Relaxed);
+        self.lane_queue(lane).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn lane_queue(&self, lane: Lane) -> &AtomicIsize {
+        match lane {
+            Lane::Consensus => &self.consensus_message_queue,
+            Lane::Payload => &self.payload_message_queue,
+        }
+    }
+
+    pub fn record_oversized_frame(&self) {
+        self.oversized_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_enqueue_outgoing_message(&self, bytes: usize) {
+        self.outgoing_message_queue_bytes
+            .fetch_add(bytes as isize, Ordering::Relaxed);
+    }
+
+    pub fn record_dequeue_outgoing_message(&self, bytes: usize) {
+        self.outgoing_message_queue_bytes
+            .fetch_sub(bytes as isize, Ordering::Relaxed);
+    }
+
+    pub fn record_drop_outgoing_message(&self) {
+        self.dropped_outgoing_messages
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_disconnect_slow_peer(&self) {
+        self.disconnected_slow_peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_receive_compact_block(&self, missing_transactions: usize) {
+        self.received_compact_blocks.fetch_add(1, Ordering::Relaxed);
+        self.compact_block_missing_transactions
+            .fetch_add(missing_transactions, Ordering::Relaxed);
+    }
+
+    pub fn record_fail_compact_block(&self) {
+        self.failed_compact_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_block_requests(&self, outstanding: usize, stuck: usize) {
+        self.outstanding_block_requests
+            .store(outstanding, Ordering::Relaxed);
+        self.stuck_block_requests.store(stuck, Ordering::Relaxed);
+    }
+
+    pub fn record_retry_block_requests(&self, num: usize) {
+        self.retried_block_requests
+            .fetch_add(num, Ordering::Relaxed);
+    }
+
+    pub fn record_buffer(&self, blocks: usize, dependencies: usize) {
+        self.buffered_blocks.store(blocks, Ordering::Relaxed);
+        self.buffer_dependencies
+            .store(dependencies, Ordering::Relaxed);
+    }
+
+    pub fn record_evict_buffered_blocks(&self, num: usize) {
+        self.evicted_buffered_blocks
+            .fetch_add(num, Ordering::Relaxed);
+    }
+
+    pub fn record_reject_blocks(&self, num: usize) {
+        self.rejected_blocks.fetch_add(num, Ordering::Relaxed);
     }
 
     pub fn record_receive_block(&self, b: &Block) {
@@ -240,6 +343,10 @@ impl Counter {
             .fetch_add(t.size(), Ordering::Relaxed);
     }
 
+    pub fn record_skip_transaction(&self) {
+        self.skipped_transactions.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn record_generate_transaction(&self, t: &Result<Transaction, WalletError>) {
         match t {
             Ok(t) => {
@@ -280,6 +387,7 @@ impl Counter {
             deconfirmed_transaction_bytes: self
                 .deconfirmed_transaction_bytes
                 .load(Ordering::Relaxed),
+            skipped_transactions: self.skipped_transactions.load(Ordering::Relaxed),
             confirmed_transaction_blocks: self.confirmed_transaction_blocks.load(Ordering::Relaxed),
             deconfirmed_transaction_blocks: self
                 .deconfirmed_transaction_blocks
@@ -320,6 +428,14 @@ impl Counter {
             received_voter_blocks: self.received_voter_blocks.load(Ordering::Relaxed),
             received_transaction_blocks: self.received_transaction_blocks.load(Ordering::Relaxed),
             incoming_message_queue,
+            consensus_message_queue: std::cmp::max(
+                self.consensus_message_queue.load(Ordering::Relaxed),
+                0,
+            ),
+            payload_message_queue: std::cmp::max(
+                self.payload_message_queue.load(Ordering::Relaxed),
+                0,
+            ),
             total_transaction_block_confirmation_latency: self
                 .total_transaction_block_confirmation_latency
                 .load(Ordering::Relaxed),
@@ -328,6 +444,22 @@ impl Counter {
                 .load(Ordering::Relaxed),
             proposer_main_chain_length: self.proposer_main_chain_length.load(Ordering::Relaxed),
             voter_main_chain_length_sum,
+            oversized_frames: self.oversized_frames.load(Ordering::Relaxed),
+            outgoing_message_queue_bytes: self.outgoing_message_queue_bytes.load(Ordering::Relaxed),
+            dropped_outgoing_messages: self.dropped_outgoing_messages.load(Ordering::Relaxed),
+            disconnected_slow_peers: self.disconnected_slow_peers.load(Ordering::Relaxed),
+            received_compact_blocks: self.received_compact_blocks.load(Ordering::Relaxed),
+            compact_block_missing_transactions: self
+                .compact_block_missing_transactions
+                .load(Ordering::Relaxed),
+            failed_compact_blocks: self.failed_compact_blocks.load(Ordering::Relaxed),
+            outstanding_block_requests: self.outstanding_block_requests.load(Ordering::Relaxed),
+            stuck_block_requests: self.stuck_block_requests.load(Ordering::Relaxed),
+            retried_block_requests: self.retried_block_requests.load(Ordering::Relaxed),
+            buffered_blocks: self.buffered_blocks.load(Ordering::Relaxed),
+            buffer_dependencies: self.buffer_dependencies.load(Ordering::Relaxed),
+            evicted_buffered_blocks: self.evicted_buffered_blocks.load(Ordering::Relaxed),
+            rejected_blocks: self.rejected_blocks.load(Ordering::Relaxed),
         }
     }
 }
@@ -1,8 +1,10 @@
+use crate::crypto::hash::Hashable;
 use crate::experiment::performance_counter::PERFORMANCE_COUNTER;
 use crate::handler::new_transaction;
 use crate::miner::memory_pool::MemoryPool;
-use crate::network::server::Handle as ServerHandle;
-
+use crate::network::relay::Handle as RelayHandle;
+use crate::utxodb::UtxoDatabase;
+use crate::validation::TransactionResult;
 use crate::wallet::Wallet;
 use crossbeam::channel;
 use log::{info, trace};
@@ -44,8 +46,9 @@ enum State {
 
 pub struct TransactionGenerator {
     wallet: Arc<Wallet>,
-    server: ServerHandle,
+    relay: RelayHandle,
     mempool: Arc<Mutex<MemoryPool>>,
+    utxodb: Arc<UtxoDatabase>,
     control_chan: channel::Receiver<ControlSignal>,
     arrival_distribution: ArrivalDistribution,
     value_distribution: ValueDistribution,
@@ -55,14 +58,16 @@ pub struct TransactionGenerator {
 impl TransactionGenerator {
     pub fn new(
         wallet: &Arc<Wallet>,
-        server: &ServerHandle,
+        relay: &RelayHandle,
         mempool: &Arc<Mutex<MemoryPool>>,
+        utxodb: &Arc<UtxoDatabase>,
     ) -> (Self, channel::Sender<ControlSignal>) {
         let (tx, rx) = channel::unbounded();
         let instance = Self {
             wallet: Arc::clone(wallet),
-            server: server.clone(),
+            relay: relay.clone(),
             mempool: Arc::clone(mempool),
+            utxodb: Arc::clone(utxodb),
             control_chan: rx,
             arrival_distribution: ArrivalDistribution::Uniform(UniformArrival { interval: 100 }),
             value_distribution: ValueDistribution::Uniform(UniformValue { min: 50, max: 100 }),
@@ -150,7 +155,13 @@ impl TransactionGenerator {
                 match transaction {
                     Ok(t) => {
                         prev_coin = Some(t.input.last().unwrap().coin);
-                        new_transaction(t, &self.mempool, &self.server);
+                        let hash = t.hash();
+                        match new_transaction(t, &self.mempool, &self.utxodb, &self.relay) {
+                            TransactionResult::Pass => {}
+                            result => {
+                                trace!("Generated transaction {:.8} rejected: {}", hash, result)
+                            }
+                        }
                         // if we are in stepping mode, decrease the step count
                         if let State::Step(step_count) = self.state {
                             if step_count - 1 == 0 {
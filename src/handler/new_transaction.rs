@@ -1,19 +1,42 @@
 use crate::crypto::hash::Hashable;
 use crate::miner::memory_pool::MemoryPool;
 
-use crate::network::server::Handle;
+use crate::network::relay::Handle as RelayHandle;
 use crate::transaction::Transaction;
+use crate::utxodb::UtxoDatabase;
+use crate::validation::{self, TransactionResult};
 use std::sync::Mutex;
 
-/// Handler for new transaction
-// We may want to add the result of memory pool check
-pub fn new_transaction(transaction: Transaction, mempool: &Mutex<MemoryPool>, _server: &Handle) {
+/// Handler for new transaction. Returns Pass if the transaction is inserted into the memory pool,
+/// or the reason why it is not.
+pub fn new_transaction(
+    transaction: Transaction,
+    mempool: &Mutex<MemoryPool>,
+    utxodb: &UtxoDatabase,
+    relay: &RelayHandle,
+) -> TransactionResult {
+    let hash = transaction.hash();
+    // skip the expensive checks for transactions that we already have
+    if mempool.lock().unwrap().contains(&hash) {
+        return TransactionResult::Duplicate;
+    }
+    let result = validation::check_transaction(&transaction);
+    if result != TransactionResult::Pass {
+        return result;
+    }
+
     let mut mempool = mempool.lock().unwrap();
     // memory pool check
-    if !mempool.contains(&transaction.hash()) && !mempool.is_double_spend(&transaction.input) {
-        // if check passes, insert the new transaction into the mempool
-        //server.broadcast(Message::NewTransactionHashes(vec![transaction.hash()]));
-        mempool.insert(transaction);
+    let result = validation::check_transaction_inputs(&transaction, utxodb, &mempool);
+    if result != TransactionResult::Pass {
+        return result;
+    }
+    // if check passes, insert the new transaction into the mempool, and tell the peers. the
+    // mempool may turn it down if it is full and the transaction does not pay enough
+    if !mempool.insert(transaction) {
+        return TransactionResult::MempoolFull;
     }
     drop(mempool);
+    relay.announce(hash);
+    TransactionResult::Pass
 }
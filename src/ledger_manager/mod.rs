@@ -1,22 +1,30 @@
 use crate::block::Content;
-use crate::blockchain::BlockChain;
+use crate::blockchain::{BlockChain, LedgerDiff};
 use crate::blockdb::BlockDatabase;
+use crate::config::{PROPOSER_BLOCK_REWARD, TRANSACTION_BLOCK_REWARD};
 use crate::crypto::hash::{Hashable, H256};
 use crate::experiment::performance_counter::PERFORMANCE_COUNTER;
-
-use crate::transaction::{CoinId, Output, Transaction};
-use crate::utxodb::UtxoDatabase;
+use crate::miner::memory_pool::MemoryPool;
+use crate::transaction::{Address, CoinId, Input, Output, Transaction};
+use crate::utxodb::{Execution, LedgerPosition, LedgerWatermark, RewardPosition, UtxoDatabase};
+use crate::validation::{self, TransactionResult};
 use crate::wallet::Wallet;
 use crossbeam::channel;
+use log::{debug, info};
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time;
+
+/// Minimum interval between two checkpoints of the UTXO database.
+const CHECKPOINT_INTERVAL: time::Duration = time::Duration::from_secs(10);
 
 pub struct LedgerManager {
     blockdb: Arc<BlockDatabase>,
     chain: Arc<BlockChain>,
     utxodb: Arc<UtxoDatabase>,
     wallet: Arc<Wallet>,
+    mempool: Arc<Mutex<MemoryPool>>,
 }
 
 impl LedgerManager {
@@ -25,27 +33,56 @@ impl LedgerManager {
         chain: &Arc<BlockChain>,
         utxodb: &Arc<UtxoDatabase>,
         wallet: &Arc<Wallet>,
+        mempool: &Arc<Mutex<MemoryPool>>,
     ) -> Self {
         Self {
             blockdb: Arc::clone(&blockdb),
             chain: Arc::clone(&chain),
             utxodb: Arc::clone(&utxodb),
             wallet: Arc::clone(&wallet),
+            mempool: Arc::clone(&mempool),
         }
     }
 
     pub fn start(self, buffer_size: usize, num_workers: usize) {
+        // find out the position in the ledger that the UTXO set reflects. the ledger diffs after
+        // it may or may not have been applied before the last shutdown, so we replay them
+        let watermark = self.utxodb.watermark().unwrap().unwrap_or_default();
+        if watermark.ledger_diff_seq != 0 {
+            info!(
+                "Resuming ledger after diff {} (proposer level {}, transaction block {:.8})",
+                watermark.ledger_diff_seq,
+                watermark.proposer_ledger_tip,
+                watermark.last_transaction_block
+            );
+        }
+
         // start thread that updates transaction sequence
         let blockdb = Arc::clone(&self.blockdb);
         let chain = Arc::clone(&self.chain);
         let (tx_diff_tx, tx_diff_rx) = channel::bounded(buffer_size);
-        thread::spawn(move || loop {
-            let tx_diff = update_transaction_sequence(&blockdb, &chain);
-            tx_diff_tx.send(tx_diff).unwrap();
+        thread::spawn(move || {
+            let mut next_seq = watermark.ledger_diff_seq + 1;
+            loop {
+                chain.update_ledger().unwrap();
+                while let Some(diff) = chain.ledger_diff(next_seq).unwrap() {
+                    PERFORMANCE_COUNTER.record_deconfirm_transaction_blocks(diff.removed.len());
+                    let jobs = jobs(&blockdb, &chain, &diff);
+                    let watermark = LedgerWatermark {
+                        ledger_diff_seq: next_seq,
+                        proposer_ledger_tip: diff.proposer_ledger_tip,
+                        last_transaction_block: diff.last_transaction_block,
+                    };
+                    tx_diff_tx.send((watermark, jobs)).unwrap();
+                    next_seq += 1;
+                }
+            }
         });
 
         // start thread that dispatches jobs to utxo manager
-        let _utxodb = Arc::clone(&self.utxodb);
+        let utxodb = Arc::clone(&self.utxodb);
+        let chain = Arc::clone(&self.chain);
+        let mempool = Arc::clone(&self.mempool);
         // Scoreboard notes the transaction ID of the coins that is being looked up, may be added,
         // or may be deleted. Before dispatching a transaction, we first check whether the input
         // and output are used by transactions being processed. If no, we will dispatch this
@@ -62,12 +99,23 @@ impl LedgerManager {
         let (coin_diff_tx, coin_diff_rx) = channel::unbounded();
 
         thread::spawn(move || {
+            let mut last_checkpoint = time::Instant::now();
             loop {
                 // get the diff
-                let (mut added_tx, mut removed_tx) = tx_diff_rx.recv().unwrap();
+                let (watermark, mut jobs) = tx_diff_rx.recv().unwrap();
 
-                // dispatch transactions
-                for (t, h) in removed_tx.drain(..).rev() {
+                // note the transactions that leave the ledger, in ledger order
+                let mut deconfirmed: Vec<Transaction> = jobs
+                    .iter()
+                    .filter_map(|job| match job {
+                        Job::Transaction(false, t, _, _, _) => Some(t.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                deconfirmed.reverse();
+
+                // dispatch jobs
+                for job in jobs.drain(..) {
                     // drain the notification channel so that we mark all finished transaction as
                     // finished
                     for processed in notification_rx.try_iter() {
@@ -77,11 +125,15 @@ impl LedgerManager {
                         }
                     }
 
-                    // collect the tx hash of all coins this tx will touch
+                    // collect the tx hash of all coins this job will touch
+                    let h = job.hash();
                     let mut touched_coin_transaction_hash: HashSet<H256> = HashSet::new();
                     touched_coin_transaction_hash.insert(h); // the transaction hash of all output coins
-                    for input in &t.input {
-                        touched_coin_transaction_hash.insert(input.coin.hash); // tx hash of input coin
+                    if let Job::Transaction(_, t, _, _, _) = &job {
+                        // tx hash of input coins
+                        for input in &t.input {
+                            touched_coin_transaction_hash.insert(input.coin.hash);
+                        }
                     }
 
                     // wait until we are not touching hot coins
@@ -100,42 +152,44 @@ impl LedgerManager {
                         scoreboard.insert(hash);
                     }
                     transaction_coins.insert(h, touched);
-                    transaction_tx.send((false, t, h)).unwrap();
+                    transaction_tx.send(job).unwrap();
                 }
-                for (t, h) in added_tx.drain(..) {
-                    // drain the notification channel so that we mark all finished transaction as
-                    // finished
-                    for processed in notification_rx.try_iter() {
+
+                // return the deconfirmed transactions to the memory pool. before that, wait until
+                // all dispatched jobs are processed, so that they are checked against the UTXO
+                // set of the new ledger
+                if !deconfirmed.is_empty() {
+                    while !transaction_coins.is_empty() {
+                        let processed = notification_rx.recv().unwrap();
                         let finished_coins = transaction_coins.remove(&processed).unwrap();
                         for hash in &finished_coins {
                             scoreboard.remove(&hash);
                         }
                     }
+                    let num_returned = return_to_mempool(&deconfirmed, &utxodb, &mempool);
+                    info!(
+                        "Returned {} of {} deconfirmed transactions to the memory pool",
+                        num_returned,
+                        deconfirmed.len()
+                    );
+                }
 
-                    // collect the tx hash of all coins this tx will touch
-                    let mut touched_coin_transaction_hash: HashSet<H256> = HashSet::new();
-                    touched_coin_transaction_hash.insert(h); // the transaction hash of all output coins
-                    for input in &t.input {
-                        touched_coin_transaction_hash.insert(input.coin.hash); // tx hash of input coin
-                    }
-
-                    // wait until we are not touching hot coins
-                    while !scoreboard.is_disjoint(&touched_coin_transaction_hash) {
+                // checkpoint the UTXO database once in a while. before that, wait until all
+                // dispatched transactions are processed, so that the UTXO set being flushed is
+                // exactly the one at the watermark
+                if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                    while !transaction_coins.is_empty() {
                         let processed = notification_rx.recv().unwrap();
                         let finished_coins = transaction_coins.remove(&processed).unwrap();
                         for hash in &finished_coins {
                             scoreboard.remove(&hash);
                         }
                     }
-
-                    // mark the coins that we will be touching as hot
-                    let mut touched: Vec<H256> = vec![];
-                    for hash in touched_coin_transaction_hash.drain() {
-                        touched.push(hash);
-                        scoreboard.insert(hash);
-                    }
-                    transaction_coins.insert(h, touched);
-                    transaction_tx.send((true, t, h)).unwrap();
+                    utxodb.checkpoint(&watermark).unwrap();
+                    // keep the diff at the watermark, so that the blockchain knows the latest
+                    // sequence number when being loaded
+                    chain.prune_ledger_diffs(watermark.ledger_diff_seq).unwrap();
+                    last_checkpoint = time::Instant::now();
                 }
             }
         });
@@ -161,8 +215,8 @@ impl LedgerManager {
 #[derive(Clone)]
 struct UtxoManager {
     utxodb: Arc<UtxoDatabase>,
-    /// Channel for dispatching jobs (add/delete, transaction, hash of transaction).
-    transaction_chan: channel::Receiver<(bool, Transaction, H256)>,
+    /// Channel for dispatching jobs.
+    transaction_chan: channel::Receiver<Job>,
     /// Channel for returning added and removed coins.
     coin_chan: channel::Sender<(Vec<(CoinId, Output)>, Vec<CoinId>)>,
     /// Channel for notifying the dispatcher about the completion of processing this transaction.
@@ -181,59 +235,235 @@ impl UtxoManager {
 
     fn worker_loop(&self) {
         loop {
-            let (add, transaction, hash) = self.transaction_chan.recv().unwrap();
-            if add {
-                let diff = self.utxodb.add_transaction(&transaction, hash).unwrap();
-                self.coin_chan.send(diff).unwrap();
-            } else {
-                let diff = self.utxodb.remove_transaction(&transaction, hash).unwrap();
-                self.coin_chan.send(diff).unwrap();
+            let job = self.transaction_chan.recv().unwrap();
+            let diff = job.execute(&self.utxodb).unwrap();
+            self.coin_chan.send(diff).unwrap();
+            self.notification_chan.send(job.hash()).unwrap();
+        }
+    }
+}
+
+/// A change to the UTXO set caused by a change of the ledger.
+pub enum Job {
+    /// Add (true) or remove (false) a copy of a transaction, given its hash, its position in the
+    /// ledger, and the miner of the transaction block that confirms it, who receives the fee.
+    Transaction(bool, Transaction, H256, LedgerPosition, Address),
+    /// Pay (true) or take back (false) the reward of the block with the given hash, for the
+    /// reference to the block at the given position of the ledger.
+    Reward(bool, H256, Output, RewardPosition),
+}
+
+impl Job {
+    /// Get the hash of the transaction or the block that produces the coins of this job.
+    pub fn hash(&self) -> H256 {
+        match self {
+            Job::Transaction(_, _, hash, _, _) => *hash,
+            Job::Reward(_, hash, _, _) => *hash,
+        }
+    }
+
+    /// Apply this job to the UTXO database, and return the added and removed coins.
+    pub fn execute(
+        &self,
+        utxodb: &UtxoDatabase,
+    ) -> Result<(Vec<(CoinId, Output)>, Vec<CoinId>), rocksdb::Error> {
+        match self {
+            Job::Transaction(true, t, hash, position, miner) => {
+                let (execution, added, removed) =
+                    utxodb.add_transaction(t, *hash, *position, *miner)?;
+                if execution != Execution::Applied {
+                    PERFORMANCE_COUNTER.record_skip_transaction();
+                    debug!(
+                        "Skipped transaction {:.8} at {:.8}/{} on level {}: {:?}",
+                        hash, position.block, position.index, position.level, execution
+                    );
+                }
+                Ok((added, removed))
+            }
+            Job::Transaction(false, t, hash, position, _) => {
+                utxodb.remove_transaction(t, *hash, *position)
+            }
+            Job::Reward(true, hash, output, position) => {
+                utxodb.add_reward(*hash, *output, *position)
             }
-            self.notification_chan.send(hash).unwrap();
+            Job::Reward(false, hash, _, position) => utxodb.remove_reward(*hash, *position),
         }
     }
 }
 
-fn update_transaction_sequence(
-    blockdb: &BlockDatabase,
-    chain: &BlockChain,
-) -> (Vec<(Transaction, H256)>, Vec<(Transaction, H256)>) {
-    let diff = chain.update_ledger().unwrap();
-    PERFORMANCE_COUNTER.record_deconfirm_transaction_blocks(diff.1.len());
-
-    // gather the transaction diff
-    let mut add: Vec<(Transaction, H256)> = vec![];
-    let mut remove: Vec<(Transaction, H256)> = vec![];
-    for hash in diff.0 {
-        let block = blockdb.get(&hash).unwrap().unwrap();
-        PERFORMANCE_COUNTER.record_confirm_transaction_block(&block);
+/// Turn the given ledger diff into jobs, in the order that they must be executed. Removed blocks
+/// are rolled back in reverse order, before the added blocks are applied. The leader rewards of
+/// the added levels are paid before the transactions of the levels are applied.
+pub fn jobs(blockdb: &BlockDatabase, chain: &BlockChain, diff: &LedgerDiff) -> Vec<Job> {
+    let mut jobs: Vec<Job> = vec![];
+    let removed_references = references(&diff.removed_levels);
+    for ((hash, level), reference) in diff
+        .removed
+        .iter()
+        .zip(&diff.removed_levels)
+        .zip(removed_references)
+        .rev()
+    {
+        let block = blockdb.get(hash).unwrap().unwrap();
+        let miner = block.header.miner;
         let content = match block.content {
             Content::Transaction(data) => data,
             _ => unreachable!(),
         };
-        let mut transactions = content
-            .transactions
-            .iter()
-            .map(|t| (t.clone(), t.hash()))
-            .collect();
-        // TODO: precompute the hash here. Note that although lazy-eval for tx hash, and we could have
-        // just called hash() here without storing the results (the results will be cached in the struct),
-        // such function call will be optimized away by LLVM. As a result, we have to manually pass the hash
-        // here. The same for added transactions below. This is a very ugly hack.
-        add.append(&mut transactions);
+        for (index, t) in content.transactions.into_iter().enumerate().rev() {
+            let h = t.hash();
+            let position = LedgerPosition {
+                level: *level,
+                reference,
+                block: *hash,
+                index: index as u32,
+            };
+            jobs.push(Job::Transaction(false, t, h, position, miner));
+        }
+        let position = RewardPosition {
+            level: *level,
+            reference,
+        };
+        jobs.push(Job::Reward(
+            false,
+            *hash,
+            reward(miner, TRANSACTION_BLOCK_REWARD),
+            position,
+        ));
+    }
+    for hash in diff.removed_leaders.iter().rev() {
+        let block = blockdb.get(hash).unwrap().unwrap();
+        let output = reward(block.header.miner, PROPOSER_BLOCK_REWARD);
+        jobs.push(Job::Reward(
+            false,
+            *hash,
+            output,
+            leader_position(chain, hash),
+        ));
+    }
+    for hash in &diff.added_leaders {
+        let block = blockdb.get(hash).unwrap().unwrap();
+        let output = reward(block.header.miner, PROPOSER_BLOCK_REWARD);
+        jobs.push(Job::Reward(
+            true,
+            *hash,
+            output,
+            leader_position(chain, hash),
+        ));
     }
-    for hash in diff.1 {
-        let block = blockdb.get(&hash).unwrap().unwrap();
+    let added_references = references(&diff.added_levels);
+    for ((hash, level), reference) in diff
+        .added
+        .iter()
+        .zip(&diff.added_levels)
+        .zip(added_references)
+    {
+        let block = blockdb.get(hash).unwrap().unwrap();
+        PERFORMANCE_COUNTER.record_confirm_transaction_block(&block);
+        let miner = block.header.miner;
         let content = match block.content {
             Content::Transaction(data) => data,
             _ => unreachable!(),
         };
-        let mut transactions = content
-            .transactions
-            .iter()
-            .map(|t| (t.clone(), t.hash()))
-            .collect();
-        remove.append(&mut transactions);
+        let position = RewardPosition {
+            level: *level,
+            reference,
+        };
+        jobs.push(Job::Reward(
+            true,
+            *hash,
+            reward(miner, TRANSACTION_BLOCK_REWARD),
+            position,
+        ));
+        // TODO: precompute the hash here. Note that although lazy-eval for tx hash, and we could have
+        // just called hash() here without storing the results (the results will be cached in the struct),
+        // such function call will be optimized away by LLVM. As a result, we have to manually pass the hash
+        // here. This is a very ugly hack.
+        for (index, t) in content.transactions.into_iter().enumerate() {
+            let h = t.hash();
+            let position = LedgerPosition {
+                level: *level,
+                reference,
+                block: *hash,
+                index: index as u32,
+            };
+            jobs.push(Job::Transaction(true, t, h, position, miner));
+        }
+    }
+    jobs
+}
+
+/// Get the position of a proposer leader in the ledger. A proposer block can only lead its own
+/// level, so it is referred to at most once.
+fn leader_position(chain: &BlockChain, hash: &H256) -> RewardPosition {
+    RewardPosition {
+        level: chain.proposer_level(hash).unwrap(),
+        reference: 0,
+    }
+}
+
+/// Number the transaction block references of each level in a ledger diff, given the level of each
+/// reference. The references of a level are always added or removed together, so the numbers are
+/// the same whenever the level is confirmed by the same leader.
+fn references(levels: &[u64]) -> Vec<u32> {
+    let mut references = Vec::with_capacity(levels.len());
+    let mut last_level: Option<u64> = None;
+    let mut next = 0;
+    for level in levels {
+        if last_level != Some(*level) {
+            last_level = Some(*level);
+            next = 0;
+        }
+        references.push(next);
+        next += 1;
+    }
+    references
+}
+
+/// Put the given deconfirmed transactions back into the memory pool if they are still valid, and
+/// return the number of transactions put back. The transactions must be in ledger order, so that
+/// a transaction spending the output of another one comes after it. The transactions in the memory
+/// pool that spend the outputs of a transaction that is no longer valid are removed.
+fn return_to_mempool(
+    transactions: &[Transaction],
+    utxodb: &UtxoDatabase,
+    mempool: &Mutex<MemoryPool>,
+) -> usize {
+    let mut mempool = mempool.lock().unwrap();
+    let mut num_returned = 0;
+    for t in transactions {
+        let hash = t.hash();
+        // the transaction may still be applied, as another copy or a copy added back to the ledger
+        if utxodb.execution(&hash).unwrap().is_some() {
+            continue;
+        }
+        match validation::check_transaction_inputs(t, utxodb, &mempool) {
+            TransactionResult::Pass => {
+                if mempool.insert(t.clone()) {
+                    num_returned += 1;
+                    continue;
+                }
+            }
+            TransactionResult::Duplicate => continue,
+            result => debug!("Deconfirmed transaction {:.8} dropped: {}", hash, result),
+        }
+        for (index, output) in t.output.iter().enumerate() {
+            mempool.remove_by_input(&Input {
+                coin: CoinId {
+                    hash,
+                    index: index as u32,
+                },
+                value: output.value,
+                owner: output.recipient,
+            });
+        }
+    }
+    num_returned
+}
+
+fn reward(miner: Address, value: u64) -> Output {
+    Output {
+        value,
+        recipient: miner,
     }
-    (add, remove)
 }
@@ -17,6 +17,8 @@ pub mod handler;
 pub mod ledger_manager;
 pub mod miner;
 pub mod network;
+pub mod node;
+pub mod reindex;
 pub mod transaction;
 pub mod utxodb;
 pub mod validation;
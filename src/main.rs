@@ -1,21 +1,23 @@
 #[macro_use]
 extern crate clap;
 
-use crossbeam::channel;
 use ed25519_dalek::Keypair;
 use log::{debug, error, info};
-use piper;
 use prism::api::Server as ApiServer;
+use prism::blockchain::confirmation::{AllChainsDeep, MajorityDeep};
 use prism::blockchain::BlockChain;
 use prism::blockdb::BlockDatabase;
 use prism::config::BlockchainConfig;
 use prism::crypto::hash::H256;
 use prism::experiment::transaction_generator::TransactionGenerator;
-use prism::ledger_manager::LedgerManager;
-use prism::miner;
 use prism::miner::memory_pool::MemoryPool;
-use prism::network::server;
-use prism::network::worker;
+use prism::network::address_book::AddressBook;
+use prism::network::buffer::BufferLimits;
+use prism::network::lane::LaneSizes;
+use prism::network::message::FrameLimits;
+use prism::network::peer::WriteQueueConfig;
+use prism::network::transport::TcpTransport;
+use prism::node::{self, NodeConfig};
 use prism::transaction::Address;
 use prism::utxodb::UtxoDatabase;
 use prism::visualization::Server as VisualizationServer;
@@ -38,18 +40,33 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg visualization: --visual [ADDR] "Enables the visualization server and sets its address and port")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg address_book: --addrbook [PATH] default_value("/tmp/prism-addrbook.txt") "Sets the path to the file that stores known peer addresses")
+     (@arg network_id: --("network-id") [INT] default_value("0") "Sets the network ID, which must match that of the peers")
+     (@arg outgoing_peers: --("outgoing-peers") [INT] default_value("0") "Sets the number of outgoing peers to keep by connecting to known addresses, 0 to disable")
+     (@arg max_frame_size: --("max-frame-size") [BYTES] default_value("67108864") "Sets the maximum size of a message carrying blocks or transactions")
+     (@arg write_queue_size: --("write-queue-size") [BYTES] default_value("134217728") "Sets the maximum total size of the messages waiting to be sent to a peer")
+     (@arg slow_peer_policy: --("slow-peer-policy") [POLICY] possible_values(&["drop", "disconnect"]) default_value("drop") "Sets whether to drop messages or disconnect when a peer's write queue is full")
+     (@arg no_compact_blocks: --("no-compact-blocks") "Disables asking peers for transaction blocks in the compact form")
+     (@arg no_headers_first: --("no-headers-first") "Downloads whole blocks from one peer during the initial block download, instead of checking the headers first and downloading the contents from all peers")
+     (@arg buffer_size: --("buffer-size") [INT] default_value("50000") "Sets the maximum number of blocks waiting for their dependencies")
+     (@arg buffer_peer_quota: --("buffer-peer-quota") [INT] default_value("10000") "Sets the maximum number of blocks from one peer waiting for their dependencies")
      (@arg block_db: --blockdb [PATH] default_value("/tmp/prism-blocks.rocksdb") "Sets the path to the block database")
      (@arg utxo_db: --utxodb [PATH] default_value("/tmp/prism-utxo.rocksdb") "Sets the path to the UTXO database")
      (@arg blockchain_db: --blockchaindb [PATH] default_value("/tmp/prism-blockchain.rocksdb") "Sets the path to the blockchain database")
      (@arg wallet_db: --walletdb [PATH] default_value("/tmp/prism-wallet.rocksdb") "Sets the path to the wallet database")
+     (@arg resume: --resume "Opens the existing databases instead of creating new ones")
      (@arg init_fund_addr: --("fund-addr") ... [ADDR] "Endows the given address an initial fund in the genesis block")
      (@arg init_fund_coins: --("fund-coins") [INT] default_value("50000") "Sets the number of initial coins for each address")
      (@arg init_fund_value: --("fund-value") [INT] default_value("100") "Sets the value of each initial coin")
      (@arg load_key_path: --("load-key") ... [PATH] "Loads a key pair into the wallet from the given path")
+     (@arg miner_addr: --("miner-addr") [ADDR] "Sets the address that receives the mining rewards and fees, instead of the first address in the wallet")
      (@arg mempool_size: --("mempool-size") [INT] default_value("500000") "Sets the maximum number of transactions for the memory pool")
+     (@arg mempool_expiry: --("mempool-expiry") [SEC] default_value("3600") "Sets the number of seconds after which a transaction is removed from the memory pool")
      (@arg execution_workers: --("execution-workers") [INT] default_value("8") "Sets the number of worker threads for transaction execution")
      (@arg execution_buffer: --("execution-buffer") [INT] default_value("3") "Sets the size of the buffer between pipeline stages in transaction execution")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("16") "Sets the number of worker threads for P2P server")
+     (@arg consensus_queue_size: --("consensus-queue-size") [INT] default_value("1000") "Sets the number of incoming messages about proposer and voter blocks waiting to be processed")
+     (@arg payload_queue_size: --("payload-queue-size") [INT] default_value("100") "Sets the number of incoming messages about transactions and transaction blocks waiting to be processed")
      (@arg voter_chains: --("voter-chains") [INT] default_value("1000") "Sets the number of voter chains")
      (@arg tx_throughput: --("tx-throughput") [INT] default_value("80000") "Sets the target transaction throughput")
      (@arg tx_block_size: --("tx-block-size") [INT] default_value("64000") "Sets the maximum size of the transaction block in Bytes")
@@ -57,11 +74,18 @@ fn main() {
      (@arg voter_mining_rate: --("voter-mining-rate") [FLOAT] default_value("0.1") "Sets the voter chain mining rate")
      (@arg adv_ratio: --("adversary-ratio") [FLOAT] default_value("0.4") "Sets the ratio of adversary hashing power")
      (@arg log_epsilon: --("confirm-confidence") [FLOAT] default_value("20.0") "Sets -log(epsilon) for confirmation")
+     (@arg confirmation_policy: --("confirmation-policy") [POLICY] possible_values(&["probabilistic", "majority-deep", "all-chains-deep"]) default_value("probabilistic") "Sets the rule that confirms proposer leaders")
+     (@arg confirmation_depth: --("confirmation-depth") [INT] default_value("6") "Sets how deep a vote must be to count towards the majority-deep and all-chains-deep confirmation policies")
+     (@arg difficulty_epoch: --("difficulty-epoch") [INT] default_value("0") "Sets the number of proposer levels between two difficulty adjustments, or 0 to keep the difficulty fixed")
+     (@arg initial_difficulty: --("initial-difficulty") [HEX] "Sets the difficulty of the blocks mined on the genesis block, as 32 bytes in hex")
 
      (@subcommand keygen =>
       (about: "Generates Prism wallet key pair")
       (@arg display_address: --addr "Prints the address of the key pair to STDERR")
      )
+     (@subcommand reindex =>
+      (about: "Rebuilds the blockchain and UTXO databases from the blocks in the block database")
+     )
     )
     .get_matches();
 
@@ -147,7 +171,7 @@ fn main() {
             error!("Error parsing confirm confidence: {}", e);
             process::exit(1);
         });
-    let config = BlockchainConfig::new(
+    let mut config = BlockchainConfig::new(
         voter_chains,
         tx_blk_size,
         tx_throughput,
@@ -156,6 +180,49 @@ fn main() {
         adv_ratio,
         log_epsilon,
     );
+    config.difficulty_epoch = matches
+        .value_of("difficulty_epoch")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing difficulty epoch: {}", e);
+            process::exit(1);
+        });
+    if let Some(difficulty) = matches.value_of("initial_difficulty") {
+        let raw: [u8; 32] = hex::decode(difficulty)
+            .ok()
+            .and_then(|d| d.as_slice().try_into().ok())
+            .unwrap_or_else(|| {
+                error!("Error parsing initial difficulty: expecting 32 bytes in hex");
+                process::exit(1);
+            });
+        config.initial_difficulty = raw.into();
+    }
+    let confirmation_depth = matches
+        .value_of("confirmation_depth")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing confirmation depth: {}", e);
+            process::exit(1);
+        });
+    match matches.value_of("confirmation_policy").unwrap() {
+        "majority-deep" => {
+            config.confirmation_policy = Arc::new(MajorityDeep::new(confirmation_depth));
+            info!(
+                "Confirming proposer leaders with {}-deep votes from a majority of voter chains",
+                confirmation_depth
+            );
+        }
+        "all-chains-deep" => {
+            config.confirmation_policy = Arc::new(AllChainsDeep::new(confirmation_depth));
+            info!(
+                "Confirming proposer leaders with {}-deep votes from all voter chains",
+                confirmation_depth
+            );
+        }
+        _ => {}
+    }
     info!(
         "Proposer block mining rate set to {} blks/s",
         config.proposer_mining_rate
@@ -169,6 +236,61 @@ fn main() {
         config.tx_mining_rate
     );
 
+    // rebuild the blockchain and UTXO databases from the block database, then exit
+    if let ("reindex", Some(_)) = matches.subcommand() {
+        let blockdb = BlockDatabase::load(&matches.value_of("block_db").unwrap(), config.clone())
+            .unwrap_or_else(|e| {
+                error!("Error opening block database: {}", e);
+                process::exit(1);
+            });
+        let blockchain =
+            BlockChain::new(&matches.value_of("blockchain_db").unwrap(), config.clone())
+                .unwrap_or_else(|e| {
+                    error!("Error opening blockchain database: {}", e);
+                    process::exit(1);
+                });
+        let utxodb = UtxoDatabase::new(&matches.value_of("utxo_db").unwrap()).unwrap_or_else(|e| {
+            error!("Error opening UTXO database: {}", e);
+            process::exit(1);
+        });
+        let utxodb = Arc::new(utxodb);
+        let wallet = Wallet::load(&matches.value_of("wallet_db").unwrap()).unwrap_or_else(|e| {
+            error!("Error opening wallet database: {}", e);
+            process::exit(1);
+        });
+        // the coins are rebuilt from the ledger, so only the key pairs are kept
+        wallet.clear_coins().unwrap_or_else(|e| {
+            error!("Error clearing wallet coins: {}", e);
+            process::exit(1);
+        });
+        let wallet = Arc::new(wallet);
+        info!(
+            "Reindexing {} blocks in the block database",
+            blockdb.num_blocks()
+        );
+        let num_blocks = prism::reindex::rebuild_blockchain(&blockdb, &blockchain, &config)
+            .unwrap_or_else(|e| {
+                error!("Error rebuilding blockchain database: {}", e);
+                process::exit(1);
+            });
+        info!(
+            "Inserted {} blocks into the blockchain database",
+            num_blocks
+        );
+        // the initial fund is not in any block, so we need to add it again
+        fund(&matches, &utxodb, &wallet);
+        let num_tx_blocks = prism::reindex::rebuild_utxo(&blockdb, &blockchain, &utxodb, &wallet)
+            .unwrap_or_else(|e| {
+                error!("Error rebuilding UTXO database: {}", e);
+                process::exit(1);
+            });
+        info!(
+            "Applied {} transaction blocks to the UTXO database",
+            num_tx_blocks
+        );
+        return;
+    }
+
     // init mempool
     let mempool_size = matches
         .value_of("mempool_size")
@@ -178,31 +300,90 @@ fn main() {
             error!("Error parsing memory pool size limit: {}", e);
             process::exit(1);
         });
-    let mempool = MemoryPool::new(mempool_size);
+    let mempool_expiry = matches
+        .value_of("mempool_expiry")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing memory pool expiry: {}", e);
+            process::exit(1);
+        });
+    let mempool = MemoryPool::new(mempool_size, time::Duration::from_secs(mempool_expiry));
     let mempool = Arc::new(std::sync::Mutex::new(mempool));
     debug!("Initialized mempool, maximum size set to {}", mempool_size);
 
+    // whether to open the existing databases or to create new ones
+    let resume = matches.is_present("resume");
+    if resume {
+        info!("Resuming from existing databases");
+    }
+
     // init block database
-    let blockdb =
-        BlockDatabase::new(&matches.value_of("block_db").unwrap(), config.clone()).unwrap();
+    let blockdb = if resume {
+        BlockDatabase::load(&matches.value_of("block_db").unwrap(), config.clone())
+    } else {
+        BlockDatabase::new(&matches.value_of("block_db").unwrap(), config.clone())
+    }
+    .unwrap_or_else(|e| {
+        error!("Error opening block database: {}", e);
+        process::exit(1);
+    });
     let blockdb = Arc::new(blockdb);
-    debug!("Initialized block database");
+    debug!(
+        "Initialized block database with {} blocks",
+        blockdb.num_blocks()
+    );
 
     // init utxo database
-    let utxodb = UtxoDatabase::new(&matches.value_of("utxo_db").unwrap()).unwrap();
+    let utxodb = if resume {
+        UtxoDatabase::load(&matches.value_of("utxo_db").unwrap())
+    } else {
+        UtxoDatabase::new(&matches.value_of("utxo_db").unwrap())
+    }
+    .unwrap_or_else(|e| {
+        error!("Error opening UTXO database: {}", e);
+        process::exit(1);
+    });
     let utxodb = Arc::new(utxodb);
     debug!("Initialized UTXO database");
 
     // init blockchain database
-    let blockchain =
-        BlockChain::new(&matches.value_of("blockchain_db").unwrap(), config.clone()).unwrap();
+    let blockchain = if resume {
+        BlockChain::load(&matches.value_of("blockchain_db").unwrap(), config.clone())
+            .unwrap_or_else(|e| {
+                error!("Error loading blockchain database: {}", e);
+                process::exit(1);
+            })
+    } else {
+        BlockChain::new(&matches.value_of("blockchain_db").unwrap(), config.clone()).unwrap_or_else(
+            |e| {
+                error!("Error opening blockchain database: {}", e);
+                process::exit(1);
+            },
+        )
+    };
+    if !blockchain
+        .contains_proposer(&config.proposer_genesis)
+        .unwrap()
+    {
+        error!("Blockchain database does not contain the genesis block, cannot resume");
+        process::exit(1);
+    }
     let blockchain = Arc::new(blockchain);
     debug!("Initialized blockchain database");
 
     // init wallet database
-    let wallet = Wallet::new(&matches.value_of("wallet_db").unwrap()).unwrap();
+    let wallet = if resume {
+        Wallet::load(&matches.value_of("wallet_db").unwrap())
+    } else {
+        Wallet::new(&matches.value_of("wallet_db").unwrap())
+    }
+    .unwrap_or_else(|e| {
+        error!("Error opening wallet database: {}", e);
+        process::exit(1);
+    });
     let wallet = Arc::new(wallet);
-    debug!("Initialized wallet");
+    debug!("Initialized wallet with {} coins", wallet.number_of_coins());
 
     // load wallet keys
     if let Some(wallet_keys) = matches.values_of("load_key_path") {
@@ -232,7 +413,12 @@ fn main() {
         }
     }
 
-    // start thread to update ledger
+    // create wallet key pair if there is none
+    if wallet.addresses().unwrap().is_empty() {
+        wallet.generate_keypair().unwrap();
+    }
+
+    // parse the settings of the ledger manager
     let tx_workers = matches
         .value_of("execution_workers")
         .unwrap()
@@ -249,12 +435,6 @@ fn main() {
             error!("Error parsing transaction execution buffer size: {}", e);
             process::exit(1);
         });
-    let ledger_manager = LedgerManager::new(&blockdb, &blockchain, &utxodb, &wallet);
-    ledger_manager.start(tx_buffer, tx_workers);
-    debug!(
-        "Initialized ledger manager with buffer size {} and {} workers",
-        tx_buffer, tx_workers
-    );
 
     // parse p2p server address
     let p2p_addr = matches
@@ -276,16 +456,84 @@ fn main() {
             process::exit(1);
         });
 
-    // create channels between server and worker, worker and miner, miner and worker
-    let (msg_tx, msg_rx) = piper::chan(100); // TODO: make this buffer adjustable
-    let (ctx_tx, ctx_rx) = channel::unbounded();
-    let ctx_tx_miner = ctx_tx.clone();
+    // parse the sizes of the channels between server and worker
+    let lane_sizes = LaneSizes {
+        consensus: matches
+            .value_of("consensus_queue_size")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing consensus queue size: {}", e);
+                process::exit(1);
+            }),
+        payload: matches
+            .value_of("payload_queue_size")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing payload queue size: {}", e);
+                process::exit(1);
+            }),
+    };
 
-    // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    // load the address book
+    let address_book = AddressBook::load(&matches.value_of("address_book").unwrap())
+        .unwrap_or_else(|e| {
+            error!("Error loading address book: {}", e);
+            process::exit(1);
+        });
+    debug!("Loaded address book with {} addresses", address_book.len());
+    let outgoing_peers = matches
+        .value_of("outgoing_peers")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing number of outgoing peers: {}", e);
+            process::exit(1);
+        });
 
-    // start the worker
+    let network_id = matches
+        .value_of("network_id")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing network ID: {}", e);
+            process::exit(1);
+        });
+
+    let max_frame_size = matches
+        .value_of("max_frame_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing maximum frame size: {}", e);
+            process::exit(1);
+        });
+    let frame_limits = FrameLimits {
+        blocks: max_frame_size,
+        transactions: max_frame_size,
+        ..Default::default()
+    };
+    let write_queue_config = WriteQueueConfig {
+        max_queued_bytes: matches
+            .value_of("write_queue_size")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing write queue size: {}", e);
+                process::exit(1);
+            }),
+        slow_peer_policy: matches
+            .value_of("slow_peer_policy")
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|e| {
+                error!("Error parsing slow peer policy: {}", e);
+                process::exit(1);
+            }),
+    };
+
+    // parse the settings of the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
         .unwrap()
@@ -294,36 +542,82 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
-    let worker_ctx = worker::new(
+    let buffer_limits = BufferLimits {
+        max_blocks: matches
+            .value_of("buffer_size")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing buffer size: {}", e);
+                process::exit(1);
+            }),
+        max_blocks_per_peer: matches
+            .value_of("buffer_peer_quota")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing buffer peer quota: {}", e);
+                process::exit(1);
+            }),
+        ..Default::default()
+    };
+
+    // the miner sends the rewards to the first address in the wallet unless told otherwise
+    let miner_addr: Address = match matches.value_of("miner_addr") {
+        Some(addr) => {
+            let decoded = base64::decode(&addr.trim()).unwrap_or_else(|e| {
+                error!("Error decoding miner address {}: {}", &addr.trim(), e);
+                process::exit(1);
+            });
+            if decoded.len() < 32 {
+                error!("Miner address {} is too short", &addr.trim());
+                process::exit(1);
+            }
+            let addr_bytes: [u8; 32] = (&decoded[0..32]).try_into().unwrap();
+            addr_bytes.into()
+        }
+        None => wallet.addresses().unwrap()[0],
+    };
+    info!("Mining rewards go to address {}", &miner_addr);
+
+    // start the node. the miner starts once we have caught up with the peers
+    let node_config = NodeConfig {
+        p2p_addr,
+        address_book,
+        outgoing_peers,
+        network_id,
+        frame_limits,
+        write_queue_config,
+        lane_sizes,
+        buffer_limits,
         p2p_workers,
-        msg_rx,
-        &blockchain,
+        execution_workers: tx_workers,
+        execution_buffer: tx_buffer,
+        compact_blocks: !matches.is_present("no_compact_blocks"),
+        bootstrap: matches.is_present("known_peer"),
+        headers_first: !matches.is_present("no_headers_first"),
+        miner_addr,
+    };
+    let node = node::start(
         &blockdb,
+        &blockchain,
         &utxodb,
         &wallet,
         &mempool,
-        ctx_tx,
-        &server,
-        config.clone(),
-    );
-    worker_ctx.start();
-
-    // start the miner
-    let (miner_ctx, miner) = miner::new(
-        &mempool,
-        &blockchain,
-        &blockdb,
-        ctx_rx,
-        &ctx_tx_miner,
-        &server,
+        node_config,
         config.clone(),
-    );
-    miner_ctx.start();
+        Arc::new(TcpTransport),
+    )
+    .unwrap_or_else(|e| {
+        error!("Error starting P2P server: {}", e);
+        process::exit(1);
+    });
 
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
+        let server = node.server.clone();
+        let sync = node.sync.clone();
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
@@ -335,8 +629,9 @@ fn main() {
                         }
                     };
                     match server.connect(addr) {
-                        Ok(_) => {
+                        Ok(handle) => {
                             info!("Connected to outgoing peer {}", &addr);
+                            sync.add_peer(handle);
                             break;
                         }
                         Err(e) => {
@@ -353,53 +648,21 @@ fn main() {
         });
     }
 
-    // fund the given addresses
-    if let Some(fund_addrs) = matches.values_of("init_fund_addr") {
-        let num_coins = matches
-            .value_of("init_fund_coins")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap_or_else(|e| {
-                error!("Error parsing number of initial fund coins: {}", e);
-                process::exit(1);
-            });
-        let coin_value = matches
-            .value_of("init_fund_value")
-            .unwrap()
-            .parse::<u64>()
-            .unwrap_or_else(|e| {
-                error!("Error parsing value of initial fund coins: {}", e);
-                process::exit(1);
-            });
-        let mut addrs = vec![];
-        for addr in fund_addrs {
-            let decoded = match base64::decode(&addr.trim()) {
-                Ok(d) => d,
-                Err(e) => {
-                    error!("Error decoding address {}: {}", &addr.trim(), e);
-                    process::exit(1);
-                }
-            };
-            let addr_bytes: [u8; 32] = (&decoded[0..32]).try_into().unwrap();
-            let hash: H256 = addr_bytes.into();
-            addrs.push(hash);
-        }
-        info!(
-            "Funding {} addresses with {} initial coins of {}",
-            addrs.len(),
-            num_coins,
-            coin_value
-        );
-        prism::experiment::ico(&addrs, &utxodb, &wallet, num_coins, coin_value).unwrap();
-    }
-
-    // create wallet key pair if there is none
-    if wallet.addresses().unwrap().is_empty() {
-        wallet.generate_keypair().unwrap();
+    // fund the given addresses. when resuming, the initial fund may already be in the UTXO
+    // database and may have been spent, so we must not add it again
+    let funded = utxodb.funded().unwrap_or_else(|e| {
+        error!("Error reading UTXO database: {}", e);
+        process::exit(1);
+    });
+    if funded && matches.is_present("init_fund_addr") {
+        info!("Skipping initial funding since the UTXO database already has it");
+    } else {
+        fund(&matches, &utxodb, &wallet);
     }
 
     // start the transaction generator
-    let (txgen_ctx, txgen_control_chan) = TransactionGenerator::new(&wallet, &server, &mempool);
+    let (txgen_ctx, txgen_control_chan) =
+        TransactionGenerator::new(&wallet, &node.relay, &mempool, &utxodb);
     txgen_ctx.start();
 
     // start the API server
@@ -408,9 +671,10 @@ fn main() {
         &wallet,
         &blockchain,
         &utxodb,
-        &server,
-        &miner,
+        &node.server,
+        &node.miner,
         &mempool,
+        &node.relay,
         txgen_control_chan,
     );
 
@@ -428,3 +692,47 @@ fn main() {
         std::thread::park();
     }
 }
+
+/// Endow the addresses given in the command line arguments an initial fund.
+fn fund(matches: &clap::ArgMatches, utxodb: &Arc<UtxoDatabase>, wallet: &Arc<Wallet>) {
+    let fund_addrs = match matches.values_of("init_fund_addr") {
+        Some(a) => a,
+        None => return,
+    };
+    let num_coins = matches
+        .value_of("init_fund_coins")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing number of initial fund coins: {}", e);
+            process::exit(1);
+        });
+    let coin_value = matches
+        .value_of("init_fund_value")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing value of initial fund coins: {}", e);
+            process::exit(1);
+        });
+    let mut addrs = vec![];
+    for addr in fund_addrs {
+        let decoded = match base64::decode(&addr.trim()) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Error decoding address {}: {}", &addr.trim(), e);
+                process::exit(1);
+            }
+        };
+        let addr_bytes: [u8; 32] = (&decoded[0..32]).try_into().unwrap();
+        let hash: H256 = addr_bytes.into();
+        addrs.push(hash);
+    }
+    info!(
+        "Funding {} addresses with {} initial coins of {}",
+        addrs.len(),
+        num_coins,
+        coin_value
+    );
+    prism::experiment::ico(&addrs, utxodb, wallet, num_coins, coin_value).unwrap();
+}
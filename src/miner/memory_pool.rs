@@ -1,8 +1,16 @@
+use crate::block::transaction::short_id;
 use crate::crypto::hash::{Hashable, H256};
 use crate::transaction::{CoinId, Input, Transaction};
+use std::cmp::Reverse;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Fee rates are kept in units of 1/FEE_RATE_SCALE per byte, so that small fees are not rounded
+/// down to zero.
+const FEE_RATE_SCALE: u128 = 1000;
 
 /// transactions storage
 #[derive(Debug)]
@@ -11,14 +19,22 @@ pub struct MemoryPool {
     num_transactions: u64,
     /// Maximum number that the memory pool can hold
     max_transactions: u64,
+    /// How long a transaction may stay in the memory pool
+    max_age: Duration,
     /// Counter for storage index
     counter: u64,
     /// By-hash storage
     by_hash: HashMap<H256, Entry>,
     /// Transactions by previous output, formatted as Input
     by_input: HashMap<Input, H256>,
+    /// Transactions by short ID, used to rebuild compact transaction blocks. If short IDs collide,
+    /// only the latest transaction is kept
+    by_short_id: HashMap<u64, H256>,
     /// Storage for order by storage index, it is equivalent to FIFO
     by_storage_index: BTreeMap<u64, H256>,
+    /// Storage for order by fee rate. Among transactions of the same fee rate, the older ones come
+    /// last, so that they are the first to be mined and the last to be evicted
+    by_fee_rate: BTreeMap<(u64, Reverse<u64>), H256>,
 }
 
 #[derive(Debug, Clone)]
@@ -27,45 +43,173 @@ pub struct Entry {
     pub transaction: Transaction,
     /// counter of the tx
     storage_index: u64,
+    /// Fee of the tx
+    pub fee: u64,
+    /// Serialized size of the tx in bytes
+    pub size: u64,
+    /// Time when the tx entered the memory pool
+    time: Instant,
+    /// Transactions in the memory pool whose outputs this tx spends
+    parents: HashSet<H256>,
+    /// Transactions in the memory pool that spend the outputs of this tx
+    children: HashSet<H256>,
+}
+
+impl Entry {
+    /// Get the fee rate of the tx in units of 1/FEE_RATE_SCALE per byte.
+    fn fee_rate(&self) -> u64 {
+        let rate = u128::from(self.fee) * FEE_RATE_SCALE / u128::from(std::cmp::max(self.size, 1));
+        std::cmp::min(rate, u128::from(std::u64::MAX)) as u64
+    }
+
+    fn priority(&self) -> (u64, Reverse<u64>) {
+        (self.fee_rate(), Reverse(self.storage_index))
+    }
 }
 
 impl MemoryPool {
-    pub fn new(size_limit: u64) -> Self {
+    pub fn new(size_limit: u64, max_age: Duration) -> Self {
         Self {
             num_transactions: 0,
             max_transactions: size_limit,
+            max_age,
             counter: 0,
             by_hash: HashMap::new(),
             by_input: HashMap::new(),
+            by_short_id: HashMap::new(),
             by_storage_index: BTreeMap::new(),
+            by_fee_rate: BTreeMap::new(),
         }
     }
 
-    /// Insert a tx into memory pool. The input of it will also be recorded.
-    pub fn insert(&mut self, tx: Transaction) {
-        if self.num_transactions > self.max_transactions {
-            return;
-        }
+    /// Insert a tx into memory pool. The input of it will also be recorded. If the memory pool is
+    /// full, the transactions of the lowest fee rates are evicted to make room, as long as their
+    /// fee rates are lower than that of the new tx. Returns whether the tx is inserted.
+    pub fn insert(&mut self, tx: Transaction) -> bool {
         // assumes no duplicates nor double spends
         let hash = tx.hash();
-        let entry = Entry {
+        let mut entry = Entry {
+            fee: tx.fee().unwrap_or(0),
+            size: bincode::serialized_size(&tx).unwrap(),
             transaction: tx,
             storage_index: self.counter,
+            time: Instant::now(),
+            parents: HashSet::new(),
+            children: HashSet::new(),
         };
+
+        // link the tx with the unconfirmed transactions that it spends from and that spend it
+        for input in &entry.transaction.input {
+            if self.by_hash.contains_key(&input.coin.hash) {
+                entry.parents.insert(input.coin.hash);
+            }
+        }
+        for (index, output) in entry.transaction.output.iter().enumerate() {
+            let spent_by = Input {
+                coin: CoinId {
+                    hash,
+                    index: index as u32,
+                },
+                value: output.value,
+                owner: output.recipient,
+            };
+            if let Some(child) = self.by_input.get(&spent_by) {
+                entry.children.insert(*child);
+            }
+        }
+
+        if self.num_transactions >= self.max_transactions && !self.make_room(&entry) {
+            return false;
+        }
         self.counter += 1;
 
+        for parent in &entry.parents {
+            self.by_hash.get_mut(parent).unwrap().children.insert(hash);
+        }
+        for child in &entry.children {
+            self.by_hash.get_mut(child).unwrap().parents.insert(hash);
+        }
+
         // associate all inputs with this transaction
         for input in &entry.transaction.input {
             self.by_input.insert(input.clone(), hash);
         }
 
+        self.by_short_id.insert(short_id(&hash), hash);
+
         // add to btree
         self.by_storage_index.insert(entry.storage_index, hash);
+        self.by_fee_rate.insert(entry.priority(), hash);
 
         // add to hashmap
         self.by_hash.insert(hash, entry);
 
         self.num_transactions += 1;
+        true
+    }
+
+    /// Evict transactions of lower fee rates than the given entry, together with their
+    /// descendants, until there is room for the entry. A transaction is kept if any of its
+    /// descendants pays a fee rate as high as the entry, and the ancestors and the descendants of
+    /// the entry are never evicted. Returns whether there is room for the entry.
+    fn make_room(&mut self, entry: &Entry) -> bool {
+        let mut related = self.ancestors(entry.parents.iter());
+        related.extend(self.descendants(entry.children.iter()));
+        let rate = entry.fee_rate();
+        let mut victims: HashSet<H256> = HashSet::new();
+        for (priority, hash) in &self.by_fee_rate {
+            if self.num_transactions - (victims.len() as u64) < self.max_transactions {
+                break;
+            }
+            if priority.0 >= rate {
+                return false;
+            }
+            if related.contains(hash) || victims.contains(hash) {
+                continue;
+            }
+            let descendants = self.descendants(std::iter::once(hash));
+            if !descendants.is_disjoint(&related) {
+                continue;
+            }
+            if descendants
+                .iter()
+                .any(|d| self.by_hash[d].fee_rate() >= rate)
+            {
+                continue;
+            }
+            victims.extend(descendants);
+        }
+        if self.num_transactions - (victims.len() as u64) >= self.max_transactions {
+            return false;
+        }
+        for hash in &victims {
+            self.remove_and_get(hash);
+        }
+        true
+    }
+
+    /// Get the given transactions and all their ancestors in the memory pool.
+    fn ancestors<'a>(&self, hashes: impl Iterator<Item = &'a H256>) -> HashSet<H256> {
+        let mut result: HashSet<H256> = HashSet::new();
+        let mut queue: VecDeque<H256> = hashes.copied().collect();
+        while let Some(hash) = queue.pop_front() {
+            if result.insert(hash) {
+                queue.extend(&self.by_hash[&hash].parents);
+            }
+        }
+        result
+    }
+
+    /// Get the given transactions and all their descendants in the memory pool.
+    fn descendants<'a>(&self, hashes: impl Iterator<Item = &'a H256>) -> HashSet<H256> {
+        let mut result: HashSet<H256> = HashSet::new();
+        let mut queue: VecDeque<H256> = hashes.copied().collect();
+        while let Some(hash) = queue.pop_front() {
+            if result.insert(hash) {
+                queue.extend(&self.by_hash[&hash].children);
+            }
+        }
+        result
     }
 
     pub fn get(&self, h: &H256) -> Option<&Entry> {
@@ -73,6 +217,12 @@ impl MemoryPool {
         Some(entry)
     }
 
+    /// Get a tx by its short ID.
+    pub fn get_by_short_id(&self, id: u64) -> Option<&Entry> {
+        let hash = self.by_short_id.get(&id)?;
+        self.get(hash)
+    }
+
     /// Check whether a tx hash is in memory pool
     /// When adding tx into mempool, should check this.
     pub fn contains(&self, h: &H256) -> bool {
@@ -90,7 +240,22 @@ impl MemoryPool {
         for input in &entry.transaction.input {
             self.by_input.remove(&input);
         }
+        for parent in &entry.parents {
+            if let Some(p) = self.by_hash.get_mut(parent) {
+                p.children.remove(hash);
+            }
+        }
+        for child in &entry.children {
+            if let Some(c) = self.by_hash.get_mut(child) {
+                c.parents.remove(hash);
+            }
+        }
         self.by_storage_index.remove(&entry.storage_index);
+        self.by_fee_rate.remove(&entry.priority());
+        let id = short_id(hash);
+        if self.by_short_id.get(&id) == Some(hash) {
+            self.by_short_id.remove(&id);
+        }
         self.num_transactions -= 1;
         Some(entry)
     }
@@ -125,11 +290,71 @@ impl MemoryPool {
         }
     }
 
-    /// get n transaction by fifo
+    /// Remove the transactions that have stayed in the memory pool for too long, together with
+    /// their descendants.
+    pub fn expire(&mut self, now: Instant) {
+        let mut expired: Vec<H256> = vec![];
+        // storage indices follow the time of arrival
+        for hash in self.by_storage_index.values() {
+            let entry = &self.by_hash[hash];
+            if entry.time + self.max_age > now {
+                break;
+            }
+            expired.push(*hash);
+        }
+        for hash in &expired {
+            if !self.contains(hash) {
+                continue;
+            }
+            for d in self.descendants(std::iter::once(hash)) {
+                self.remove_and_get(&d);
+            }
+        }
+    }
+
+    /// Get at most n transactions in the order of fee rates. A tx always comes after the
+    /// transactions in the memory pool that it spends from, and it is left out if any of them is.
     pub fn get_transactions(&self, n: u32) -> Vec<Transaction> {
-        self.by_storage_index
-            .values()
-            .take(n as usize)
+        let mut selected: Vec<H256> = vec![];
+        let mut selected_set: HashSet<H256> = HashSet::new();
+        // transactions waiting for their parents to be selected
+        let mut waiting: HashSet<H256> = HashSet::new();
+        for hash in self.by_fee_rate.values().rev() {
+            if selected.len() >= n as usize {
+                break;
+            }
+            if !self.by_hash[hash]
+                .parents
+                .iter()
+                .all(|p| selected_set.contains(p))
+            {
+                waiting.insert(*hash);
+                continue;
+            }
+            // select the tx, then the waiting descendants whose parents are all selected now
+            let mut queue: VecDeque<H256> = VecDeque::new();
+            queue.push_back(*hash);
+            while let Some(h) = queue.pop_front() {
+                if selected.len() >= n as usize {
+                    break;
+                }
+                selected.push(h);
+                selected_set.insert(h);
+                for child in &self.by_hash[&h].children {
+                    if waiting.contains(child)
+                        && self.by_hash[child]
+                            .parents
+                            .iter()
+                            .all(|p| selected_set.contains(p))
+                    {
+                        waiting.remove(child);
+                        queue.push_back(*child);
+                    }
+                }
+            }
+        }
+        selected
+            .iter()
             .map(|hash| self.get(hash).unwrap().transaction.clone())
             .collect()
     }
@@ -141,4 +366,114 @@ impl MemoryPool {
 }
 
 #[cfg(test)]
-pub mod tests {}
+pub mod tests {
+    use super::MemoryPool;
+    use crate::crypto::hash::{Hashable, H256};
+    use crate::transaction::{CoinId, Input, Output, Transaction};
+    use std::time::{Duration, Instant};
+
+    /// Create a transaction spending the given coin of the given value, paying the given fee.
+    fn transaction(coin: CoinId, value: u64, fee: u64) -> Transaction {
+        Transaction {
+            input: vec![Input {
+                coin,
+                value,
+                owner: H256::default(),
+            }],
+            output: vec![Output {
+                value: value - fee,
+                recipient: H256::default(),
+            }],
+            authorization: vec![],
+            hash: Default::default(),
+        }
+    }
+
+    fn coin(seed: u8) -> CoinId {
+        CoinId {
+            hash: [seed; 32].into(),
+            index: 0,
+        }
+    }
+
+    fn child(parent: &Transaction, fee: u64) -> Transaction {
+        let coin = CoinId {
+            hash: parent.hash(),
+            index: 0,
+        };
+        transaction(coin, parent.output[0].value, fee)
+    }
+
+    #[test]
+    fn fee_rate_order_and_ancestors() {
+        let mut mempool = MemoryPool::new(100, Duration::from_secs(60));
+        let low = transaction(coin(1), 1000, 1);
+        let high = transaction(coin(2), 1000, 50);
+        // a child that pays well for a parent that pays poorly
+        let parent = transaction(coin(3), 1000, 0);
+        let rich_child = child(&parent, 100);
+        assert!(mempool.insert(low.clone()));
+        assert!(mempool.insert(high.clone()));
+        assert!(mempool.insert(rich_child.clone()));
+        assert!(mempool.insert(parent.clone()));
+
+        let txs = mempool.get_transactions(4);
+        assert_eq!(
+            txs,
+            vec![high.clone(), low.clone(), parent.clone(), rich_child]
+        );
+        // the child is left out if its parent is
+        let txs = mempool.get_transactions(2);
+        assert_eq!(txs, vec![high, low]);
+
+        // removing the parent by its input removes the child as well
+        mempool.remove_by_input(&parent.input[0]);
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn eviction_and_expiry() {
+        let mut mempool = MemoryPool::new(3, Duration::from_secs(60));
+        let parent = transaction(coin(1), 1000, 1);
+        let poor_child = child(&parent, 2);
+        assert!(mempool.insert(parent.clone()));
+        assert!(mempool.insert(transaction(coin(2), 1000, 20)));
+        assert!(mempool.insert(poor_child.clone()));
+        assert_eq!(mempool.len(), 3);
+
+        // a tx paying no more than the cheapest one is rejected
+        assert!(!mempool.insert(transaction(coin(3), 1000, 0)));
+        // a tx paying more evicts the cheapest one and its descendants
+        assert!(mempool.insert(transaction(coin(4), 1000, 30)));
+        assert_eq!(mempool.len(), 2);
+        assert!(!mempool.contains(&parent.hash()));
+        assert!(!mempool.contains(&poor_child.hash()));
+
+        // transactions expire after the maximum age
+        mempool.expire(Instant::now());
+        assert_eq!(mempool.len(), 2);
+        mempool.expire(Instant::now() + Duration::from_secs(60));
+        assert_eq!(mempool.len(), 0);
+        assert!(mempool.get_transactions(10).is_empty());
+    }
+
+    #[test]
+    fn eviction_keeps_rich_descendants() {
+        let mut mempool = MemoryPool::new(2, Duration::from_secs(60));
+        let parent = transaction(coin(1), 1000, 0);
+        let rich_child = child(&parent, 100);
+        assert!(mempool.insert(parent.clone()));
+        assert!(mempool.insert(rich_child.clone()));
+
+        // the parent pays less than the tx, but its child pays more
+        assert!(!mempool.insert(transaction(coin(2), 1000, 30)));
+        assert!(mempool.contains(&parent.hash()));
+        assert!(mempool.contains(&rich_child.hash()));
+
+        // a tx paying more than both evicts them
+        assert!(mempool.insert(transaction(coin(3), 1000, 200)));
+        assert_eq!(mempool.len(), 1);
+        assert!(!mempool.contains(&parent.hash()));
+        assert!(!mempool.contains(&rich_child.hash()));
+    }
+}
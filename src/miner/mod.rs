@@ -12,6 +12,8 @@ use crate::experiment::performance_counter::PERFORMANCE_COUNTER;
 use crate::handler::new_validated_block;
 use crate::network::message::Message;
 use crate::network::server::Handle as ServerHandle;
+use crate::transaction::Address;
+use crate::validation;
 
 use log::info;
 
@@ -82,6 +84,7 @@ pub fn new(
     ctx_update_source: Receiver<ContextUpdateSignal>,
     ctx_update_tx: &Sender<ContextUpdateSignal>,
     server: &ServerHandle,
+    address: Address,
     config: BlockchainConfig,
 ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
@@ -125,6 +128,7 @@ pub fn new(
             content_merkle_root: H256::default(),
             extra_content: [0; 32],
             difficulty: *DEFAULT_DIFFICULTY,
+            miner: address,
         },
         contents,
         content_merkle_tree,
@@ -262,7 +266,8 @@ impl Context {
 
             // update transaction block content
             if new_transaction_block {
-                let mempool = self.mempool.lock().unwrap();
+                let mut mempool = self.mempool.lock().unwrap();
+                mempool.expire(time::Instant::now());
                 let transactions = mempool.get_transactions(self.config.tx_txs);
                 drop(mempool);
                 let _chain_id: usize = TRANSACTION_INDEX as usize;
@@ -506,12 +511,7 @@ impl Context {
     /// Calculate the difficulty for the block to be mined
     // TODO: shall we make a dedicated type for difficulty?
     fn get_difficulty(&self, block_hash: &H256) -> H256 {
-        // Get the header of the block corresponding to block_hash
-        match self.blockdb.get(block_hash).unwrap() {
-            // extract difficulty
-            Some(b) => b.header.difficulty,
-            None => *DEFAULT_DIFFICULTY,
-        }
+        validation::expected_difficulty(block_hash, &self.blockchain, &self.blockdb, &self.config)
     }
 }
 
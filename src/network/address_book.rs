@@ -0,0 +1,188 @@
+use log::debug;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Number of consecutive failed connection attempts after which an address is forgotten.
+const MAX_FAILURES: u32 = 3;
+/// Maximum number of known addresses, so that peers cannot grow the address book without limit.
+pub const MAX_ADDRS: usize = 4096;
+
+/// Addresses of the peers that we know about, persisted to a text file with one address per line.
+pub struct AddressBook {
+    path: PathBuf,
+    /// Known addresses, and the number of consecutive failed connection attempts to each of them.
+    addrs: HashMap<SocketAddr, u32>,
+    /// Whether the content has changed since the last save.
+    dirty: bool,
+}
+
+impl AddressBook {
+    /// Load the address book from the given path. The address book is empty if the file does not
+    /// exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let mut addrs = HashMap::new();
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                for line in content.lines().take(MAX_ADDRS) {
+                    match line.trim().parse::<SocketAddr>() {
+                        Ok(addr) => {
+                            addrs.insert(addr, 0);
+                        }
+                        Err(_) => debug!("Ignoring invalid address {} in address book", line),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            addrs,
+            dirty: false,
+        })
+    }
+
+    /// Add an address. Returns whether the address is new and has been added. If the address book
+    /// is full, the address that has failed the most is forgotten to make room, and the new
+    /// address is dropped if none has failed.
+    pub fn insert(&mut self, addr: SocketAddr) -> bool {
+        if self.addrs.contains_key(&addr) {
+            return false;
+        }
+        if self.addrs.len() >= MAX_ADDRS && !self.evict(false) {
+            return false;
+        }
+        self.addrs.insert(addr, 0);
+        self.dirty = true;
+        true
+    }
+
+    /// Record a successful connection to the given address.
+    pub fn mark_connected(&mut self, addr: SocketAddr) {
+        if !self.addrs.contains_key(&addr) && self.addrs.len() >= MAX_ADDRS {
+            self.evict(true);
+        }
+        if self.addrs.insert(addr, 0).is_none() {
+            self.dirty = true;
+        }
+    }
+
+    /// Record a failed connection attempt to the given address, and forget the address if it
+    /// fails too many times in a row.
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        if let Some(failures) = self.addrs.get_mut(&addr) {
+            *failures += 1;
+            if *failures >= MAX_FAILURES {
+                self.addrs.remove(&addr);
+                self.dirty = true;
+                debug!("Removed unreachable address {} from address book", addr);
+            }
+        }
+    }
+
+    /// Forget the address that has failed the most. Unless `force` is set, addresses that have
+    /// never failed are kept. Returns whether an address is forgotten.
+    fn evict(&mut self, force: bool) -> bool {
+        let worst = self
+            .addrs
+            .iter()
+            .max_by_key(|(_, failures)| **failures)
+            .map(|(addr, failures)| (*addr, *failures));
+        match worst {
+            Some((addr, failures)) if force || failures > 0 => {
+                self.addrs.remove(&addr);
+                self.dirty = true;
+                debug!("Removed address {} from full address book", addr);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Get all known addresses.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.keys().copied().collect()
+    }
+
+    /// Randomly choose at most `num` addresses that are not in `exclude`.
+    pub fn sample(&self, num: usize, exclude: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let mut candidates: Vec<SocketAddr> = self
+            .addrs
+            .keys()
+            .filter(|a| !exclude.contains(a))
+            .copied()
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+        candidates.truncate(num);
+        candidates
+    }
+
+    /// Get the number of known addresses.
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Check whether there is no known address.
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    /// Write the address book to the disk if it has changed.
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        // write to a temporary file first, so that we don't end up with a truncated file
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        for addr in self.addrs.keys() {
+            writeln!(file, "{}", addr)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressBook, MAX_ADDRS};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn capacity() {
+        let path = "/tmp/prism_test_address_book_capacity.txt";
+        let _ = std::fs::remove_file(path);
+        let mut book = AddressBook::load(path).unwrap();
+        let addr = |i: usize| -> SocketAddr {
+            format!("10.0.{}.{}:6000", i / 256, i % 256)
+                .parse()
+                .unwrap()
+        };
+        for i in 0..MAX_ADDRS {
+            assert!(book.insert(addr(i)));
+        }
+
+        // a full address book keeps the addresses that have never failed
+        assert!(!book.insert(addr(MAX_ADDRS)));
+        assert_eq!(book.len(), MAX_ADDRS);
+
+        // and forgets the one that has failed the most to make room
+        book.mark_failed(addr(1));
+        book.mark_failed(addr(2));
+        book.mark_failed(addr(2));
+        assert!(book.insert(addr(MAX_ADDRS)));
+        assert_eq!(book.len(), MAX_ADDRS);
+        assert!(!book.addrs().contains(&addr(2)));
+        assert!(book.addrs().contains(&addr(1)));
+
+        // the saved address book loads within the limit
+        book.save().unwrap();
+        assert_eq!(AddressBook::load(path).unwrap().len(), MAX_ADDRS);
+    }
+}
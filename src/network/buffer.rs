@@ -1,10 +1,53 @@
 use crate::block::Block;
 use crate::crypto::hash::{Hashable, H256};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time;
+
+/// Limits on the blocks being buffered.
+#[derive(Clone, Debug)]
+pub struct BufferLimits {
+    /// Maximum number of blocks in the buffer.
+    pub max_blocks: usize,
+    /// Maximum number of blocks from a single peer in the buffer.
+    pub max_blocks_per_peer: usize,
+    /// How long a block may wait for its dependencies.
+    pub max_age: time::Duration,
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        Self {
+            max_blocks: 50000,
+            max_blocks_per_peer: 10000,
+            max_age: time::Duration::from_secs(600),
+        }
+    }
+}
+
+impl BufferLimits {
+    /// No limit at all, e.g. for buffering blocks from the local database.
+    pub fn unbounded() -> Self {
+        Self {
+            max_blocks: usize::max_value(),
+            max_blocks_per_peer: usize::max_value(),
+            max_age: time::Duration::from_secs(u64::max_value()),
+        }
+    }
+}
+
+/// A buffered block, and where and when it comes from.
+struct Entry {
+    block: Block,
+    peer: Option<SocketAddr>,
+    arrival: time::Instant,
+    seq: u64,
+}
 
 pub struct BlockBuffer {
+    limits: BufferLimits,
     /// All blocks that have been received but not processed.
-    blocks: HashMap<H256, Block>,
+    blocks: HashMap<H256, Entry>,
     // TODO: we could use a sorted vector for better performance
     /// Mapping between all blocks that have been received and not processed, and their
     /// dependencies
@@ -12,19 +55,37 @@ pub struct BlockBuffer {
     /// Mapping between all blocks that have not been processed (but either received or
     /// not), and their dependents
     dependent: HashMap<H256, HashSet<H256>>,
+    /// Buffered blocks in the order that they arrive
+    by_arrival: BTreeMap<u64, H256>,
+    /// Arrival order of the buffered blocks from each peer
+    by_peer: HashMap<SocketAddr, BTreeSet<u64>>,
+    /// Counter for arrival order
+    counter: u64,
 }
 
 impl BlockBuffer {
-    pub fn new() -> Self {
+    pub fn new(limits: BufferLimits) -> Self {
         Self {
+            limits,
             blocks: HashMap::new(),
             dependency: HashMap::new(),
             dependent: HashMap::new(),
+            by_arrival: BTreeMap::new(),
+            by_peer: HashMap::new(),
+            counter: 0,
         }
     }
 
-    /// Buffer a block whose parent and/or references are missing.
-    pub fn insert(&mut self, block: Block, dependencies: &[H256]) {
+    /// Buffer a block whose parent and/or references are missing. If this exceeds the quota of
+    /// the peer or the size limit, the oldest blocks of the peer or in the buffer are evicted.
+    /// Returns the hashes of the evicted blocks.
+    pub fn insert(
+        &mut self,
+        block: Block,
+        dependencies: &[H256],
+        peer: Option<SocketAddr>,
+        now: time::Instant,
+    ) -> Vec<H256> {
         // Potential race condition here: if X depends on A. Suppose X is received first and
         // validation finds that we miss block A. Then we need to insert X. However, at this moment
         // A comes. Unaware of X, we just process A without marking X's deps as satisfied. Then X
@@ -32,8 +93,25 @@ impl BlockBuffer {
         // validation and buffer insert are not one atomic operation (and we probably don't want
         // to do so). Conclusion: for now we make validation and buffer an atomic operation.
         let hash = block.hash();
+        if self.blocks.contains_key(&hash) {
+            return vec![];
+        }
 
-        self.blocks.insert(hash, block);
+        let seq = self.counter;
+        self.counter += 1;
+        self.blocks.insert(
+            hash,
+            Entry {
+                block,
+                peer,
+                arrival: now,
+                seq,
+            },
+        );
+        self.by_arrival.insert(seq, hash);
+        if let Some(peer) = peer {
+            self.by_peer.entry(peer).or_default().insert(seq);
+        }
 
         let mut dependency = HashSet::new();
         for dep_hash in dependencies {
@@ -46,6 +124,21 @@ impl BlockBuffer {
             dependent.insert(hash);
         }
         self.dependency.insert(hash, dependency);
+
+        // enforce the quota of the peer, then the size limit
+        let mut evicted = vec![];
+        if let Some(peer) = peer {
+            while self.by_peer.get(&peer).map_or(0, |s| s.len()) > self.limits.max_blocks_per_peer {
+                let oldest = *self.by_peer[&peer].iter().next().unwrap();
+                let oldest = self.by_arrival[&oldest];
+                evicted.extend(self.evict(oldest));
+            }
+        }
+        while self.blocks.len() > self.limits.max_blocks {
+            let oldest = *self.by_arrival.values().next().unwrap();
+            evicted.extend(self.evict(oldest));
+        }
+        evicted
     }
 
     /// Mark that the given block has been processed.
@@ -59,10 +152,169 @@ impl BlockBuffer {
                 dependency.remove(&hash);
                 if dependency.is_empty() {
                     self.dependency.remove(&node).unwrap();
-                    resolved_blocks.push(self.blocks.remove(&node).unwrap());
+                    let entry = self.remove_entry(node);
+                    resolved_blocks.push(entry.block);
                 }
             }
         }
         resolved_blocks
     }
+
+    /// Mark that the given block will never be processed, e.g. because it is invalid, and evict
+    /// the blocks that depend on it. Returns the hashes of the evicted blocks.
+    pub fn drop_dependency(&mut self, hash: &H256) -> Vec<H256> {
+        let mut evicted = vec![];
+        if let Some(dependents) = self.dependent.remove(hash) {
+            for node in dependents {
+                evicted.extend(self.evict(node));
+            }
+        }
+        evicted
+    }
+
+    /// Evict the blocks that have waited for their dependencies for too long. Returns the hashes
+    /// of the evicted blocks.
+    pub fn evict_expired(&mut self, now: time::Instant) -> Vec<H256> {
+        let mut evicted = vec![];
+        while let Some(oldest) = self.by_arrival.values().next() {
+            let oldest = *oldest;
+            if now.saturating_duration_since(self.blocks[&oldest].arrival) < self.limits.max_age {
+                break;
+            }
+            evicted.extend(self.evict(oldest));
+        }
+        evicted
+    }
+
+    /// Evict a block and the blocks that depend on it. Returns the hashes of the evicted blocks.
+    fn evict(&mut self, hash: H256) -> Vec<H256> {
+        let mut evicted = vec![];
+        let mut queue = vec![hash];
+        while let Some(hash) = queue.pop() {
+            if !self.blocks.contains_key(&hash) {
+                continue;
+            }
+            self.remove_entry(&hash);
+            // clean up the dependencies of the evicted block
+            for dep_hash in self.dependency.remove(&hash).unwrap() {
+                // the dependency may be the one being dropped, which is removed already
+                if let Some(dependent) = self.dependent.get_mut(&dep_hash) {
+                    dependent.remove(&hash);
+                    if dependent.is_empty() {
+                        self.dependent.remove(&dep_hash);
+                    }
+                }
+            }
+            // the blocks that depend on the evicted block cannot be processed anymore
+            if let Some(dependents) = self.dependent.remove(&hash) {
+                queue.extend(dependents);
+            }
+            evicted.push(hash);
+        }
+        evicted
+    }
+
+    /// Remove a block from the storage and the indices, but not the dependency graph.
+    fn remove_entry(&mut self, hash: &H256) -> Entry {
+        let entry = self.blocks.remove(hash).unwrap();
+        self.by_arrival.remove(&entry.seq);
+        if let Some(peer) = entry.peer {
+            let seqs = self.by_peer.get_mut(&peer).unwrap();
+            seqs.remove(&entry.seq);
+            if seqs.is_empty() {
+                self.by_peer.remove(&peer);
+            }
+        }
+        entry
+    }
+
+    /// Get the number of blocks being buffered.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Check whether no block is being buffered.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Get the number of missing blocks that the buffered blocks are waiting for.
+    pub fn num_dependencies(&self) -> usize {
+        self.dependent.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockBuffer, BufferLimits};
+    use crate::block::tests::proposer_block;
+    use crate::crypto::hash::{Hashable, H256};
+    use std::net::SocketAddr;
+    use std::time;
+
+    #[test]
+    fn limits_and_eviction() {
+        let limits = BufferLimits {
+            max_blocks: 3,
+            max_blocks_per_peer: 2,
+            max_age: time::Duration::from_secs(10),
+        };
+        let mut buffer = BlockBuffer::new(limits);
+        let now = time::Instant::now();
+        let a: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let missing = H256::default();
+
+        // a chain of blocks from peer a, waiting for a missing block
+        let x = proposer_block(missing, 0, vec![], vec![]);
+        let y = proposer_block(x.hash(), 1, vec![], vec![]);
+        assert!(buffer
+            .insert(x.clone(), &[missing], Some(a), now)
+            .is_empty());
+        assert!(buffer
+            .insert(y.clone(), &[x.hash()], Some(a), now)
+            .is_empty());
+
+        // the quota of peer a is exceeded, so its oldest block and the block depending on it go
+        let z = proposer_block(missing, 2, vec![], vec![]);
+        let evicted = buffer.insert(z.clone(), &[missing], Some(a), now);
+        assert_eq!(evicted.len(), 2);
+        assert!(evicted.contains(&x.hash()) && evicted.contains(&y.hash()));
+        assert_eq!(buffer.len(), 1);
+
+        // the size limit is exceeded, so the oldest block goes
+        let later = now + time::Duration::from_secs(5);
+        let w = proposer_block(missing, 3, vec![], vec![]);
+        assert!(buffer.insert(w, &[missing], Some(b), later).is_empty());
+        let w = proposer_block(missing, 4, vec![], vec![]);
+        assert!(buffer.insert(w, &[missing], None, later).is_empty());
+        let w = proposer_block(missing, 5, vec![], vec![]);
+        assert_eq!(buffer.insert(w, &[missing], None, later), vec![z.hash()]);
+        assert_eq!(buffer.len(), 3);
+
+        // old blocks expire
+        assert!(buffer.evict_expired(later).is_empty());
+        let expired = buffer.evict_expired(later + time::Duration::from_secs(10));
+        assert_eq!(expired.len(), 3);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.num_dependencies(), 0);
+    }
+
+    #[test]
+    fn drop_unavailable_dependency() {
+        let mut buffer = BlockBuffer::new(BufferLimits::unbounded());
+        let now = time::Instant::now();
+        let invalid = H256::default();
+        let other: H256 = [1u8; 32].into();
+        let x = proposer_block(invalid, 0, vec![], vec![]);
+        let y = proposer_block(other, 1, vec![], vec![]);
+        buffer.insert(x.clone(), &[invalid, other], None, now);
+        buffer.insert(y.clone(), &[other], None, now);
+        assert_eq!(buffer.drop_dependency(&invalid), vec![x.hash()]);
+        assert_eq!(buffer.num_dependencies(), 1);
+        let resolved = buffer.satisfy(other);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].hash(), y.hash());
+        assert!(buffer.is_empty());
+    }
 }
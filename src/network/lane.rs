@@ -0,0 +1,193 @@
+use super::message::{tag_kind, MessageKind};
+use super::peer;
+use crate::block::header::Header;
+use crate::experiment::performance_counter::PERFORMANCE_COUNTER;
+use futures::future::select_all;
+use std::convert::TryInto;
+
+/// Queues that incoming messages wait in before the workers process them. A worker always takes a
+/// message from the consensus lane if there is one, so that a flood of transactions and
+/// transaction blocks does not delay the proposer and voter blocks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Lane {
+    /// Proposer and voter blocks, requests and announcements of blocks, and control messages.
+    Consensus = 0,
+    /// Transactions and transaction blocks.
+    Payload = 1,
+}
+
+/// Lanes in the order that the workers take messages from them.
+const LANES: [Lane; 2] = [Lane::Consensus, Lane::Payload];
+
+/// Capacity of each lane, in number of messages.
+#[derive(Clone, Debug)]
+pub struct LaneSizes {
+    pub consensus: usize,
+    pub payload: usize,
+}
+
+impl Default for LaneSizes {
+    fn default() -> Self {
+        Self {
+            consensus: 1000,
+            payload: 100,
+        }
+    }
+}
+
+/// Get the lane of a message, given its encoding. Messages that cannot be recognized go in the
+/// payload lane, so that garbage from a peer never delays the consensus messages.
+pub fn classify(msg: &[u8]) -> Lane {
+    // the variant index of the message is encoded in the first 4 bytes
+    let tag = match msg.get(0..4) {
+        Some(tag) => u32::from_le_bytes(tag.try_into().unwrap()),
+        None => return Lane::Payload,
+    };
+    match tag_kind(tag) {
+        Some(MessageKind::Control) | Some(MessageKind::BlockInventory) => Lane::Consensus,
+        Some(MessageKind::Blocks) => classify_blocks(&msg[4..]),
+        Some(MessageKind::TransactionInventory)
+        | Some(MessageKind::CompactBlocks)
+        | Some(MessageKind::Transactions)
+        | None => Lane::Payload,
+    }
+}
+
+/// Get the lane of a list of encoded blocks, which goes in the consensus lane if any of the blocks
+/// is a proposer or voter block. Only the headers and the content types are decoded. Malformed
+/// lists go in the payload lane.
+fn classify_blocks(mut buf: &[u8]) -> Lane {
+    let num_blocks = match read_u64(&mut buf) {
+        Some(num) => num,
+        None => return Lane::Payload,
+    };
+    for _ in 0..num_blocks {
+        let size = match read_u64(&mut buf) {
+            Some(size) if size as usize <= buf.len() => size as usize,
+            _ => return Lane::Payload,
+        };
+        let (encoded_block, rest) = buf.split_at(size);
+        buf = rest;
+        if classify_block(encoded_block) == Lane::Consensus {
+            return Lane::Consensus;
+        }
+    }
+    Lane::Payload
+}
+
+/// Get the lane of an encoded block. Blocks that cannot be decoded go in the payload lane.
+pub fn classify_block(encoded_block: &[u8]) -> Lane {
+    // the content type comes right after the header, and transaction blocks are the first type
+    match bincode::deserialize::<(Header, u32)>(encoded_block) {
+        Ok((_, 0)) | Err(_) => Lane::Payload,
+        Ok(_) => Lane::Consensus,
+    }
+}
+
+fn read_u64(buf: &mut &[u8]) -> Option<u64> {
+    let bytes = buf.get(0..8)?;
+    let num = u64::from_le_bytes(bytes.try_into().unwrap());
+    *buf = &buf[8..];
+    Some(num)
+}
+
+/// The sending end of the lanes.
+#[derive(Clone)]
+pub struct Sender {
+    lanes: Vec<piper::Sender<(Vec<u8>, peer::Handle)>>,
+}
+
+/// The receiving end of the lanes.
+#[derive(Clone)]
+pub struct Receiver {
+    lanes: Vec<piper::Receiver<(Vec<u8>, peer::Handle)>>,
+}
+
+pub fn new(sizes: &LaneSizes) -> (Sender, Receiver) {
+    let (consensus_tx, consensus_rx) = piper::chan(sizes.consensus);
+    let (payload_tx, payload_rx) = piper::chan(sizes.payload);
+    let sender = Sender {
+        lanes: vec![consensus_tx, payload_tx],
+    };
+    let receiver = Receiver {
+        lanes: vec![consensus_rx, payload_rx],
+    };
+    (sender, receiver)
+}
+
+impl Sender {
+    /// Put a message in its lane, waiting if the lane is full.
+    pub async fn send(&self, msg: Vec<u8>, peer: peer::Handle) {
+        let lane = classify(&msg);
+        PERFORMANCE_COUNTER.record_receive_message(lane);
+        self.lanes[lane as usize].send((msg, peer)).await;
+    }
+}
+
+impl Receiver {
+    /// Take the next message, from the lane of the highest priority that has one. Blocks if all
+    /// lanes are empty, and returns None if the senders are gone.
+    pub fn recv(&self) -> Option<(Vec<u8>, peer::Handle)> {
+        // the futures are polled in order, so the first lane that is ready wins
+        let recvs = self.lanes.iter().map(|lane| Box::pin(lane.recv()));
+        let (msg, index, _) = futures::executor::block_on(select_all(recvs));
+        let msg = msg?;
+        PERFORMANCE_COUNTER.record_process_message(LANES[index]);
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, classify_block, Lane};
+    use crate::block::tests::{proposer_block, transaction_block};
+    use crate::crypto::hash::H256;
+    use crate::network::message::tests::samples;
+    use crate::network::message::{Message, MessageKind};
+
+    #[test]
+    fn classify_messages() {
+        let proposer =
+            bincode::serialize(&proposer_block(H256::default(), 0, vec![], vec![])).unwrap();
+        let transaction =
+            bincode::serialize(&transaction_block(H256::default(), 0, vec![])).unwrap();
+        assert_eq!(classify_block(&proposer), Lane::Consensus);
+        assert_eq!(classify_block(&transaction), Lane::Payload);
+
+        let msg = Message::Blocks(vec![transaction.clone(), transaction.clone()]);
+        assert_eq!(classify(&bincode::serialize(&msg).unwrap()), Lane::Payload);
+        let msg = Message::Blocks(vec![transaction, proposer]);
+        assert_eq!(
+            classify(&bincode::serialize(&msg).unwrap()),
+            Lane::Consensus
+        );
+        let msg = Message::GetBlocks(vec![H256::default()]);
+        assert_eq!(
+            classify(&bincode::serialize(&msg).unwrap()),
+            Lane::Consensus
+        );
+        let msg = Message::Transactions(vec![]);
+        assert_eq!(classify(&bincode::serialize(&msg).unwrap()), Lane::Payload);
+
+        // every message other than blocks goes in the lane of its kind
+        for msg in samples() {
+            let lane = match msg.kind() {
+                MessageKind::Control | MessageKind::BlockInventory => Lane::Consensus,
+                MessageKind::Blocks => continue,
+                _ => Lane::Payload,
+            };
+            assert_eq!(
+                classify(&bincode::serialize(&msg).unwrap()),
+                lane,
+                "{:?}",
+                msg
+            );
+        }
+
+        // garbage does not get into the consensus lane
+        assert_eq!(classify(&[1, 2]), Lane::Payload);
+        assert_eq!(classify(&1000u32.to_le_bytes()), Lane::Payload);
+        assert_eq!(classify(&[5, 0, 0, 0, 1]), Lane::Payload);
+        assert_eq!(classify_block(&[0; 8]), Lane::Payload);
+    }
+}
@@ -1,8 +1,18 @@
+use crate::block::transaction::CompactBlock;
+use crate::block::ProvenHeader;
+use crate::config::BlockchainConfig;
 use crate::crypto::hash::H256;
 use crate::transaction::Transaction;
+use crate::validation::TransactionResult;
+use std::net::SocketAddr;
+
+/// Version of the P2P protocol. Peers with different versions do not talk to each other.
+pub const PROTOCOL_VERSION: u32 = 2;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
+    /// The first message sent on a connection.
+    Handshake(Handshake),
     Ping(String),
     Pong(String),
     NewBlockHashes(Vec<H256>),
@@ -11,5 +21,339 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<Transaction>),
+    /// Ask for a batch of blocks that arrived after the given block.
     Bootstrap(H256),
+    /// The hash of the last block in a batch sent for bootstrap, and the number of blocks after it.
+    BootstrapProgress(H256, u64),
+    /// Ask for the addresses of the peers that the peer knows about.
+    GetPeers,
+    /// Addresses of peers that accept incoming connections.
+    Peers(Vec<SocketAddr>),
+    /// Ask for blocks, and for transaction blocks in the compact form.
+    GetCompactBlocks(Vec<H256>),
+    CompactBlocks(Vec<CompactBlock>),
+    /// Ask for the transactions at the given positions in a transaction block.
+    GetBlockTransactions(H256, Vec<u32>),
+    /// Transactions in a transaction block, in response to GetBlockTransactions.
+    BlockTransactions(H256, Vec<Transaction>),
+    /// Ask for the headers of a batch of blocks that arrived after the given block.
+    GetHeaders(H256),
+    /// Headers of a batch of blocks in the order that they arrived, and the number of blocks after
+    /// the last one.
+    Headers(Vec<ProvenHeader>, u64),
+    /// Transactions sent by the peer that did not enter our memory pool, and the reasons.
+    RejectedTransactions(Vec<(H256, TransactionResult)>),
+}
+
+/// Kinds of messages, which decide the maximum frame size of a message and the lane that it waits
+/// in before being decoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// Messages of a small, bounded size, e.g. handshakes and pings.
+    Control,
+    /// Lists of hashes, addresses or headers about blocks and peers.
+    BlockInventory,
+    /// Lists of hashes or positions about transactions.
+    TransactionInventory,
+    /// Blocks of any type.
+    Blocks,
+    /// Transaction blocks in the compact form.
+    CompactBlocks,
+    /// Transactions.
+    Transactions,
+}
+
+impl Message {
+    /// Get the kind of this message.
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Handshake(_) => MessageKind::Control,
+            Message::Ping(_) => MessageKind::Control,
+            Message::Pong(_) => MessageKind::Control,
+            Message::NewBlockHashes(_) => MessageKind::BlockInventory,
+            Message::GetBlocks(_) => MessageKind::BlockInventory,
+            Message::Blocks(_) => MessageKind::Blocks,
+            Message::NewTransactionHashes(_) => MessageKind::TransactionInventory,
+            Message::GetTransactions(_) => MessageKind::TransactionInventory,
+            Message::Transactions(_) => MessageKind::Transactions,
+            Message::Bootstrap(_) => MessageKind::Control,
+            Message::BootstrapProgress(_, _) => MessageKind::Control,
+            Message::GetPeers => MessageKind::Control,
+            Message::Peers(_) => MessageKind::BlockInventory,
+            Message::GetCompactBlocks(_) => MessageKind::BlockInventory,
+            Message::CompactBlocks(_) => MessageKind::CompactBlocks,
+            Message::GetBlockTransactions(_, _) => MessageKind::TransactionInventory,
+            Message::BlockTransactions(_, _) => MessageKind::Transactions,
+            Message::GetHeaders(_) => MessageKind::BlockInventory,
+            Message::Headers(_, _) => MessageKind::BlockInventory,
+            Message::RejectedTransactions(_) => MessageKind::TransactionInventory,
+        }
+    }
+}
+
+/// Kinds of messages by variant index, which is what the encoding of a message starts with. It
+/// follows the order in which the variants of Message are declared, and agrees with
+/// Message::kind, which the tests check.
+const TAG_KINDS: [MessageKind; 20] = [
+    MessageKind::Control,              // Handshake
+    MessageKind::Control,              // Ping
+    MessageKind::Control,              // Pong
+    MessageKind::BlockInventory,       // NewBlockHashes
+    MessageKind::BlockInventory,       // GetBlocks
+    MessageKind::Blocks,               // Blocks
+    MessageKind::TransactionInventory, // NewTransactionHashes
+    MessageKind::TransactionInventory, // GetTransactions
+    MessageKind::Transactions,         // Transactions
+    MessageKind::Control,              // Bootstrap
+    MessageKind::Control,              // BootstrapProgress
+    MessageKind::Control,              // GetPeers
+    MessageKind::BlockInventory,       // Peers
+    MessageKind::BlockInventory,       // GetCompactBlocks
+    MessageKind::CompactBlocks,        // CompactBlocks
+    MessageKind::TransactionInventory, // GetBlockTransactions
+    MessageKind::Transactions,         // BlockTransactions
+    MessageKind::BlockInventory,       // GetHeaders
+    MessageKind::BlockInventory,       // Headers
+    MessageKind::TransactionInventory, // RejectedTransactions
+];
+
+/// Get the kind of the message with the given variant index, or None if there is no such message.
+pub fn tag_kind(tag: u32) -> Option<MessageKind> {
+    TAG_KINDS.get(tag as usize).copied()
+}
+
+/// Maximum frame sizes in bytes for each kind of message.
+#[derive(Clone, Debug)]
+pub struct FrameLimits {
+    /// Messages of a small, bounded size, e.g. handshakes and pings.
+    pub control: usize,
+    /// Messages carrying lists of hashes or addresses.
+    pub inventory: usize,
+    /// Messages carrying blocks.
+    pub blocks: usize,
+    /// Messages carrying transactions.
+    pub transactions: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            control: 4 * 1024,
+            inventory: 4 * 1024 * 1024,
+            blocks: 64 * 1024 * 1024,
+            transactions: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl FrameLimits {
+    /// Get the maximum frame size of the message with the given variant index, or None if there
+    /// is no such message.
+    pub fn limit(&self, tag: u32) -> Option<usize> {
+        let limit = match tag_kind(tag)? {
+            MessageKind::Control => self.control,
+            MessageKind::BlockInventory | MessageKind::TransactionInventory => self.inventory,
+            MessageKind::Blocks | MessageKind::CompactBlocks => self.blocks,
+            MessageKind::Transactions => self.transactions,
+        };
+        Some(limit)
+    }
+}
+
+/// Information that peers exchange when connected, to make sure that they are on the same chain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub network_id: u32,
+    pub voter_chains: u16,
+    pub proposer_genesis: H256,
+    pub proposer_mining_rate: f32,
+    pub voter_mining_rate: f32,
+    pub tx_mining_rate: f32,
+    pub difficulty_epoch: u64,
+    pub initial_difficulty: H256,
+    /// Level of the best proposer block of the sender.
+    pub best_proposer_level: u64,
+}
+
+impl Handshake {
+    pub fn new(config: &BlockchainConfig, network_id: u32, best_proposer_level: u64) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            network_id,
+            voter_chains: config.voter_chains,
+            proposer_genesis: config.proposer_genesis,
+            proposer_mining_rate: config.proposer_mining_rate,
+            voter_mining_rate: config.voter_mining_rate,
+            tx_mining_rate: config.tx_mining_rate,
+            difficulty_epoch: config.difficulty_epoch,
+            initial_difficulty: config.initial_difficulty,
+            best_proposer_level,
+        }
+    }
+
+    /// Check whether a peer with the given handshake is on the same chain as us, and return the
+    /// reason if not. The mining rates determine the sortition widths, so they must match exactly.
+    pub fn incompatibility(&self, other: &Handshake) -> Option<String> {
+        if self.protocol_version != other.protocol_version {
+            return Some(format!(
+                "protocol version {} does not match ours {}",
+                other.protocol_version, self.protocol_version
+            ));
+        }
+        if self.network_id != other.network_id {
+            return Some(format!(
+                "network id {} does not match ours {}",
+                other.network_id, self.network_id
+            ));
+        }
+        if self.voter_chains != other.voter_chains {
+            return Some(format!(
+                "{} voter chains do not match our {}",
+                other.voter_chains, self.voter_chains
+            ));
+        }
+        if self.proposer_genesis != other.proposer_genesis {
+            return Some(format!(
+                "proposer genesis {} does not match ours {}",
+                other.proposer_genesis, self.proposer_genesis
+            ));
+        }
+        if self.proposer_mining_rate.to_bits() != other.proposer_mining_rate.to_bits()
+            || self.voter_mining_rate.to_bits() != other.voter_mining_rate.to_bits()
+            || self.tx_mining_rate.to_bits() != other.tx_mining_rate.to_bits()
+        {
+            return Some(format!(
+                "mining rates {}/{}/{} do not match ours {}/{}/{}",
+                other.proposer_mining_rate,
+                other.voter_mining_rate,
+                other.tx_mining_rate,
+                self.proposer_mining_rate,
+                self.voter_mining_rate,
+                self.tx_mining_rate
+            ));
+        }
+        if self.difficulty_epoch != other.difficulty_epoch
+            || self.initial_difficulty != other.initial_difficulty
+        {
+            return Some(format!(
+                "difficulty epoch {} and initial difficulty {} do not match ours {} and {}",
+                other.difficulty_epoch,
+                other.initial_difficulty,
+                self.difficulty_epoch,
+                self.initial_difficulty
+            ));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// One message of each variant, in the order in which the variants are declared.
+    pub fn samples() -> Vec<Message> {
+        let config = BlockchainConfig::new(100, 64000, 80000, 0.1, 0.1, 0.4, 20.0);
+        let samples = vec![
+            Message::Handshake(Handshake::new(&config, 0, 0)),
+            Message::Ping(String::new()),
+            Message::Pong(String::new()),
+            Message::NewBlockHashes(vec![]),
+            Message::GetBlocks(vec![]),
+            Message::Blocks(vec![]),
+            Message::NewTransactionHashes(vec![]),
+            Message::GetTransactions(vec![]),
+            Message::Transactions(vec![]),
+            Message::Bootstrap(H256::default()),
+            Message::BootstrapProgress(H256::default(), 0),
+            Message::GetPeers,
+            Message::Peers(vec![]),
+            Message::GetCompactBlocks(vec![]),
+            Message::CompactBlocks(vec![]),
+            Message::GetBlockTransactions(H256::default(), vec![]),
+            Message::BlockTransactions(H256::default(), vec![]),
+            Message::GetHeaders(H256::default()),
+            Message::Headers(vec![], 0),
+            Message::RejectedTransactions(vec![]),
+        ];
+        for (index, msg) in samples.iter().enumerate() {
+            // a new variant does not compile here until it has a sample above
+            let expected = match msg {
+                Message::Handshake(_) => 0,
+                Message::Ping(_) => 1,
+                Message::Pong(_) => 2,
+                Message::NewBlockHashes(_) => 3,
+                Message::GetBlocks(_) => 4,
+                Message::Blocks(_) => 5,
+                Message::NewTransactionHashes(_) => 6,
+                Message::GetTransactions(_) => 7,
+                Message::Transactions(_) => 8,
+                Message::Bootstrap(_) => 9,
+                Message::BootstrapProgress(_, _) => 10,
+                Message::GetPeers => 11,
+                Message::Peers(_) => 12,
+                Message::GetCompactBlocks(_) => 13,
+                Message::CompactBlocks(_) => 14,
+                Message::GetBlockTransactions(_, _) => 15,
+                Message::BlockTransactions(_, _) => 16,
+                Message::GetHeaders(_) => 17,
+                Message::Headers(_, _) => 18,
+                Message::RejectedTransactions(_) => 19,
+            };
+            assert_eq!(index, expected, "{:?}", msg);
+        }
+        samples
+    }
+
+    /// Get the variant index that the encoding of the given message starts with.
+    pub fn tag(msg: &Message) -> u32 {
+        let encoded = bincode::serialize(msg).unwrap();
+        let mut tag: [u8; 4] = [0; 4];
+        tag.copy_from_slice(&encoded[0..4]);
+        u32::from_le_bytes(tag)
+    }
+
+    #[test]
+    fn tag_kinds() {
+        let samples = samples();
+        for msg in &samples {
+            assert_eq!(tag_kind(tag(msg)), Some(msg.kind()), "{:?}", msg);
+        }
+        // every variant has a kind, and there are no kinds for variants that do not exist
+        assert_eq!(TAG_KINDS.len(), samples.len());
+        assert_eq!(tag_kind(samples.len() as u32), None);
+    }
+
+    #[test]
+    fn frame_limits() {
+        let limits = FrameLimits {
+            control: 1,
+            inventory: 2,
+            blocks: 3,
+            transactions: 4,
+        };
+        let expected = vec![1, 1, 1, 2, 2, 3, 2, 2, 4, 1, 1, 1, 2, 2, 3, 2, 4, 2, 2, 2];
+        for (msg, limit) in samples().iter().zip(expected) {
+            assert_eq!(limits.limit(tag(msg)), Some(limit), "{:?}", msg);
+        }
+        assert_eq!(limits.limit(1000), None);
+    }
+
+    #[test]
+    fn handshake_compatibility() {
+        let config = BlockchainConfig::new(100, 64000, 80000, 0.1, 0.1, 0.4, 20.0);
+        let ours = Handshake::new(&config, 0, 10);
+        // the best proposer level does not matter
+        let theirs = Handshake::new(&config, 0, 20);
+        assert!(ours.incompatibility(&theirs).is_none());
+        let theirs = Handshake::new(&config, 1, 10);
+        assert!(ours.incompatibility(&theirs).is_some());
+        let other_config = BlockchainConfig::new(1000, 64000, 80000, 0.1, 0.1, 0.4, 20.0);
+        let theirs = Handshake::new(&other_config, 0, 10);
+        assert!(ours.incompatibility(&theirs).is_some());
+        let other_config = BlockchainConfig::new(100, 64000, 80000, 0.2, 0.1, 0.4, 20.0);
+        let theirs = Handshake::new(&other_config, 0, 10);
+        assert!(ours.incompatibility(&theirs).is_some());
+    }
 }
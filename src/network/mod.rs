@@ -1,5 +1,12 @@
-mod buffer;
+pub mod address_book;
+pub mod buffer;
+pub mod lane;
+pub mod memory;
 pub mod message;
 pub mod peer;
+pub mod relay;
+pub mod request;
 pub mod server;
+pub mod sync;
+pub mod transport;
 pub mod worker;
@@ -1,18 +1,30 @@
 use super::message;
-use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
-use smol::Async;
+use super::transport::Disconnect;
+use crate::experiment::performance_counter::PERFORMANCE_COUNTER;
+use futures::{channel::mpsc, stream::StreamExt};
+use log::{debug, trace, warn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 pub fn new(
-    stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
-    let (write_sender, write_receiver) = mpsc::unbounded(); // TODO: think about the buffer size here
-    let addr = stream.get_ref().peer_addr()?;
+    addr: std::net::SocketAddr,
+    disconnect: Arc<dyn Disconnect>,
+    config: &WriteQueueConfig,
+) -> (WriteQueue, Handle) {
+    let (write_sender, write_receiver) = mpsc::unbounded();
+    let queued_bytes = Arc::new(AtomicUsize::new(0));
     let handle = Handle {
         write_queue: write_sender,
+        queued_bytes: Arc::clone(&queued_bytes),
+        config: config.clone(),
+        disconnect,
         addr,
     };
-    Ok((write_receiver, handle))
+    let queue = WriteQueue {
+        receiver: write_receiver,
+        queued_bytes,
+    };
+    (queue, handle)
 }
 
 #[derive(Copy, Clone)]
@@ -21,20 +33,182 @@ pub enum Direction {
     Outgoing,
 }
 
+/// What to do when the write queue of a peer is full, i.e. the peer does not read as fast as we
+/// write.
+#[derive(Copy, Clone, Debug)]
+pub enum SlowPeerPolicy {
+    /// Drop the messages that do not fit in the queue.
+    Drop,
+    /// Disconnect the peer.
+    Disconnect,
+}
+
+impl std::str::FromStr for SlowPeerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(SlowPeerPolicy::Drop),
+            "disconnect" => Ok(SlowPeerPolicy::Disconnect),
+            _ => Err(format!("unknown slow peer policy {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WriteQueueConfig {
+    /// Maximum total size in bytes of the messages waiting to be written to a peer.
+    pub max_queued_bytes: usize,
+    pub slow_peer_policy: SlowPeerPolicy,
+}
+
+/// The receiving end of the write queue of a peer.
+pub struct WriteQueue {
+    receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    queued_bytes: Arc<AtomicUsize>,
+}
+
+impl WriteQueue {
+    /// Get the next message to write to the peer.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        let msg = self.receiver.next().await?;
+        self.queued_bytes.fetch_sub(msg.len(), Ordering::Relaxed);
+        PERFORMANCE_COUNTER.record_dequeue_outgoing_message(msg.len());
+        Some(msg)
+    }
+}
+
+impl Drop for WriteQueue {
+    fn drop(&mut self) {
+        // the messages left in the queue are never written
+        let left = self.queued_bytes.swap(0, Ordering::Relaxed);
+        PERFORMANCE_COUNTER.record_dequeue_outgoing_message(left);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    /// Total size of the messages in the write queue.
+    queued_bytes: Arc<AtomicUsize>,
+    config: WriteQueueConfig,
+    /// The connection, so that we can disconnect a slow peer.
+    disconnect: Arc<dyn Disconnect>,
 }
 
 impl Handle {
+    /// Get the address of the peer.
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Check whether the peer is still connected, i.e. its write queue is open.
+    pub fn is_connected(&self) -> bool {
+        !self.write_queue.is_closed()
+    }
+
+    /// Close the write queue of the peer. The messages already in the queue are still written.
+    pub fn close(&self) {
+        self.write_queue.close_channel();
+    }
+
     pub fn write(&mut self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
-        futures::executor::block_on(async move {
-            if self.write_queue.send(buffer).await.is_err() {
-                trace!("Trying to send to disconnected peer");
+        let size = buffer.len();
+
+        // a message that does not fit even in an empty queue is never sent, whatever the policy
+        if size > self.config.max_queued_bytes {
+            warn!(
+                "Dropping message of {} bytes to peer {}, larger than the write queue",
+                size, self.addr
+            );
+            PERFORMANCE_COUNTER.record_drop_outgoing_message();
+            return;
+        }
+
+        let queued = self.queued_bytes.fetch_add(size, Ordering::Relaxed);
+        if queued + size > self.config.max_queued_bytes {
+            self.queued_bytes.fetch_sub(size, Ordering::Relaxed);
+            match self.config.slow_peer_policy {
+                SlowPeerPolicy::Drop => {
+                    debug!(
+                        "Write queue of peer {} is full, dropping message",
+                        self.addr
+                    );
+                    PERFORMANCE_COUNTER.record_drop_outgoing_message();
+                }
+                SlowPeerPolicy::Disconnect => {
+                    warn!("Write queue of peer {} is full, disconnecting", self.addr);
+                    PERFORMANCE_COUNTER.record_disconnect_slow_peer();
+                    // this makes the reader and the writer tasks of the peer exit
+                    self.disconnect.disconnect();
+                }
+            }
+            return;
+        }
+
+        PERFORMANCE_COUNTER.record_enqueue_outgoing_message(size);
+        if self.write_queue.unbounded_send(buffer).is_err() {
+            self.queued_bytes.fetch_sub(size, Ordering::Relaxed);
+            PERFORMANCE_COUNTER.record_dequeue_outgoing_message(size);
+            trace!("Trying to send to disconnected peer");
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Handle, SlowPeerPolicy, WriteQueue, WriteQueueConfig};
+    use crate::network::message::Message;
+    use crate::network::transport::Disconnect;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    struct NoDisconnect;
+
+    impl Disconnect for NoDisconnect {
+        fn disconnect(&self) {}
+    }
+
+    /// Create a peer at the given address that is not backed by a connection. The messages
+    /// written to the peer can be read from the returned queue.
+    pub fn new_peer(addr: &str, max_queued_bytes: usize) -> (WriteQueue, Handle) {
+        let config = WriteQueueConfig {
+            max_queued_bytes,
+            slow_peer_policy: SlowPeerPolicy::Drop,
+        };
+        super::new(addr.parse().unwrap(), Arc::new(NoDisconnect), &config)
+    }
+
+    #[test]
+    fn write_queue_limit() {
+        let (mut queue, mut peer) = new_peer("10.0.0.1:6000", 100);
+
+        // a message larger than the limit is dropped even if the queue is empty
+        peer.write(Message::Blocks(vec![vec![0; 200]]));
+        assert_eq!(peer.queued_bytes.load(Ordering::Relaxed), 0);
+
+        // messages are queued up to the limit
+        peer.write(Message::Ping("a".repeat(30)));
+        peer.write(Message::Ping("b".repeat(30)));
+        peer.write(Message::Ping("c".repeat(30)));
+        assert!(peer.queued_bytes.load(Ordering::Relaxed) <= 100);
+
+        peer.close();
+        let mut written: Vec<Message> = vec![];
+        while let Some(buffer) = futures::executor::block_on(queue.next()) {
+            written.push(bincode::deserialize(&buffer).unwrap());
+        }
+        match written.as_slice() {
+            [Message::Ping(a), Message::Ping(b)] => {
+                assert_eq!(a, &"a".repeat(30));
+                assert_eq!(b, &"b".repeat(30));
             }
-        });
+            _ => panic!("expected the first two pings, got {:?}", written),
+        }
+        assert_eq!(peer.queued_bytes.load(Ordering::Relaxed), 0);
     }
 }
@@ -0,0 +1,183 @@
+use super::message::Message;
+use super::server::Handle as ServerHandle;
+use crate::crypto::hash::H256;
+use crossbeam::channel::{self, RecvTimeoutError};
+use log::trace;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+/// Interval between two rounds of announcing new transactions to the peers.
+const FLUSH_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// Maximum number of transaction hashes in one announcement. A batch is flushed early when it
+/// reaches this size.
+const MAX_HASHES_PER_MESSAGE: usize = 10000;
+/// Number of transaction hashes that we remember for each peer.
+const MAX_KNOWN_HASHES: usize = 100000;
+
+/// The transaction hashes that a peer is known to have, i.e. those that the peer announced or
+/// sent to us, or that we announced or sent to the peer. Only the most recent ones are kept.
+#[derive(Default)]
+struct KnownHashes {
+    set: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+impl KnownHashes {
+    fn insert(&mut self, hash: H256) {
+        if !self.set.insert(hash) {
+            return;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN_HASHES {
+            let oldest = self.order.pop_front().unwrap();
+            self.set.remove(&oldest);
+        }
+    }
+
+    fn contains(&self, hash: &H256) -> bool {
+        self.set.contains(hash)
+    }
+}
+
+pub struct Context {
+    server: ServerHandle,
+    known: Arc<Mutex<HashMap<SocketAddr, KnownHashes>>>,
+    new_tx_chan: channel::Receiver<H256>,
+}
+
+/// Relays new transactions to the peers. Announcements are batched and flushed periodically, and
+/// a peer is not told about the transactions that it already knows.
+#[derive(Clone)]
+pub struct Handle {
+    known: Arc<Mutex<HashMap<SocketAddr, KnownHashes>>>,
+    new_tx_chan: channel::Sender<H256>,
+}
+
+pub fn new(server: &ServerHandle) -> (Context, Handle) {
+    let known = Arc::new(Mutex::new(HashMap::new()));
+    let (new_tx_tx, new_tx_rx) = channel::unbounded();
+    let ctx = Context {
+        server: server.clone(),
+        known: Arc::clone(&known),
+        new_tx_chan: new_tx_rx,
+    };
+    let handle = Handle {
+        known,
+        new_tx_chan: new_tx_tx,
+    };
+    (ctx, handle)
+}
+
+impl Handle {
+    /// Announce a transaction that has just entered the memory pool to the peers.
+    pub fn announce(&self, hash: H256) {
+        self.new_tx_chan.send(hash).unwrap();
+    }
+
+    /// Record that the given peer has the given transactions, so that we don't announce them to
+    /// it.
+    pub fn mark_known(&self, peer: SocketAddr, hashes: &[H256]) {
+        let mut known = self.known.lock().unwrap();
+        let known = known.entry(peer).or_default();
+        for hash in hashes {
+            known.insert(*hash);
+        }
+    }
+}
+
+impl Context {
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("transaction relay".to_string())
+            .spawn(move || {
+                self.relay_loop();
+            })
+            .unwrap();
+    }
+
+    fn relay_loop(&self) {
+        let mut pending: Vec<H256> = vec![];
+        let mut deadline = time::Instant::now() + FLUSH_INTERVAL;
+        loop {
+            let timeout = deadline.saturating_duration_since(time::Instant::now());
+            match self.new_tx_chan.recv_timeout(timeout) {
+                Ok(hash) => {
+                    pending.push(hash);
+                    if pending.len() < MAX_HASHES_PER_MESSAGE {
+                        continue;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            if !pending.is_empty() {
+                self.flush(&pending);
+                pending.clear();
+            }
+            deadline = time::Instant::now() + FLUSH_INTERVAL;
+        }
+    }
+
+    /// Announce the given transactions to each peer, except those that the peer already knows.
+    fn flush(&self, hashes: &[H256]) {
+        let peers = self.server.peers();
+        let mut known = self.known.lock().unwrap();
+        // forget the peers that have disconnected
+        let connected: HashSet<SocketAddr> = peers.iter().map(|p| p.addr()).collect();
+        known.retain(|addr, _| connected.contains(addr));
+        let mut num_messages = 0;
+        for mut peer in peers {
+            let known = known.entry(peer.addr()).or_default();
+            let to_announce: Vec<H256> = hashes
+                .iter()
+                .filter(|h| !known.contains(h))
+                .copied()
+                .collect();
+            if to_announce.is_empty() {
+                continue;
+            }
+            for hash in &to_announce {
+                known.insert(*hash);
+            }
+            peer.write(Message::NewTransactionHashes(to_announce));
+            num_messages += 1;
+        }
+        drop(known);
+        trace!(
+            "Announced {} transactions in {} messages",
+            hashes.len(),
+            num_messages
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KnownHashes;
+    use super::MAX_KNOWN_HASHES;
+    use crate::crypto::hash::H256;
+
+    #[test]
+    fn known_hashes_bounded() {
+        let mut known = KnownHashes::default();
+        let hashes: Vec<H256> = (0..MAX_KNOWN_HASHES as u64 + 1)
+            .map(|i| {
+                let mut raw = [0u8; 32];
+                raw[0..8].copy_from_slice(&i.to_be_bytes());
+                raw.into()
+            })
+            .collect();
+        for hash in &hashes {
+            known.insert(*hash);
+        }
+        // inserting a known hash again does not change the order
+        known.insert(hashes[1]);
+        assert!(!known.contains(&hashes[0]));
+        assert!(known.contains(&hashes[1]));
+        assert!(known.contains(&hashes[MAX_KNOWN_HASHES]));
+        assert_eq!(known.order.len(), MAX_KNOWN_HASHES);
+    }
+}
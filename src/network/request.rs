@@ -0,0 +1,148 @@
+use crate::crypto::hash::H256;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time;
+
+/// How long to wait for a peer to send a requested block before asking another peer.
+pub const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Number of attempts after which a request is considered stuck.
+pub const STUCK_ATTEMPTS: u32 = 3;
+
+/// An outstanding request for a block.
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// The peer that we last asked for the block.
+    pub peer: SocketAddr,
+    /// When we give up on the peer.
+    pub deadline: time::Instant,
+    /// Number of times that we have asked for the block.
+    pub attempts: u32,
+    /// The peers that we have asked for the block.
+    tried: HashSet<SocketAddr>,
+}
+
+/// Blocks that we have requested but not yet received, and the peers that we requested them from.
+pub struct BlockRequests {
+    requests: HashMap<H256, Request>,
+    timeout: time::Duration,
+}
+
+impl BlockRequests {
+    pub fn new(timeout: time::Duration) -> Self {
+        Self {
+            requests: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Record that we have asked the given peer for a block. If the block is already requested,
+    /// the request is moved to the given peer.
+    pub fn insert(&mut self, hash: H256, peer: SocketAddr, now: time::Instant) {
+        let deadline = now + self.timeout;
+        let request = self.requests.entry(hash).or_insert_with(|| Request {
+            peer,
+            deadline,
+            attempts: 0,
+            tried: HashSet::new(),
+        });
+        request.peer = peer;
+        request.deadline = deadline;
+        request.attempts += 1;
+        request.tried.insert(peer);
+    }
+
+    /// Check whether a block is requested.
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.requests.contains_key(hash)
+    }
+
+    /// Remove the request for a block when it arrives. Returns whether the block was requested.
+    pub fn remove(&mut self, hash: &H256) -> bool {
+        self.requests.remove(hash).is_some()
+    }
+
+    /// Get the requests whose deadline has passed.
+    pub fn expired(&self, now: time::Instant) -> Vec<(H256, Request)> {
+        self.requests
+            .iter()
+            .filter(|(_, r)| r.deadline <= now)
+            .map(|(h, r)| (*h, r.clone()))
+            .collect()
+    }
+
+    /// Choose the peer to ask for a block again, among the given connected peers. Prefers the
+    /// peers that we have not asked yet, then any peer other than the last one. Returns None if
+    /// there is no other peer.
+    pub fn next_peer(&self, hash: &H256, peers: &[SocketAddr]) -> Option<SocketAddr> {
+        let request = self.requests.get(hash)?;
+        peers
+            .iter()
+            .find(|p| !request.tried.contains(p))
+            .or_else(|| peers.iter().find(|p| **p != request.peer))
+            .copied()
+    }
+
+    /// Give the last peer more time to send a block, when there is no other peer to ask.
+    pub fn extend(&mut self, hash: &H256, now: time::Instant) {
+        if let Some(request) = self.requests.get_mut(hash) {
+            request.deadline = now + self.timeout;
+        }
+    }
+
+    /// Get the requests that have been retried too many times.
+    pub fn stuck(&self) -> Vec<(H256, Request)> {
+        self.requests
+            .iter()
+            .filter(|(_, r)| r.attempts >= STUCK_ATTEMPTS)
+            .map(|(h, r)| (*h, r.clone()))
+            .collect()
+    }
+
+    /// Get the number of outstanding requests.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Check whether there is no outstanding request.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockRequests, STUCK_ATTEMPTS};
+    use crate::crypto::hash::H256;
+    use std::net::SocketAddr;
+    use std::time;
+
+    #[test]
+    fn retry_with_different_peer() {
+        let timeout = time::Duration::from_secs(10);
+        let mut requests = BlockRequests::new(timeout);
+        let hash = H256::default();
+        let a: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let start = time::Instant::now();
+
+        requests.insert(hash, a, start);
+        assert!(requests.contains(&hash));
+        assert!(requests.expired(start).is_empty());
+        assert_eq!(requests.expired(start + timeout).len(), 1);
+
+        // an untried peer is preferred, and the last peer is not chosen if there are others
+        assert_eq!(requests.next_peer(&hash, &[a, b]), Some(b));
+        assert_eq!(requests.next_peer(&hash, &[a]), None);
+        requests.insert(hash, b, start + timeout);
+        assert!(requests.expired(start + timeout).is_empty());
+        assert_eq!(requests.next_peer(&hash, &[a, b]), Some(a));
+
+        for _ in 2..STUCK_ATTEMPTS {
+            requests.insert(hash, a, start);
+        }
+        assert_eq!(requests.stuck().len(), 1);
+        assert!(requests.remove(&hash));
+        assert!(!requests.remove(&hash));
+        assert!(requests.is_empty());
+    }
+}
@@ -1,27 +1,105 @@
+use super::address_book::AddressBook;
+use super::lane;
 use super::message;
 use super::peer;
+use super::transport::{Connection, Disconnect, Transport};
+use crate::blockchain::BlockChain;
+use crate::config::BlockchainConfig;
+use crate::experiment::performance_counter::PERFORMANCE_COUNTER;
 
-use futures::{channel::oneshot, stream::StreamExt};
-use log::{debug, info, trace};
+use futures::channel::oneshot;
+use futures::future::Either;
+use log::{debug, info, trace, warn};
 use piper;
-use piper::Arc;
+use std::collections::{HashMap, HashSet};
 use std::net;
+use std::sync::Arc;
+use std::time;
 
 use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
-use smol::{Async, Task};
+use smol::{Task, Timer};
 use std::thread;
 
+/// Interval between two rounds of replacing dropped outgoing peers and saving the address book.
+const PEER_MAINTENANCE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+/// Maximum number of addresses in one message.
+pub const MAX_ADDRS_PER_MESSAGE: usize = 1000;
+/// Maximum number of new addresses that we learn from a peer while it is connected.
+const MAX_LEARNED_ADDRS_PER_PEER: usize = 1000;
+/// How long to wait for a newly connected peer to send its handshake.
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+/// Maximum size of a handshake frame in bytes.
+const MAX_HANDSHAKE_SIZE: usize = 1024;
+/// Misbehavior score at which a peer is disconnected and banned.
+const BAN_THRESHOLD: u32 = 100;
+/// How long a banned peer is refused.
+const BAN_DURATION: time::Duration = time::Duration::from_secs(600);
+/// How much the misbehavior score of each peer decreases every peer maintenance interval, so that
+/// occasional mistakes of honest peers do not add up over time.
+const SCORE_DECAY: u32 = 5;
+
+/// Kinds of misbehavior of a peer.
+#[derive(Debug, Copy, Clone)]
+pub enum Misbehavior {
+    /// Sent a frame that cannot be decoded.
+    MalformedMessage,
+    /// Sent a block whose PoW or sortition id is invalid.
+    InvalidPoW,
+    /// Sent a block whose sortition proof is invalid.
+    InvalidSortitionProof,
+    /// Sent a block that we did not ask for.
+    UnsolicitedBlock,
+    /// Sent the transactions of a compact block that we did not ask it for.
+    UnsolicitedTransactions,
+}
+
+impl Misbehavior {
+    /// The amount that this misbehavior adds to the misbehavior score of the peer.
+    fn penalty(self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 20,
+            // a block with invalid PoW costs nothing to generate, so it is never an honest mistake
+            Misbehavior::InvalidPoW => 50,
+            Misbehavior::InvalidSortitionProof => 50,
+            Misbehavior::UnsolicitedBlock => 2,
+            Misbehavior::UnsolicitedTransactions => 2,
+        }
+    }
+}
+
 pub fn new(
     addr: std::net::SocketAddr,
-    msg_sink: piper::Sender<(Vec<u8>, peer::Handle)>,
+    msg_sink: lane::Sender,
+    address_book: AddressBook,
+    target_outgoing: usize,
+    blockchain: &std::sync::Arc<BlockChain>,
+    config: BlockchainConfig,
+    network_id: u32,
+    frame_limits: message::FrameLimits,
+    write_queue_config: peer::WriteQueueConfig,
+    transport: Arc<dyn Transport>,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = piper::chan(10000); // TODO: think about the buffer size
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
     };
     let ctx = Context {
-        peers: std::collections::HashMap::new(),
+        peers: HashMap::new(),
+        outgoing: HashSet::new(),
+        connecting: HashSet::new(),
+        streams: HashMap::new(),
+        scores: HashMap::new(),
+        learned: HashMap::new(),
+        banned: HashMap::new(),
+        address_book,
+        target_outgoing,
+        chain: std::sync::Arc::clone(blockchain),
+        config,
+        network_id,
+        frame_limits,
+        write_queue_config,
+        transport,
         addr,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
@@ -31,16 +109,44 @@ pub fn new(
 }
 
 pub struct Context {
-    peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    peers: HashMap<std::net::SocketAddr, peer::Handle>,
+    /// Addresses of the peers that we connected to.
+    outgoing: HashSet<std::net::SocketAddr>,
+    /// Addresses of the peers that we are connecting to, whose handshakes are not done yet.
+    connecting: HashSet<std::net::SocketAddr>,
+    /// Connections to the peers, so that we can disconnect them.
+    streams: HashMap<std::net::SocketAddr, Arc<dyn Disconnect>>,
+    /// Misbehavior scores of the peers.
+    scores: HashMap<std::net::SocketAddr, u32>,
+    /// Number of new addresses learned from each peer.
+    learned: HashMap<std::net::SocketAddr, usize>,
+    /// Banned IP addresses and when the bans expire.
+    banned: HashMap<net::IpAddr, time::Instant>,
+    address_book: AddressBook,
+    /// Number of outgoing peers that the connection manager tries to keep.
+    target_outgoing: usize,
+    chain: std::sync::Arc<BlockChain>,
+    config: BlockchainConfig,
+    network_id: u32,
+    frame_limits: message::FrameLimits,
+    write_queue_config: peer::WriteQueueConfig,
+    /// How we reach the peers, e.g. TCP.
+    transport: Arc<dyn Transport>,
     addr: std::net::SocketAddr,
     control_chan: piper::Receiver<ControlSignal>,
     control_sender: piper::Sender<ControlSignal>,
-    new_msg_chan: piper::Sender<(Vec<u8>, peer::Handle)>,
+    new_msg_chan: lane::Sender,
 }
 
 impl Context {
     /// Start a new server context.
     pub fn start(self) -> std::io::Result<()> {
+        // periodically tell the server to replace dropped peers
+        let control_chan = self.control_sender.clone();
+        thread::spawn(move || loop {
+            thread::sleep(PEER_MAINTENANCE_INTERVAL);
+            futures::executor::block_on(control_chan.send(ControlSignal::MaintainPeers));
+        });
         thread::spawn(move || {
             smol::run(async move {
                 self.mainloop().await.unwrap();
@@ -51,7 +157,7 @@ impl Context {
 
     pub async fn mainloop(mut self) -> std::io::Result<()> {
         // initialize the server socket
-        let listener = Async::<net::TcpListener>::bind(&self.addr)?;
+        let listener = self.transport.listen(self.addr)?;
         info!("P2P server listening at {}", self.addr);
         let control_chan = self.control_sender.clone();
 
@@ -63,9 +169,15 @@ impl Context {
 
         // finally, enter the loop that endlessly accept incoming peers
         loop {
-            let (stream, addr) = listener.accept().await?;
+            let stream = listener.accept().await?;
+            match stream.peer_addr() {
+                Ok(addr) => info!("Incoming peer from {}", addr),
+                Err(e) => {
+                    warn!("Error getting the address of incoming peer: {}", e);
+                    continue;
+                }
+            }
             control_chan.send(ControlSignal::GetNewPeer(stream)).await;
-            info!("Incoming peer from {}", addr);
         }
     }
 
@@ -75,8 +187,7 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr).await;
-                    result_chan.send(handle).unwrap();
+                    self.connect(addr, Some(result_chan));
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
@@ -84,51 +195,269 @@ impl Context {
                         hd.write(msg.clone());
                     }
                 }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    result_chan
+                        .send(self.peers.values().cloned().collect())
+                        .unwrap();
+                }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream).await?;
+                    self.accept(stream);
+                }
+                ControlSignal::Registered(stream, handshake, direction, result_chan) => {
+                    trace!("Processing Registered command");
+                    let handle = self.register(stream, handshake, direction);
+                    if let Err(e) = &handle {
+                        warn!("Error registering peer: {}", e);
+                    }
+                    if let Some(result_chan) = result_chan {
+                        // the caller may have stopped waiting
+                        let _ = result_chan.send(handle);
+                    }
+                }
+                ControlSignal::ConnectFailed(addr, e, result_chan) => {
+                    trace!("Processing ConnectFailed({})", addr);
+                    self.connecting.remove(&addr);
+                    self.address_book.mark_failed(addr);
+                    debug!("Error connecting to peer {}: {}", addr, e);
+                    if let Some(result_chan) = result_chan {
+                        let _ = result_chan.send(Err(e));
+                    }
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
                     self.peers.remove(&addr);
+                    self.outgoing.remove(&addr);
+                    self.streams.remove(&addr);
+                    self.scores.remove(&addr);
+                    self.learned.remove(&addr);
                     info!("Peer {} disconnected", addr);
                 }
+                ControlSignal::ReportMisbehavior(addr, misbehavior) => {
+                    trace!("Processing ReportMisbehavior({}, {:?})", addr, misbehavior);
+                    // the peer may have been disconnected already
+                    if !self.peers.contains_key(&addr) {
+                        continue;
+                    }
+                    let score = self.scores.entry(addr).or_insert(0);
+                    *score += misbehavior.penalty();
+                    debug!(
+                        "Peer {} misbehaved ({:?}), misbehavior score {}",
+                        addr, misbehavior, score
+                    );
+                    if *score >= BAN_THRESHOLD {
+                        self.ban(addr);
+                    }
+                }
+                ControlSignal::GetKnownAddrs(result_chan) => {
+                    trace!("Processing GetKnownAddrs command");
+                    let mut exclude = HashSet::new();
+                    exclude.insert(self.addr);
+                    let mut addrs = self
+                        .address_book
+                        .sample(MAX_ADDRS_PER_MESSAGE - 1, &exclude);
+                    addrs.push(self.addr);
+                    result_chan.send(addrs).unwrap();
+                }
+                ControlSignal::AddKnownAddrs(source, addrs) => {
+                    trace!("Processing AddKnownAddrs command");
+                    let learned = self.learned.entry(source).or_insert(0);
+                    for addr in addrs {
+                        if *learned >= MAX_LEARNED_ADDRS_PER_PEER {
+                            debug!("Ignoring further peer addresses from {}", source);
+                            break;
+                        }
+                        if addr != self.addr && self.address_book.insert(addr) {
+                            *learned += 1;
+                            debug!("Learned new peer address {}", addr);
+                        }
+                    }
+                }
+                ControlSignal::MaintainPeers => {
+                    trace!("Processing MaintainPeers command");
+                    self.maintain_peers();
+                }
             }
         }
         return Ok(());
     }
 
-    /// Connect to a peer, and register this peer
-    async fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+    /// Disconnect a peer, and refuse connections from or to its IP address for a while.
+    fn ban(&mut self, addr: std::net::SocketAddr) {
+        warn!("Banning peer {} for misbehavior", addr);
+        self.banned
+            .insert(addr.ip(), time::Instant::now() + BAN_DURATION);
+        self.peers.remove(&addr);
+        self.outgoing.remove(&addr);
+        self.scores.remove(&addr);
+        if let Some(stream) = self.streams.remove(&addr) {
+            // this makes the reader and the writer tasks of the peer exit
+            stream.disconnect();
+        }
+    }
+
+    /// Check whether the given address is banned.
+    fn is_banned(&mut self, addr: &std::net::SocketAddr) -> bool {
+        match self.banned.get(&addr.ip()) {
+            Some(expiry) if *expiry > time::Instant::now() => true,
+            Some(_) => {
+                self.banned.remove(&addr.ip());
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Connect to a peer in the background. The peer is registered once it has sent a valid
+    /// handshake, and the result is sent to the given channel, if any. The handshake is not done
+    /// in the control loop, so that a slow or silent peer does not hold up other control signals.
+    fn connect(
+        &mut self,
+        addr: std::net::SocketAddr,
+        result_chan: Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+    ) {
+        if self.is_banned(&addr) {
+            if let Some(result_chan) = result_chan {
+                let _ = result_chan.send(Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "peer is banned",
+                )));
+            }
+            return;
+        }
         debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr).await?;
+        self.connecting.insert(addr);
+        let transport = Arc::clone(&self.transport);
+        let ours = self.our_handshake();
+        let control_chan = self.control_sender.clone();
+        Task::local(async move {
+            let result = match transport.connect(addr).await {
+                Ok(mut stream) => handshake(&mut stream, ours).await.map(|h| (stream, h)),
+                Err(e) => Err(e),
+            };
+            let signal = match result {
+                Ok((stream, theirs)) => ControlSignal::Registered(
+                    stream,
+                    theirs,
+                    peer::Direction::Outgoing,
+                    result_chan,
+                ),
+                Err(e) => ControlSignal::ConnectFailed(addr, e, result_chan),
+            };
+            control_chan.send(signal).await;
+        })
+        .detach();
+    }
 
-        // register the new peer
-        self.register(stream, peer::Direction::Outgoing).await
+    /// Get the handshake that we send to new peers.
+    fn our_handshake(&self) -> message::Handshake {
+        message::Handshake::new(
+            &self.config,
+            self.network_id,
+            self.chain.best_proposer_level(),
+        )
     }
 
-    async fn accept(&mut self, stream: Async<net::TcpStream>) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming).await?;
-        Ok(())
+    /// Connect to new peers from the address book if we have fewer outgoing peers than the
+    /// target, and save the address book.
+    fn maintain_peers(&mut self) {
+        self.scores.retain(|_, s| {
+            *s = s.saturating_sub(SCORE_DECAY);
+            *s != 0
+        });
+
+        let num_missing = self
+            .target_outgoing
+            .saturating_sub(self.outgoing.len() + self.connecting.len());
+        if num_missing != 0 {
+            let mut exclude: HashSet<std::net::SocketAddr> = self.peers.keys().copied().collect();
+            exclude.extend(&self.connecting);
+            exclude.insert(self.addr);
+            let addrs = self.address_book.sample(num_missing, &exclude);
+            // ask for more addresses if the address book cannot make up for the missing peers
+            let short = addrs.len() < num_missing;
+            for addr in addrs {
+                self.connect(addr, None);
+            }
+            if short {
+                for (_, hd) in self.peers.iter_mut() {
+                    hd.write(message::Message::GetPeers);
+                }
+            }
+        }
+        if let Err(e) = self.address_book.save() {
+            warn!("Error saving address book: {}", e);
+        }
     }
 
-    async fn register(
+    /// Exchange handshakes with an incoming peer in the background, and register the peer once
+    /// it has sent a valid handshake.
+    fn accept(&mut self, mut stream: Box<dyn Connection>) {
+        let addr = match stream.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("Error accepting incoming peer: {}", e);
+                return;
+            }
+        };
+        if self.is_banned(&addr) {
+            debug!("Refusing banned peer {}", addr);
+            return;
+        }
+        let ours = self.our_handshake();
+        let control_chan = self.control_sender.clone();
+        Task::local(async move {
+            match handshake(&mut stream, ours).await {
+                Ok(theirs) => {
+                    let signal =
+                        ControlSignal::Registered(stream, theirs, peer::Direction::Incoming, None);
+                    control_chan.send(signal).await;
+                }
+                Err(e) => warn!("Error accepting incoming peer {}: {}", addr, e),
+            }
+        })
+        .detach();
+    }
+
+    /// Start the reader and the writer of a peer that has sent a valid handshake.
+    fn register(
         &mut self,
-        stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        stream: Box<dyn Connection>,
+        handshake: message::Handshake,
+        direction: peer::Direction,
     ) -> std::io::Result<peer::Handle> {
+        let addr = stream.peer_addr()?;
+        if let peer::Direction::Outgoing = direction {
+            self.connecting.remove(&addr);
+        }
+        // the peer may have been banned while we were waiting for its handshake
+        if self.is_banned(&addr) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "peer is banned",
+            ));
+        }
+        info!(
+            "Peer {} is at proposer level {}",
+            addr, handshake.best_proposer_level
+        );
+
         // create a handle so that we can write to this peer TODO
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let disconnect = stream.disconnect_handle()?;
+        let (mut write_queue, handle) =
+            peer::new(addr, Arc::clone(&disconnect), &self.write_queue_config);
 
-        let stream = Arc::new(stream);
+        let (reader, writer) = stream.split();
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
-        let addr = stream.get_ref().peer_addr()?;
+        let frame_limits = self.frame_limits.clone();
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
-        let mut reader = BufReader::new(stream.clone());
+        let reader_disconnect = Arc::clone(&disconnect);
+        let mut reader = BufReader::new(reader);
         Task::local(async move {
             // the buffer to store the frame header, which contains the length of the frame
             let mut size_buffer: [u8; 4] = [0; 4];
@@ -142,33 +471,66 @@ impl Context {
                         break;
                     }
                 };
+                // then, read the variant index of the message, which is encoded in the first 4
+                // bytes, and check the frame size against the limit for that kind of message
+                // before allocating the buffer
+                if msg_size < 4 {
+                    break;
+                }
+                let mut tag_buffer: [u8; 4] = [0; 4];
+                match reader.read_exact(&mut tag_buffer).await {
+                    Ok(_) => {}
+                    Err(_) => {
+                        break;
+                    }
+                }
+                let tag = u32::from_le_bytes(tag_buffer);
+                match frame_limits.limit(tag) {
+                    Some(limit) if msg_size as usize <= limit => {}
+                    _ => {
+                        warn!(
+                            "Disconnecting peer {} for sending a frame of {} bytes with message type {}",
+                            addr, msg_size, tag
+                        );
+                        PERFORMANCE_COUNTER.record_oversized_frame();
+                        break;
+                    }
+                }
                 // then, read exactly msg_size bytes to get the whole message
                 if msg_buffer.len() < msg_size as usize {
                     msg_buffer.resize(msg_size as usize, 0);
                 }
+                msg_buffer[0..4].copy_from_slice(&tag_buffer);
                 match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
+                    .read_exact(&mut msg_buffer[4..msg_size as usize])
                     .await
                 {
                     Ok(_) => {
                         let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan.send((new_payload, handle_copy.clone())).await;
+                        new_msg_chan.send(new_payload, handle_copy.clone()).await;
                     }
                     Err(_) => {
                         break;
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, or we are disconnecting it. in the latter case, shutting
+            // down the connection makes the writer task exit as well. closing the write queue
+            // wakes up the writer task if it is waiting for a message
+            reader_disconnect.disconnect();
+            handle_copy.close();
         })
         .detach();
 
         // second, start a task that keeps writing to this guy
-        let mut writer = BufWriter::new(stream.clone());
+        let mut writer = BufWriter::new(writer);
         Task::local(async move {
             loop {
                 // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+                let new_msg = match write_queue.next().await {
+                    Some(msg) => msg,
+                    None => break,
+                };
 
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
@@ -200,10 +562,78 @@ impl Context {
 
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
+        self.streams.insert(addr, disconnect);
+        if let peer::Direction::Outgoing = direction {
+            self.outgoing.insert(addr);
+            self.address_book.mark_connected(addr);
+            info!("Connected to outgoing peer {}", addr);
+
+            // tell the peer where we are listening at, and ask for the peers it knows
+            let mut handle = handle.clone();
+            handle.write(message::Message::Peers(vec![self.addr]));
+            handle.write(message::Message::GetPeers);
+        }
         Ok(handle)
     }
 }
 
+/// Send our handshake to a newly connected peer, and receive and check its handshake.
+async fn handshake(
+    stream: &mut Box<dyn Connection>,
+    ours: message::Handshake,
+) -> std::io::Result<message::Handshake> {
+    let encoded = bincode::serialize(&message::Message::Handshake(ours.clone())).unwrap();
+    stream
+        .write_all(&(encoded.len() as u32).to_be_bytes())
+        .await?;
+    stream.write_all(&encoded).await?;
+    stream.flush().await?;
+
+    // read the handshake of the peer, and give up if it takes too long
+    let read = Box::pin(async move {
+        let mut size_buffer: [u8; 4] = [0; 4];
+        stream.read_exact(&mut size_buffer).await?;
+        let msg_size = u32::from_be_bytes(size_buffer) as usize;
+        if msg_size > MAX_HANDSHAKE_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "handshake too large",
+            ));
+        }
+        let mut msg_buffer: Vec<u8> = vec![0; msg_size];
+        stream.read_exact(&mut msg_buffer).await?;
+        Ok::<Vec<u8>, std::io::Error>(msg_buffer)
+    });
+    let timeout = Box::pin(async {
+        Timer::after(HANDSHAKE_TIMEOUT).await;
+        Err::<Vec<u8>, std::io::Error>(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "handshake timed out",
+        ))
+    });
+    let msg_buffer = match futures::future::select(read, timeout).await {
+        Either::Left((r, _)) => r?,
+        Either::Right((r, _)) => r?,
+    };
+
+    let theirs = match bincode::deserialize(&msg_buffer) {
+        Ok(message::Message::Handshake(h)) => h,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "expecting handshake",
+            ))
+        }
+    };
+    if let Some(reason) = ours.incompatibility(&theirs) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("incompatible peer: {}", reason),
+        ));
+    }
+    Ok(theirs)
+}
+
 #[derive(Clone)]
 pub struct Handle {
     control_chan: piper::Sender<ControlSignal>,
@@ -222,6 +652,36 @@ impl Handle {
     pub fn broadcast(&self, msg: message::Message) {
         futures::executor::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg)));
     }
+
+    /// Get the handles of the connected peers.
+    pub fn peers(&self) -> Vec<peer::Handle> {
+        let (sender, receiver) = oneshot::channel();
+        futures::executor::block_on(self.control_chan.send(ControlSignal::GetPeers(sender)));
+        futures::executor::block_on(receiver).unwrap()
+    }
+
+    /// Get some addresses of the peers that we know about, including our own.
+    pub fn known_addrs(&self) -> Vec<std::net::SocketAddr> {
+        let (sender, receiver) = oneshot::channel();
+        futures::executor::block_on(self.control_chan.send(ControlSignal::GetKnownAddrs(sender)));
+        futures::executor::block_on(receiver).unwrap()
+    }
+
+    /// Report that a peer has misbehaved. The peer is banned if it misbehaves too much.
+    pub fn report(&self, addr: std::net::SocketAddr, misbehavior: Misbehavior) {
+        futures::executor::block_on(
+            self.control_chan
+                .send(ControlSignal::ReportMisbehavior(addr, misbehavior)),
+        );
+    }
+
+    /// Add addresses of peers sent by the given peer to the address book.
+    pub fn add_known_addrs(&self, source: std::net::SocketAddr, addrs: Vec<std::net::SocketAddr>) {
+        futures::executor::block_on(
+            self.control_chan
+                .send(ControlSignal::AddKnownAddrs(source, addrs)),
+        );
+    }
 }
 
 enum ControlSignal {
@@ -230,6 +690,25 @@ enum ControlSignal {
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
     BroadcastMessage(message::Message),
-    GetNewPeer(Async<net::TcpStream>),
+    GetPeers(oneshot::Sender<Vec<peer::Handle>>),
+    GetNewPeer(Box<dyn Connection>),
+    /// A peer has sent a valid handshake, and is ready to be registered.
+    Registered(
+        Box<dyn Connection>,
+        message::Handshake,
+        peer::Direction,
+        Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+    ),
+    /// Connecting to a peer has failed.
+    ConnectFailed(
+        std::net::SocketAddr,
+        std::io::Error,
+        Option<oneshot::Sender<std::io::Result<peer::Handle>>>,
+    ),
     DroppedPeer(std::net::SocketAddr),
+    GetKnownAddrs(oneshot::Sender<Vec<std::net::SocketAddr>>),
+    /// Addresses of peers sent by the given peer.
+    AddKnownAddrs(std::net::SocketAddr, Vec<std::net::SocketAddr>),
+    MaintainPeers,
+    ReportMisbehavior(std::net::SocketAddr, Misbehavior),
 }
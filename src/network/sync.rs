@@ -0,0 +1,462 @@
+use super::message::Message;
+use super::peer;
+use crate::blockdb::BlockDatabase;
+use crate::crypto::hash::H256;
+use crossbeam::channel::{self, RecvTimeoutError};
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time;
+
+/// The number of blocks that a peer sends in response to one bootstrap request.
+pub const BOOTSTRAP_BATCH_SIZE: u64 = 500;
+
+/// How long to wait for a peer to respond to a bootstrap request before asking another peer.
+const BOOTSTRAP_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// The number of headers that a peer sends in response to one header request.
+pub const HEADERS_BATCH_SIZE: u64 = 2000;
+
+/// The number of blocks to ask a peer for in one request when downloading the contents of the
+/// blocks whose headers we have.
+const FETCH_BATCH_SIZE: usize = 100;
+
+/// How long to wait for the requested blocks before asking other peers.
+const FETCH_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Number of times to ask for the blocks of a batch of headers before leaving the rest to gossip.
+const MAX_FETCH_ATTEMPTS: usize = 3;
+
+/// Interval between two checks of whether the requested blocks have arrived, or the peer that we
+/// are waiting for is disconnected.
+const FETCH_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+/// The state of the initial block download.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Waiting for a peer to download blocks from.
+    Idle,
+    /// Downloading blocks from the given peer.
+    Syncing(SocketAddr),
+    /// Caught up with the peers. From now on, new blocks are learned through gossip.
+    Synced,
+}
+
+/// A response to a header request. None if the headers fail the PoW or sortition checks.
+type HeadersResponse = Option<(Vec<H256>, u64)>;
+
+pub struct Context {
+    blockdb: Arc<BlockDatabase>,
+    state: Arc<(Mutex<State>, Condvar)>,
+    bootstrap_peers: Arc<Mutex<HashSet<SocketAddr>>>,
+    headers_first: bool,
+    new_peer_chan: channel::Receiver<peer::Handle>,
+    progress_chan: channel::Receiver<(SocketAddr, H256, u64)>,
+    headers_chan: channel::Receiver<(SocketAddr, HeadersResponse)>,
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    state: Arc<(Mutex<State>, Condvar)>,
+    /// Peers that we have sent bootstrap requests to, and thus may send us blocks that we did not
+    /// explicitly ask for.
+    bootstrap_peers: Arc<Mutex<HashSet<SocketAddr>>>,
+    new_peer_chan: channel::Sender<peer::Handle>,
+    progress_chan: channel::Sender<(SocketAddr, H256, u64)>,
+    headers_chan: channel::Sender<(SocketAddr, HeadersResponse)>,
+}
+
+/// Create a new initial block download context. If `bootstrap` is false, the node does not download
+/// blocks from peers and is considered synced from the start. If `headers_first` is true, the node
+/// downloads the headers of the blocks from one peer and checks them, then downloads the contents
+/// from all peers in parallel. Otherwise, it downloads whole blocks from one peer.
+pub fn new(
+    blockdb: &Arc<BlockDatabase>,
+    bootstrap: bool,
+    headers_first: bool,
+) -> (Context, Handle) {
+    let state = if bootstrap {
+        State::Idle
+    } else {
+        State::Synced
+    };
+    let state = Arc::new((Mutex::new(state), Condvar::new()));
+    let bootstrap_peers = Arc::new(Mutex::new(HashSet::new()));
+    let (new_peer_tx, new_peer_rx) = channel::unbounded();
+    let (progress_tx, progress_rx) = channel::unbounded();
+    let (headers_tx, headers_rx) = channel::unbounded();
+    let ctx = Context {
+        blockdb: Arc::clone(blockdb),
+        state: Arc::clone(&state),
+        bootstrap_peers: Arc::clone(&bootstrap_peers),
+        headers_first,
+        new_peer_chan: new_peer_rx,
+        progress_chan: progress_rx,
+        headers_chan: headers_rx,
+    };
+    let handle = Handle {
+        state,
+        bootstrap_peers,
+        new_peer_chan: new_peer_tx,
+        progress_chan: progress_tx,
+        headers_chan: headers_tx,
+    };
+    (ctx, handle)
+}
+
+impl Handle {
+    /// Tell the context about a peer that we can download blocks from.
+    pub fn add_peer(&self, peer: peer::Handle) {
+        // the context is gone once the node is synced
+        let _ = self.new_peer_chan.send(peer);
+    }
+
+    /// Tell the context that a peer has sent us a batch of blocks ending at the given block, and
+    /// it has the given number of blocks after that.
+    pub fn progress(&self, peer: SocketAddr, last: H256, remaining: u64) {
+        let _ = self.progress_chan.send((peer, last, remaining));
+    }
+
+    /// Tell the context that a peer has sent us a batch of headers that pass the checks, and it
+    /// has the given number of blocks after the last one.
+    pub fn headers(&self, peer: SocketAddr, hashes: Vec<H256>, remaining: u64) {
+        let _ = self.headers_chan.send((peer, Some((hashes, remaining))));
+    }
+
+    /// Tell the context that a peer has sent us a batch of headers that fail the checks.
+    pub fn invalid_headers(&self, peer: SocketAddr) {
+        let _ = self.headers_chan.send((peer, None));
+    }
+
+    /// Get the current state.
+    pub fn state(&self) -> State {
+        *(self.state.0).lock().unwrap()
+    }
+
+    /// Check whether we have sent bootstrap requests to the given peer.
+    pub fn is_bootstrap_peer(&self, peer: SocketAddr) -> bool {
+        self.bootstrap_peers.lock().unwrap().contains(&peer)
+    }
+
+    /// Block until the node has caught up with the peers.
+    pub fn wait_synced(&self) {
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while *state != State::Synced {
+            state = cvar.wait(state).unwrap();
+        }
+    }
+}
+
+impl Context {
+    pub fn start(self) {
+        if self.state() == State::Synced {
+            return;
+        }
+        thread::Builder::new()
+            .name("sync".to_string())
+            .spawn(move || {
+                self.sync_loop();
+            })
+            .unwrap();
+    }
+
+    fn state(&self) -> State {
+        *(self.state.0).lock().unwrap()
+    }
+
+    fn set_state(&self, new_state: State) {
+        let (lock, cvar) = &*self.state;
+        *lock.lock().unwrap() = new_state;
+        cvar.notify_all();
+    }
+
+    /// Forget the peers that are disconnected.
+    fn remove_disconnected(&self, peers: &mut Vec<peer::Handle>) {
+        let mut bootstrap_peers = self.bootstrap_peers.lock().unwrap();
+        peers.retain(|peer| {
+            if peer.is_connected() {
+                return true;
+            }
+            debug!("Sync peer {} disconnected", peer.addr());
+            bootstrap_peers.remove(&peer.addr());
+            false
+        });
+    }
+
+    fn sync_loop(&self) {
+        // resume from the latest block that we have. if the peer does not know it, the peer
+        // starts from the genesis blocks. blocks that arrived at the peer before this one but are
+        // missing here are requested later as the dependencies of newer blocks
+        let mut cursor = self.blockdb.latest_block_hash().unwrap();
+        let mut peers: Vec<peer::Handle> = vec![];
+        let mut num_batches: u64 = 0;
+        // whether the peer had less than one batch left after the last batch, so that the next
+        // batch is the final one
+        let mut final_batch = false;
+        let start = time::Instant::now();
+        let batch_size = if self.headers_first {
+            HEADERS_BATCH_SIZE
+        } else {
+            BOOTSTRAP_BATCH_SIZE
+        };
+        loop {
+            // wait for a peer to download from
+            peers.extend(self.new_peer_chan.try_iter());
+            self.remove_disconnected(&mut peers);
+            if peers.is_empty() {
+                self.set_state(State::Idle);
+                peers.push(self.new_peer_chan.recv().unwrap());
+                continue;
+            }
+            let mut peer = peers[0].clone();
+            self.set_state(State::Syncing(peer.addr()));
+
+            // ask for the next batch. we only ask for the next batch after the current one is
+            // downloaded, so that a syncing node does not flood the peer or itself
+            let remaining = if self.headers_first {
+                self.headers_batch(&mut peer, &mut peers, &mut cursor)
+            } else {
+                self.bootstrap_batch(&mut peer, &mut cursor)
+            };
+
+            match remaining {
+                Some(remaining) => {
+                    num_batches += 1;
+                    debug!(
+                        "Peer {} has {} more blocks after {:.8}",
+                        peer.addr(),
+                        remaining,
+                        cursor
+                    );
+                    // the peer keeps receiving new blocks while we are downloading, so we may
+                    // never see it run out of blocks. once the rest fits in one batch, download
+                    // it and leave the blocks after that to gossip
+                    if remaining == 0 || final_batch {
+                        // the blocks from the bootstrap peers are no longer expected
+                        self.bootstrap_peers.lock().unwrap().clear();
+                        self.set_state(State::Synced);
+                        info!(
+                            "Caught up with peer {} after downloading {} batches of blocks in {} seconds",
+                            peer.addr(),
+                            num_batches,
+                            start.elapsed().as_secs()
+                        );
+                        return;
+                    }
+                    final_batch = remaining < batch_size;
+                }
+                None => {
+                    // try the next peer
+                    warn!(
+                        "Peer {} did not respond to sync request in time, disconnected or sent invalid headers",
+                        peer.addr()
+                    );
+                    peers.rotate_left(1);
+                }
+            }
+        }
+    }
+
+    /// Ask a peer for the next batch of blocks after the cursor, wait until the blocks are stored,
+    /// and move the cursor to the last block in the batch. Returns the number of blocks that the
+    /// peer has after the batch, or None if the peer does not respond in time or disconnects.
+    fn bootstrap_batch(&self, peer: &mut peer::Handle, cursor: &mut H256) -> Option<u64> {
+        debug!("Asking peer {} for blocks after {:.8}", peer.addr(), cursor);
+        self.bootstrap_peers.lock().unwrap().insert(peer.addr());
+        peer.write(Message::Bootstrap(*cursor));
+        let deadline = time::Instant::now() + BOOTSTRAP_TIMEOUT;
+        let (last, remaining) = loop {
+            match self.progress_chan.recv_timeout(FETCH_POLL_INTERVAL) {
+                Ok((addr, last, remaining)) => {
+                    // ignore late responses from the peers that we gave up on
+                    if addr != peer.addr() {
+                        continue;
+                    }
+                    break (last, remaining);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if !peer.is_connected() || time::Instant::now() >= deadline {
+                        return None;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            }
+        };
+        // the progress may arrive while the blocks of the batch are still being processed
+        while !self.blockdb.contains(&last).unwrap() {
+            if time::Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(FETCH_POLL_INTERVAL);
+        }
+        *cursor = last;
+        Some(remaining)
+    }
+
+    /// Ask a peer for the headers of the next batch of blocks after the cursor, then download the
+    /// blocks from all peers, and move the cursor to the last block in the batch. Returns the
+    /// number of blocks that the peer has after the batch, or None if the peer does not respond
+    /// in time, disconnects or sends headers that fail the checks.
+    fn headers_batch(
+        &self,
+        peer: &mut peer::Handle,
+        peers: &mut Vec<peer::Handle>,
+        cursor: &mut H256,
+    ) -> Option<u64> {
+        debug!(
+            "Asking peer {} for headers after {:.8}",
+            peer.addr(),
+            cursor
+        );
+        peer.write(Message::GetHeaders(*cursor));
+        let deadline = time::Instant::now() + BOOTSTRAP_TIMEOUT;
+        let (hashes, remaining) = loop {
+            match self.headers_chan.recv_timeout(FETCH_POLL_INTERVAL) {
+                Ok((addr, response)) => {
+                    // ignore late responses from the peers that we gave up on
+                    if addr != peer.addr() {
+                        continue;
+                    }
+                    break response?;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if !peer.is_connected() || time::Instant::now() >= deadline {
+                        return None;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            }
+        };
+        if let Some(last) = hashes.last() {
+            *cursor = *last;
+        }
+        self.fetch_blocks(&hashes, peers);
+        Some(remaining)
+    }
+
+    /// Download the given blocks from the peers in parallel, and wait until they are stored. The
+    /// blocks that do not arrive in time are asked from other peers, and after a few attempts
+    /// they are left to gossip.
+    fn fetch_blocks(&self, hashes: &[H256], peers: &mut Vec<peer::Handle>) {
+        let mut missing: Vec<H256> = hashes
+            .iter()
+            .filter(|h| !self.blockdb.contains(h).unwrap())
+            .copied()
+            .collect();
+        let mut bootstrap_peers = self.bootstrap_peers.lock().unwrap();
+        for peer in peers.iter() {
+            bootstrap_peers.insert(peer.addr());
+        }
+        drop(bootstrap_peers);
+
+        for attempt in 0..MAX_FETCH_ATTEMPTS {
+            self.remove_disconnected(peers);
+            if missing.is_empty() || peers.is_empty() {
+                break;
+            }
+            // spread the blocks over the peers, starting from a different peer in each attempt
+            debug!(
+                "Downloading {} blocks from {} peers",
+                missing.len(),
+                peers.len()
+            );
+            for (i, chunk) in missing.chunks(FETCH_BATCH_SIZE).enumerate() {
+                let peer = &mut peers[(i + attempt) % peers.len()];
+                peer.write(Message::GetBlocks(chunk.to_vec()));
+            }
+            let deadline = time::Instant::now() + FETCH_TIMEOUT;
+            while !missing.is_empty() && time::Instant::now() < deadline {
+                thread::sleep(FETCH_POLL_INTERVAL);
+                missing.retain(|h| !self.blockdb.contains(h).unwrap());
+            }
+        }
+        if !missing.is_empty() {
+            warn!(
+                "{} blocks did not arrive in time, leaving them to gossip",
+                missing.len()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new, State};
+    use crate::block::tests::proposer_block;
+    use crate::blockdb::BlockDatabase;
+    use crate::config::BlockchainConfig;
+    use crate::crypto::hash::Hashable;
+    use crate::network::message::Message;
+    use crate::network::peer::tests::new_peer;
+    use crate::network::peer::WriteQueue;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time;
+
+    fn next_message(queue: &mut WriteQueue) -> Message {
+        let buffer = futures::executor::block_on(queue.next()).unwrap();
+        bincode::deserialize(&buffer).unwrap()
+    }
+
+    fn wait_for<F: FnMut() -> bool>(mut condition: F) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(50));
+        }
+        panic!("condition does not hold after 5 seconds");
+    }
+
+    #[test]
+    fn lose_peer_and_sync() {
+        let config = BlockchainConfig::new(1, 64000, 38, 0.1, 0.1, 0.4, 20.0);
+        let blockdb = BlockDatabase::new("/tmp/prism_test_sync_blockdb.rocksdb", config).unwrap();
+        let blockdb = Arc::new(blockdb);
+        let (ctx, sync) = new(&blockdb, true, false);
+        let (mut queue_a, a) = new_peer("10.0.0.1:6000", 1000000);
+        let (mut queue_b, b) = new_peer("10.0.0.2:6000", 1000000);
+        sync.add_peer(a.clone());
+        sync.add_peer(b.clone());
+        ctx.start();
+
+        // the first peer is asked for blocks, and disconnects without responding
+        let cursor = match next_message(&mut queue_a) {
+            Message::Bootstrap(cursor) => cursor,
+            _ => panic!("expected a bootstrap request"),
+        };
+        assert_eq!(sync.state(), State::Syncing(a.addr()));
+        assert!(sync.is_bootstrap_peer(a.addr()));
+        drop(queue_a);
+
+        // the second peer is asked for the same blocks, and the first one is forgotten
+        match next_message(&mut queue_b) {
+            Message::Bootstrap(after) => assert_eq!(after, cursor),
+            _ => panic!("expected a bootstrap request"),
+        }
+        assert_eq!(sync.state(), State::Syncing(b.addr()));
+        assert!(!sync.is_bootstrap_peer(a.addr()));
+        assert!(sync.is_bootstrap_peer(b.addr()));
+
+        // the peer has less than a batch of blocks left, so the next batch is the final one
+        let first = proposer_block(cursor, 1, vec![], vec![]);
+        blockdb.insert(&first).unwrap();
+        sync.progress(b.addr(), first.hash(), 1);
+        match next_message(&mut queue_b) {
+            Message::Bootstrap(after) => assert_eq!(after, first.hash()),
+            _ => panic!("expected a bootstrap request"),
+        }
+
+        // the node is not synced until the blocks of the final batch are stored
+        let last = proposer_block(first.hash(), 2, vec![], vec![]);
+        sync.progress(b.addr(), last.hash(), 0);
+        thread::sleep(time::Duration::from_millis(500));
+        assert_eq!(sync.state(), State::Syncing(b.addr()));
+        blockdb.insert(&last).unwrap();
+        wait_for(|| sync.state() == State::Synced);
+        assert!(!sync.is_bootstrap_peer(b.addr()));
+    }
+}
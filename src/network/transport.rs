@@ -0,0 +1,82 @@
+use futures::future::LocalBoxFuture;
+use futures::io::{AsyncRead, AsyncWrite};
+use smol::Async;
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+
+/// A way for the P2P server to reach its peers, e.g. TCP, or links in memory for tests.
+pub trait Transport: Send + Sync {
+    /// Start accepting connections at the given address.
+    fn listen(&self, addr: SocketAddr) -> std::io::Result<Box<dyn Listener>>;
+
+    /// Connect to the peer at the given address.
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> LocalBoxFuture<'static, std::io::Result<Box<dyn Connection>>>;
+}
+
+/// Accepts incoming connections.
+pub trait Listener {
+    /// Wait for the next incoming connection.
+    fn accept(&self) -> LocalBoxFuture<'_, std::io::Result<Box<dyn Connection>>>;
+}
+
+/// A connection to a peer, which carries the frames in both directions as a byte stream.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {
+    /// Get the address of the peer.
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+
+    /// Get a handle that closes the connection, possibly from another thread.
+    fn disconnect_handle(&self) -> std::io::Result<Arc<dyn Disconnect>>;
+}
+
+/// Closes a connection in both directions, so that the tasks reading from and writing to it exit.
+pub trait Disconnect: Send + Sync + std::fmt::Debug {
+    fn disconnect(&self);
+}
+
+/// The transport over TCP.
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn listen(&self, addr: SocketAddr) -> std::io::Result<Box<dyn Listener>> {
+        let listener = Async::<net::TcpListener>::bind(&addr)?;
+        Ok(Box::new(listener))
+    }
+
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> LocalBoxFuture<'static, std::io::Result<Box<dyn Connection>>> {
+        Box::pin(async move {
+            let stream = Async::<net::TcpStream>::connect(&addr).await?;
+            Ok(Box::new(stream) as Box<dyn Connection>)
+        })
+    }
+}
+
+impl Listener for Async<net::TcpListener> {
+    fn accept(&self) -> LocalBoxFuture<'_, std::io::Result<Box<dyn Connection>>> {
+        Box::pin(async move {
+            let (stream, _) = Async::<net::TcpListener>::accept(self).await?;
+            Ok(Box::new(stream) as Box<dyn Connection>)
+        })
+    }
+}
+
+impl Connection for Async<net::TcpStream> {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    fn disconnect_handle(&self) -> std::io::Result<Arc<dyn Disconnect>> {
+        Ok(Arc::new(self.get_ref().try_clone()?))
+    }
+}
+
+impl Disconnect for net::TcpStream {
+    fn disconnect(&self) {
+        let _ = self.shutdown(net::Shutdown::Both);
+    }
+}
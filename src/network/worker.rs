@@ -1,7 +1,13 @@
-use super::buffer::BlockBuffer;
+use super::buffer::{BlockBuffer, BufferLimits};
+use super::lane::{self, Lane};
 use super::message::Message;
 use super::peer;
-use crate::block::{Block, Content};
+use super::relay;
+use super::request::{BlockRequests, REQUEST_TIMEOUT};
+use super::server::{Misbehavior, MAX_ADDRS_PER_MESSAGE};
+use super::sync::{self, BOOTSTRAP_BATCH_SIZE, HEADERS_BATCH_SIZE};
+use crate::block::transaction::CompactBlock;
+use crate::block::{Block, Content, ProvenHeader};
 use crate::blockchain::BlockChain;
 use crate::blockdb::BlockDatabase;
 use crate::config::*;
@@ -12,19 +18,73 @@ use crate::handler::new_validated_block;
 use crate::miner::memory_pool::MemoryPool;
 use crate::miner::ContextUpdateSignal;
 use crate::network::server::Handle as ServerHandle;
+use crate::transaction::Transaction;
 use crate::utxodb::UtxoDatabase;
-use crate::validation::{self, BlockResult};
+use crate::validation::{self, BlockResult, TransactionResult};
 use crate::wallet::Wallet;
 use crossbeam::channel;
 use log::{debug, warn};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{self, SystemTime};
+
+/// Maximum number of compact blocks waiting for their missing transactions. Beyond this, we ask for
+/// the full blocks instead.
+const MAX_PARTIAL_BLOCKS: usize = 1000;
+/// Number of rejected blocks to remember.
+const MAX_REJECTED_BLOCKS: usize = 100000;
+/// Interval between two rounds of checking for expired block requests and buffered blocks.
+const MAINTENANCE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+/// Maximum number of blocks waiting for the local time to catch up with their timestamps.
+const MAX_EARLY_BLOCKS: usize = 1000;
+/// How long a block may wait for the local time to catch up with its timestamp.
+const EARLY_BLOCK_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// Hashes of the most recent blocks that fail validation.
+#[derive(Default)]
+struct RejectedBlocks {
+    set: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+impl RejectedBlocks {
+    fn insert(&mut self, hash: H256) {
+        if !self.set.insert(hash) {
+            return;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_REJECTED_BLOCKS {
+            let oldest = self.order.pop_front().unwrap();
+            self.set.remove(&oldest);
+        }
+    }
+
+    fn contains(&self, hash: &H256) -> bool {
+        self.set.contains(hash)
+    }
+}
+
+/// A block whose timestamp is too far ahead of the local time, waiting to be processed again.
+struct EarlyBlock {
+    block: Block,
+    encoded_block: Option<Vec<u8>>,
+    peer: std::net::SocketAddr,
+    expiry: time::Instant,
+}
+
+/// A compact transaction block, and the transactions that we have found for it so far.
+struct PartialBlock {
+    compact: CompactBlock,
+    transactions: Vec<Option<Transaction>>,
+    /// The peer that we have asked for the missing transactions.
+    peer: std::net::SocketAddr,
+}
 
 #[derive(Clone)]
 pub struct Context {
-    msg_chan: piper::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_chan: lane::Receiver,
     num_worker: usize,
     chain: Arc<BlockChain>,
     blockdb: Arc<BlockDatabase>,
@@ -34,14 +94,20 @@ pub struct Context {
     context_update_chan: channel::Sender<ContextUpdateSignal>,
     server: ServerHandle,
     buffer: Arc<Mutex<BlockBuffer>>,
-    recent_blocks: Arc<Mutex<HashSet<H256>>>, // blocks that we have received but not yet inserted
-    requested_blocks: Arc<Mutex<HashSet<H256>>>, // blocks that we have requested but not yet received
+    recent_blocks: Arc<Mutex<HashSet<H256>>>, // blocks that we have received but not yet inserted or rejected
+    rejected_blocks: Arc<Mutex<RejectedBlocks>>, // blocks that fail validation
+    requested_blocks: Arc<Mutex<BlockRequests>>, // blocks that we have requested but not yet received
+    partial_blocks: Arc<Mutex<HashMap<H256, PartialBlock>>>, // compact blocks waiting for missing transactions
+    early_blocks: Arc<Mutex<Vec<EarlyBlock>>>, // blocks waiting for the local time to catch up
+    compact_blocks: bool,
+    sync: sync::Handle,
+    relay: relay::Handle,
     config: BlockchainConfig,
 }
 
 pub fn new(
     num_worker: usize,
-    msg_src: piper::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_src: lane::Receiver,
     blockchain: &Arc<BlockChain>,
     blockdb: &Arc<BlockDatabase>,
     utxodb: &Arc<UtxoDatabase>,
@@ -49,6 +115,10 @@ pub fn new(
     mempool: &Arc<Mutex<MemoryPool>>,
     ctx_update_sink: channel::Sender<ContextUpdateSignal>,
     server: &ServerHandle,
+    sync: &sync::Handle,
+    relay: &relay::Handle,
+    compact_blocks: bool,
+    buffer_limits: BufferLimits,
     config: BlockchainConfig,
 ) -> Context {
     Context {
@@ -61,9 +131,15 @@ pub fn new(
         mempool: Arc::clone(mempool),
         context_update_chan: ctx_update_sink,
         server: server.clone(),
-        buffer: Arc::new(Mutex::new(BlockBuffer::new())),
+        buffer: Arc::new(Mutex::new(BlockBuffer::new(buffer_limits))),
         recent_blocks: Arc::new(Mutex::new(HashSet::new())),
-        requested_blocks: Arc::new(Mutex::new(HashSet::new())),
+        rejected_blocks: Arc::new(Mutex::new(RejectedBlocks::default())),
+        requested_blocks: Arc::new(Mutex::new(BlockRequests::new(REQUEST_TIMEOUT))),
+        partial_blocks: Arc::new(Mutex::new(HashMap::new())),
+        early_blocks: Arc::new(Mutex::new(vec![])),
+        compact_blocks,
+        sync: sync.clone(),
+        relay: relay.clone(),
         config,
     }
 }
@@ -78,15 +154,242 @@ impl Context {
                 warn!("Worker thread {} exited", i);
             });
         }
+        thread::Builder::new()
+            .name("worker maintenance".to_string())
+            .spawn(move || loop {
+                thread::sleep(MAINTENANCE_INTERVAL);
+                let now = time::Instant::now();
+                self.evict_expired_blocks(now);
+                self.retry_requests(now);
+                self.retry_early_blocks(now);
+            })
+            .unwrap();
+    }
+
+    /// Evict the buffered blocks that have waited for their dependencies for too long.
+    fn evict_expired_blocks(&self, now: time::Instant) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let evicted = buffer.evict_expired(now);
+        PERFORMANCE_COUNTER.record_buffer(buffer.len(), buffer.num_dependencies());
+        drop(buffer);
+        if !evicted.is_empty() {
+            debug!(
+                "Evicted {} buffered blocks whose dependencies did not arrive in time",
+                evicted.len()
+            );
+            PERFORMANCE_COUNTER.record_evict_buffered_blocks(evicted.len());
+            self.release_blocks(&evicted);
+        }
+    }
+
+    /// Remember that the given block is invalid so that we don't fetch it again, and reject the
+    /// buffered blocks that depend on it.
+    fn reject_block(&self, hash: &H256) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let mut rejected = buffer.drop_dependency(hash);
+        drop(buffer);
+        if !rejected.is_empty() {
+            debug!(
+                "Rejected {} buffered blocks depending on invalid block {:.8}",
+                rejected.len(),
+                hash
+            );
+            PERFORMANCE_COUNTER.record_evict_buffered_blocks(rejected.len());
+        }
+        rejected.push(*hash);
+        let mut rejected_blocks = self.rejected_blocks.lock().unwrap();
+        for hash in &rejected {
+            rejected_blocks.insert(*hash);
+        }
+        drop(rejected_blocks);
+        PERFORMANCE_COUNTER.record_reject_blocks(rejected.len());
+        self.release_blocks(&rejected);
+    }
+
+    /// Keep a block whose timestamp is too far ahead of the local time, so that it is processed
+    /// again once the local time catches up. Such a block may be valid, so it is not rejected, and
+    /// it stays in `recent_blocks` meanwhile.
+    fn hold_early_block(
+        &self,
+        block: Block,
+        encoded_block: Option<Vec<u8>>,
+        peer: std::net::SocketAddr,
+    ) {
+        let hash = block.hash();
+        let mut early_blocks = self.early_blocks.lock().unwrap();
+        if early_blocks.len() >= MAX_EARLY_BLOCKS {
+            drop(early_blocks);
+            debug!("Too many early blocks, forgetting block {:.8}", hash);
+            self.release_blocks(&[hash]);
+            return;
+        }
+        debug!(
+            "Holding block {:.8} until the local time catches up with its timestamp",
+            hash
+        );
+        early_blocks.push(EarlyBlock {
+            block,
+            encoded_block,
+            peer,
+            expiry: time::Instant::now() + EARLY_BLOCK_TIMEOUT,
+        });
+    }
+
+    /// Process the early blocks whose timestamps are no longer too far ahead of the local time,
+    /// and forget the ones that have waited for too long. Forgotten blocks are not rejected, so
+    /// they can be fetched again.
+    fn retry_early_blocks(&self, now: time::Instant) {
+        let local_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let mut early_blocks = self.early_blocks.lock().unwrap();
+        let (ready, waiting): (Vec<EarlyBlock>, Vec<EarlyBlock>) = early_blocks
+            .drain(..)
+            .partition(|b| b.block.header.timestamp <= local_time + validation::MAX_FUTURE_TIME);
+        let (expired, waiting): (Vec<EarlyBlock>, Vec<EarlyBlock>) =
+            waiting.into_iter().partition(|b| b.expiry <= now);
+        *early_blocks = waiting;
+        drop(early_blocks);
+
+        let mut forgotten: Vec<H256> = expired.iter().map(|b| b.block.hash()).collect();
+        let mut peers: HashMap<std::net::SocketAddr, peer::Handle> = self
+            .server
+            .peers()
+            .into_iter()
+            .map(|p| (p.addr(), p))
+            .collect();
+        let mut to_process: HashMap<std::net::SocketAddr, Vec<EarlyBlock>> = HashMap::new();
+        for early_block in ready {
+            // the peer is needed to ask for the references that are missing
+            if peers.contains_key(&early_block.peer) {
+                to_process
+                    .entry(early_block.peer)
+                    .or_default()
+                    .push(early_block);
+            } else {
+                forgotten.push(early_block.block.hash());
+            }
+        }
+        if !forgotten.is_empty() {
+            debug!(
+                "Forgetting {} early blocks that waited for too long or whose peers left",
+                forgotten.len()
+            );
+            self.release_blocks(&forgotten);
+        }
+        for (addr, early_blocks) in to_process {
+            debug!(
+                "Processing {} blocks whose timestamps are no longer early",
+                early_blocks.len()
+            );
+            let mut blocks = vec![];
+            let mut encoded_blocks = HashMap::new();
+            for early_block in early_blocks {
+                if let Some(encoded_block) = early_block.encoded_block {
+                    encoded_blocks.insert(early_block.block.hash(), encoded_block);
+                }
+                blocks.push(early_block.block);
+            }
+            let peer = peers.get_mut(&addr).unwrap();
+            self.process_blocks(peer, blocks, encoded_blocks);
+        }
+    }
+
+    /// Mark that the given blocks are no longer being processed, i.e. they are stored, rejected or
+    /// evicted from the buffer.
+    fn release_blocks(&self, hashes: &[H256]) {
+        let mut recent_blocks = self.recent_blocks.lock().unwrap();
+        for hash in hashes {
+            recent_blocks.remove(hash);
+        }
+    }
+
+    /// Ask another peer for the blocks that the requested peers fail to send in time.
+    fn retry_requests(&self, now: time::Instant) {
+        let requested_blocks = self.requested_blocks.lock().unwrap();
+        let expired = requested_blocks.expired(now);
+        PERFORMANCE_COUNTER
+            .record_block_requests(requested_blocks.len(), requested_blocks.stuck().len());
+        drop(requested_blocks);
+        if expired.is_empty() {
+            return;
+        }
+
+        let peers: HashMap<std::net::SocketAddr, peer::Handle> = self
+            .server
+            .peers()
+            .into_iter()
+            .map(|p| (p.addr(), p))
+            .collect();
+        let addrs: Vec<std::net::SocketAddr> = peers.keys().copied().collect();
+        let mut to_request: HashMap<std::net::SocketAddr, Vec<H256>> = HashMap::new();
+        let mut requested_blocks = self.requested_blocks.lock().unwrap();
+        for (hash, _) in &expired {
+            // the block may have arrived in the meantime
+            if !requested_blocks.contains(hash) {
+                continue;
+            }
+            match requested_blocks.next_peer(hash, &addrs) {
+                Some(addr) => {
+                    requested_blocks.insert(*hash, addr, now);
+                    to_request.entry(addr).or_default().push(*hash);
+                }
+                None => {
+                    // no other peer to ask, so keep waiting
+                    requested_blocks.extend(hash, now);
+                }
+            }
+        }
+        let stuck = requested_blocks.stuck();
+        drop(requested_blocks);
+
+        let mut partial_blocks = self.partial_blocks.lock().unwrap();
+        for (addr, hashes) in to_request {
+            debug!(
+                "Asking peer {} for {} blocks that other peers failed to send",
+                addr,
+                hashes.len()
+            );
+            PERFORMANCE_COUNTER.record_retry_block_requests(hashes.len());
+            // ask for the full blocks, since the missing transactions of a compact block can
+            // only be fetched from the peer that sent it
+            for hash in &hashes {
+                partial_blocks.remove(hash);
+            }
+            let mut peer = peers[&addr].clone();
+            peer.write(Message::GetBlocks(hashes));
+        }
+        drop(partial_blocks);
+
+        if let Some((hash, request)) = stuck.first() {
+            warn!(
+                "{} block requests are stuck, e.g. block {:.8} requested {} times, last from peer {}",
+                stuck.len(),
+                hash,
+                request.attempts,
+                request.peer
+            );
+        }
     }
 
     fn worker_loop(&self) {
         loop {
-            let msg = futures::executor::block_on(self.msg_chan.recv()).unwrap();
-            PERFORMANCE_COUNTER.record_process_message();
+            let msg = self.msg_chan.recv().unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Error decoding message from peer {}: {}", peer.addr(), e);
+                    self.server
+                        .report(peer.addr(), Misbehavior::MalformedMessage);
+                    continue;
+                }
+            };
             match msg {
+                Message::Handshake(_) => {
+                    debug!("Ignoring repeated handshake");
+                }
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
                     peer.write(Message::Pong(nonce.to_string()));
@@ -96,6 +399,7 @@ impl Context {
                 }
                 Message::NewTransactionHashes(hashes) => {
                     debug!("Got {} new transaction hashes", hashes.len());
+                    self.relay.mark_known(peer.addr(), &hashes);
                     let mut hashes_to_request = vec![];
                     for hash in hashes {
                         if !self.mempool.lock().unwrap().contains(&hash) {
@@ -109,20 +413,42 @@ impl Context {
                 Message::GetTransactions(hashes) => {
                     debug!("Asked for {} transactions", hashes.len());
                     let mut transactions = vec![];
+                    let mut sent = vec![];
                     for hash in hashes {
                         match self.mempool.lock().unwrap().get(&hash) {
                             None => {}
                             Some(entry) => {
                                 transactions.push(entry.transaction.clone());
+                                sent.push(hash);
                             }
                         }
                     }
-                    peer.write(Message::Transactions(transactions));
+                    if !transactions.is_empty() {
+                        self.relay.mark_known(peer.addr(), &sent);
+                        peer.write(Message::Transactions(transactions));
+                    }
                 }
                 Message::Transactions(transactions) => {
                     debug!("Got {} transactions", transactions.len());
-                    for transaction in transactions {
-                        new_transaction(transaction, &self.mempool, &self.server);
+                    let hashes: Vec<H256> = transactions.iter().map(|t| t.hash()).collect();
+                    self.relay.mark_known(peer.addr(), &hashes);
+                    // the transactions that enter the memory pool are relayed to the other peers, and
+                    // the peer is told about the invalid ones
+                    let mut rejected = vec![];
+                    for (transaction, hash) in transactions.into_iter().zip(hashes) {
+                        match new_transaction(transaction, &self.mempool, &self.utxodb, &self.relay)
+                        {
+                            TransactionResult::Pass | TransactionResult::Duplicate => {}
+                            result => rejected.push((hash, result)),
+                        }
+                    }
+                    if !rejected.is_empty() {
+                        peer.write(Message::RejectedTransactions(rejected));
+                    }
+                }
+                Message::RejectedTransactions(rejected) => {
+                    for (hash, result) in rejected {
+                        debug!("Peer rejected transaction {:.8}: {}", hash, result);
                     }
                 }
                 Message::NewBlockHashes(hashes) => {
@@ -133,208 +459,662 @@ impl Context {
                         let requested_blocks = self.requested_blocks.lock().unwrap();
                         let requested = requested_blocks.contains(&hash);
                         drop(requested_blocks);
-                        if !(in_blockdb || requested) {
+                        let processing = self.recent_blocks.lock().unwrap().contains(&hash);
+                        let rejected = self.rejected_blocks.lock().unwrap().contains(&hash);
+                        if !(in_blockdb || requested || processing || rejected) {
                             hashes_to_request.push(hash);
                         }
                     }
+                    let now = time::Instant::now();
                     let mut requested_blocks = self.requested_blocks.lock().unwrap();
                     for hash in &hashes_to_request {
-                        requested_blocks.insert(*hash);
+                        requested_blocks.insert(*hash, peer.addr(), now);
                     }
                     drop(requested_blocks);
                     if !hashes_to_request.is_empty() {
-                        peer.write(Message::GetBlocks(hashes_to_request));
+                        peer.write(self.get_blocks(hashes_to_request));
                     }
                 }
                 Message::GetBlocks(hashes) => {
                     debug!("Asked for {} blocks", hashes.len());
+                    // send the proposer and voter blocks separately, so that the peer does not
+                    // process them in the same lane as transaction blocks
                     let mut blocks = vec![];
+                    let mut transaction_blocks = vec![];
                     for hash in hashes {
                         match self.blockdb.get_encoded(&hash).unwrap() {
                             None => {}
                             Some(encoded_block) => {
-                                blocks.push(encoded_block.to_vec());
+                                let encoded_block = encoded_block.to_vec();
+                                match lane::classify_block(&encoded_block) {
+                                    Lane::Consensus => blocks.push(encoded_block),
+                                    Lane::Payload => transaction_blocks.push(encoded_block),
+                                }
                             }
                         }
                     }
-                    peer.write(Message::Blocks(blocks));
+                    if !blocks.is_empty() || transaction_blocks.is_empty() {
+                        peer.write(Message::Blocks(blocks));
+                    }
+                    if !transaction_blocks.is_empty() {
+                        peer.write(Message::Blocks(transaction_blocks));
+                    }
                 }
                 Message::Blocks(encoded_blocks) => {
                     debug!("Got {} blocks", encoded_blocks.len());
 
                     // decode the blocks
-                    let mut blocks: Vec<Block> = vec![];
-                    let mut hashes: Vec<H256> = vec![];
-                    for encoded_block in &encoded_blocks {
+                    let mut blocks = vec![];
+                    for encoded_block in encoded_blocks {
+                        match bincode::deserialize(&encoded_block) {
+                            Ok(block) => blocks.push((block, encoded_block)),
+                            Err(e) => {
+                                warn!("Error decoding block from peer {}: {}", peer.addr(), e);
+                                self.server
+                                    .report(peer.addr(), Misbehavior::MalformedMessage);
+                            }
+                        }
+                    }
+                    self.receive_blocks(&mut peer, blocks);
+                }
+                Message::GetCompactBlocks(hashes) => {
+                    debug!("Asked for {} compact blocks", hashes.len());
+                    let mut blocks = vec![];
+                    let mut compact_blocks = vec![];
+                    for hash in hashes {
+                        let encoded_block = match self.blockdb.get_encoded(&hash).unwrap() {
+                            None => continue,
+                            Some(encoded_block) => encoded_block.to_vec(),
+                        };
                         let block: Block = bincode::deserialize(&encoded_block).unwrap();
-                        let hash = block.hash();
-
-                        // now that the block that we request has arrived, remove it from the set
-                        // of requested blocks. removing it at this stage causes a race condition,
-                        // where the block could have been removed from requested_blocks but not
-                        // yet inserted into the database. but this does not cause correctness
-                        // problem and hardly incurs a performance issue (I hope)
-                        let mut requested_blocks = self.requested_blocks.lock().unwrap();
-                        requested_blocks.remove(&hash);
-                        drop(requested_blocks);
-
-                        // check POW here. If POW does not pass, discard the block at this
-                        // stage
-                        let pow_check = validation::check_pow_sortition_id(&block, &self.config);
-                        match pow_check {
-                            BlockResult::Pass => {}
-                            _ => continue,
+                        match CompactBlock::from_block(&block) {
+                            Some(compact) => compact_blocks.push(compact),
+                            None => blocks.push(encoded_block),
                         }
-
-                        // check whether the block is being processed. note that here we use lock
-                        // to make sure that the hash either in recent_blocks, or blockdb, so we
-                        // don't have a single duplicate
-                        let mut recent_blocks = self.recent_blocks.lock().unwrap();
-                        if recent_blocks.contains(&hash) {
-                            drop(recent_blocks);
-                            continue;
+                    }
+                    if !blocks.is_empty() {
+                        peer.write(Message::Blocks(blocks));
+                    }
+                    if !compact_blocks.is_empty() {
+                        peer.write(Message::CompactBlocks(compact_blocks));
+                    }
+                }
+                Message::CompactBlocks(compact_blocks) => {
+                    debug!("Got {} compact blocks", compact_blocks.len());
+                    let mut blocks = vec![];
+                    for compact in compact_blocks {
+                        if let Some(block) = self.receive_compact_block(&mut peer, compact) {
+                            blocks.push(block);
                         }
-                        // register this block as being processed
-                        recent_blocks.insert(hash);
-                        drop(recent_blocks);
-
-                        // TODO: consider the ordering here. I'd expect a lot of duplicate blocks
-                        // to proceed to this step, which means a lot of useless database lookups
-                        // and lock/unlocks
-                        // detect duplicates
-                        if self.blockdb.contains(&hash).unwrap() {
-                            let mut recent_blocks = self.recent_blocks.lock().unwrap();
-                            recent_blocks.remove(&hash);
-                            drop(recent_blocks);
+                    }
+                    self.receive_blocks(&mut peer, blocks);
+                }
+                Message::GetBlockTransactions(hash, indices) => {
+                    debug!(
+                        "Asked for {} transactions in block {:.8}",
+                        indices.len(),
+                        hash
+                    );
+                    let content = match self.blockdb.get(&hash).unwrap() {
+                        Some(Block {
+                            content: Content::Transaction(content),
+                            ..
+                        }) => content,
+                        _ => continue,
+                    };
+                    let transactions = indices
+                        .iter()
+                        .filter_map(|i| content.transactions.get(*i as usize))
+                        .cloned()
+                        .collect();
+                    peer.write(Message::BlockTransactions(hash, transactions));
+                }
+                Message::BlockTransactions(hash, transactions) => {
+                    debug!(
+                        "Got {} transactions in block {:.8}",
+                        transactions.len(),
+                        hash
+                    );
+                    // only take the transactions from the peer that we asked for them
+                    let mut partial_blocks = self.partial_blocks.lock().unwrap();
+                    let partial = match partial_blocks.get(&hash) {
+                        Some(partial) if partial.peer == peer.addr() => {
+                            partial_blocks.remove(&hash)
+                        }
+                        _ => None,
+                    };
+                    drop(partial_blocks);
+                    let mut partial = match partial {
+                        Some(partial) => partial,
+                        None => {
+                            debug!(
+                                "Ignoring unrequested transactions of compact block {:.8} from peer {}",
+                                hash,
+                                peer.addr()
+                            );
+                            self.server
+                                .report(peer.addr(), Misbehavior::UnsolicitedTransactions);
                             continue;
                         }
+                    };
+                    // fill in the missing transactions in order
+                    let mut received = transactions.into_iter();
+                    for slot in partial.transactions.iter_mut().filter(|t| t.is_none()) {
+                        *slot = received.next();
+                    }
+                    if received.next().is_some() || partial.transactions.iter().any(|t| t.is_none())
+                    {
+                        debug!(
+                            "Got wrong number of transactions for compact block {:.8}",
+                            hash
+                        );
+                        PERFORMANCE_COUNTER.record_fail_compact_block();
+                        self.request_full_blocks(&mut peer, vec![hash]);
+                        continue;
+                    }
+                    let transactions = partial
+                        .transactions
+                        .into_iter()
+                        .map(|t| t.unwrap())
+                        .collect();
+                    if let Some(block) =
+                        self.rebuild_compact_block(&mut peer, partial.compact, transactions)
+                    {
+                        self.receive_blocks(&mut peer, vec![block]);
+                    }
+                }
+                Message::Bootstrap(after) => {
+                    debug!("Asked for blocks after {:.8}", &after);
+                    // if we don't know the block, start from the genesis blocks
+                    let after = if self.blockdb.contains(&after).unwrap() {
+                        after
+                    } else {
+                        self.config.voter_genesis[self.config.voter_chains as usize - 1]
+                    };
+                    let batch = self
+                        .blockdb
+                        .blocks_after(&after, BOOTSTRAP_BATCH_SIZE)
+                        .next()
+                        .unwrap_or_default();
+                    let last = match batch.last() {
+                        Some(block) => block.hash(),
+                        None => after,
+                    };
+                    let remaining = self.blockdb.num_blocks()
+                        - self.blockdb.sequence_number(&last).unwrap().unwrap()
+                        - 1;
+                    if !batch.is_empty() {
+                        let encoded_blocks = batch
+                            .iter()
+                            .map(|b| bincode::serialize(&b).unwrap())
+                            .collect();
+                        peer.write(Message::Blocks(encoded_blocks));
+                    }
+                    peer.write(Message::BootstrapProgress(last, remaining));
+                }
+                Message::BootstrapProgress(last, remaining) => {
+                    debug!(
+                        "Got bootstrap progress, {} blocks after {:.8}",
+                        remaining, &last
+                    );
+                    self.sync.progress(peer.addr(), last, remaining);
+                }
+                Message::GetHeaders(after) => {
+                    debug!("Asked for headers after {:.8}", &after);
+                    // if we don't know the block, start from the genesis blocks
+                    let after = if self.blockdb.contains(&after).unwrap() {
+                        after
+                    } else {
+                        self.config.voter_genesis[self.config.voter_chains as usize - 1]
+                    };
+                    let batch = self
+                        .blockdb
+                        .blocks_after(&after, HEADERS_BATCH_SIZE)
+                        .next()
+                        .unwrap_or_default();
+                    let last = match batch.last() {
+                        Some(block) => block.hash(),
+                        None => after,
+                    };
+                    let remaining = self.blockdb.num_blocks()
+                        - self.blockdb.sequence_number(&last).unwrap().unwrap()
+                        - 1;
+                    let headers = batch.iter().map(ProvenHeader::from_block).collect();
+                    peer.write(Message::Headers(headers, remaining));
+                }
+                Message::Headers(headers, remaining) => {
+                    debug!(
+                        "Got {} headers, {} blocks after them",
+                        headers.len(),
+                        remaining
+                    );
+                    // check the PoW, the sortition proofs and the difficulty before downloading
+                    // any content
+                    let checked = validation::check_headers(
+                        &headers,
+                        &self.chain,
+                        &self.blockdb,
+                        &self.config,
+                    );
+                    match checked {
+                        Ok(hashes) => self.sync.headers(peer.addr(), hashes, remaining),
+                        Err(result) => {
+                            warn!("Invalid header from peer {}: {}", peer.addr(), result);
+                            let misbehavior = match result {
+                                BlockResult::WrongPoW | BlockResult::WrongDifficulty => {
+                                    Misbehavior::InvalidPoW
+                                }
+                                _ => Misbehavior::InvalidSortitionProof,
+                            };
+                            self.server.report(peer.addr(), misbehavior);
+                            self.sync.invalid_headers(peer.addr());
+                        }
+                    }
+                }
+                Message::GetPeers => {
+                    debug!("Asked for known peers");
+                    peer.write(Message::Peers(self.server.known_addrs()));
+                }
+                Message::Peers(mut addrs) => {
+                    debug!("Got {} peer addresses", addrs.len());
+                    addrs.truncate(MAX_ADDRS_PER_MESSAGE);
+                    // a peer listening on all interfaces does not know its own IP address, so we
+                    // use the one that it connects to us from
+                    for addr in addrs.iter_mut() {
+                        if addr.ip().is_unspecified() {
+                            addr.set_ip(peer.addr().ip());
+                        }
+                    }
+                    self.server.add_known_addrs(peer.addr(), addrs);
+                }
+            }
+        }
+    }
 
-                        // store the block into database
-                        self.blockdb.insert_encoded(&hash, &encoded_block).unwrap();
+    /// Validate and process the blocks received from a peer, together with their encoded form.
+    /// Blocks are only stored into the block database after they pass validation. Until then,
+    /// they are staged in memory, either in the buffer or in this function.
+    fn receive_blocks(&self, peer: &mut peer::Handle, received_blocks: Vec<(Block, Vec<u8>)>) {
+        let mut blocks: Vec<Block> = vec![];
+        let mut encoded_blocks: HashMap<H256, Vec<u8>> = HashMap::new();
+        for (block, encoded_block) in received_blocks {
+            let hash = block.hash();
 
-                        // now that this block is store, remove the reference
-                        let mut recent_blocks = self.recent_blocks.lock().unwrap();
-                        recent_blocks.remove(&hash);
-                        drop(recent_blocks);
+            // now that the block that we request has arrived, remove it from the set
+            // of requested blocks. removing it at this stage causes a race condition,
+            // where the block could have been removed from requested_blocks but not
+            // yet inserted into the database. but this does not cause correctness
+            // problem and hardly incurs a performance issue (I hope)
+            let mut requested_blocks = self.requested_blocks.lock().unwrap();
+            let requested = requested_blocks.remove(&hash);
+            drop(requested_blocks);
 
-                        blocks.push(block);
-                        hashes.push(hash);
-                    }
+            // check POW here. If POW does not pass, discard the block at this
+            // stage
+            let pow_check = validation::check_pow_sortition_id(&block, &self.config);
+            match pow_check {
+                BlockResult::Pass => {}
+                _ => {
+                    warn!(
+                        "Ignoring block {:.8} from peer {}: {}",
+                        hash,
+                        peer.addr(),
+                        pow_check
+                    );
+                    self.server.report(peer.addr(), Misbehavior::InvalidPoW);
+                    self.rejected_blocks.lock().unwrap().insert(hash);
+                    continue;
+                }
+            }
 
-                    for block in &blocks {
-                        PERFORMANCE_COUNTER.record_receive_block(&block);
-                    }
+            // check whether the block is being processed. a block stays in recent_blocks until
+            // it is either stored into blockdb or rejected, so holding the lock while checking
+            // both makes sure that we don't have a single duplicate
+            let mut recent_blocks = self.recent_blocks.lock().unwrap();
+            if recent_blocks.contains(&hash)
+                || self.blockdb.contains(&hash).unwrap()
+                || self.rejected_blocks.lock().unwrap().contains(&hash)
+            {
+                drop(recent_blocks);
+                continue;
+            }
+            // register this block as being processed
+            recent_blocks.insert(hash);
+            drop(recent_blocks);
 
-                    // tell peers about the new blocks
-                    // TODO: we will do this only in a reasonable network topology
-                    if hashes.is_empty() {
-                        continue; // end processing this message
-                    }
-                    self.server
-                        .broadcast(Message::NewBlockHashes(hashes.clone()));
-
-                    // process each block
-                    let mut to_process: Vec<Block> = blocks;
-                    let mut to_request: Vec<H256> = vec![];
-                    let mut context_update_sig = vec![];
-                    while let Some(block) = to_process.pop() {
-                        // check data availability
-                        // make sure checking data availability and buffering are one atomic
-                        // operation. see the comments in buffer.rs
-                        let mut buffer = self.buffer.lock().unwrap();
-                        let data_availability =
-                            validation::check_data_availability(&block, &self.chain, &self.blockdb);
-                        match data_availability {
-                            BlockResult::Pass => drop(buffer),
-                            BlockResult::MissingReferences(r) => {
-                                debug!(
-                                    "Missing {} referred blocks for block {:.8}",
-                                    r.len(),
-                                    block.hash()
-                                );
-                                buffer.insert(block, &r);
-                                to_request.extend_from_slice(&r);
-                                drop(buffer);
-                                continue;
-                            }
-                            _ => unreachable!(),
-                        }
+            // a new block that we neither asked for nor are bootstrapping from this
+            // peer. we still take it, since it has passed the PoW check
+            if !requested && !self.sync.is_bootstrap_peer(peer.addr()) {
+                self.server
+                    .report(peer.addr(), Misbehavior::UnsolicitedBlock);
+            }
 
-                        // check sortition proof and content semantics
-                        let sortition_proof =
-                            validation::check_sortition_proof(&block, &self.config);
-                        match sortition_proof {
-                            BlockResult::Pass => {}
-                            _ => {
-                                warn!(
-                                    "Ignoring invalid block {:.8}: {}",
-                                    block.hash(),
-                                    sortition_proof
-                                );
-                                continue;
-                            }
-                        }
-                        let content_semantic =
-                            validation::check_content_semantic(&block, &self.chain, &self.blockdb);
-                        match content_semantic {
-                            BlockResult::Pass => {}
-                            _ => {
-                                warn!(
-                                    "Ignoring invalid block {:.8}: {}",
-                                    block.hash(),
-                                    content_semantic
-                                );
-                                continue;
-                            }
-                        }
+            encoded_blocks.insert(hash, encoded_block);
+            blocks.push(block);
+        }
 
-                        debug!("Processing block {:.8}", block.hash());
-                        new_validated_block(
-                            &block,
-                            &self.mempool,
-                            &self.blockdb,
-                            &self.chain,
-                            &self.server,
-                        );
-                        context_update_sig.push(match &block.content {
-                            Content::Proposer(_) => ContextUpdateSignal::NewProposerBlock,
-                            Content::Voter(c) => ContextUpdateSignal::NewVoterBlock(c.chain_number),
-                            Content::Transaction(_) => ContextUpdateSignal::NewTransactionBlock,
-                        });
-                        let mut buffer = self.buffer.lock().unwrap();
-                        let mut resolved_by_current = buffer.satisfy(block.hash());
+        for block in &blocks {
+            PERFORMANCE_COUNTER.record_receive_block(&block);
+        }
+        if blocks.is_empty() {
+            return; // end processing this message
+        }
+        self.process_blocks(peer, blocks, encoded_blocks);
+    }
+
+    /// Validate and store the given blocks, which are registered in `recent_blocks`, and the
+    /// buffered blocks that they resolve. `encoded_blocks` holds the encoded form of the blocks
+    /// that the peer has sent.
+    fn process_blocks(
+        &self,
+        peer: &mut peer::Handle,
+        blocks: Vec<Block>,
+        mut encoded_blocks: HashMap<H256, Vec<u8>>,
+    ) {
+        // process each block. blocks resolved from the buffer may come from other
+        // peers, so only blocks sent by the peer count towards its misbehavior
+        let received: HashSet<H256> = encoded_blocks.keys().copied().collect();
+        let mut to_process: Vec<Block> = blocks;
+        let mut to_request: Vec<H256> = vec![];
+        let mut processed: Vec<H256> = vec![];
+        let mut context_update_sig = vec![];
+        while let Some(block) = to_process.pop() {
+            let hash = block.hash();
+
+            // check data availability
+            // make sure checking data availability and buffering are one atomic
+            // operation. see the comments in buffer.rs
+            let mut buffer = self.buffer.lock().unwrap();
+            let data_availability =
+                validation::check_data_availability(&block, &self.chain, &self.blockdb);
+            match data_availability {
+                BlockResult::Pass => drop(buffer),
+                BlockResult::MissingReferences(r) => {
+                    // a block that depends on a rejected block is invalid as well
+                    if r.iter()
+                        .any(|h| self.rejected_blocks.lock().unwrap().contains(h))
+                    {
                         drop(buffer);
-                        if !resolved_by_current.is_empty() {
-                            debug!(
-                                "Resolved dependency for {} buffered blocks",
-                                resolved_by_current.len()
-                            );
-                        }
-                        for b in resolved_by_current.drain(..) {
-                            to_process.push(b);
-                        }
+                        warn!("Ignoring block {:.8} that refers to invalid blocks", hash);
+                        self.reject_block(&hash);
+                        continue;
                     }
-                    // tell the miner to update the context
-                    for sig in context_update_sig {
-                        self.context_update_chan.send(sig).unwrap();
+                    debug!("Missing {} referred blocks for block {:.8}", r.len(), hash);
+                    let evicted = buffer.insert(block, &r, Some(peer.addr()), time::Instant::now());
+                    PERFORMANCE_COUNTER.record_buffer(buffer.len(), buffer.num_dependencies());
+                    drop(buffer);
+                    if !evicted.is_empty() {
+                        debug!("Evicted {} buffered blocks to make room", evicted.len());
+                        PERFORMANCE_COUNTER.record_evict_buffered_blocks(evicted.len());
+                        self.release_blocks(&evicted);
                     }
+                    // no need to ask for the blocks that we are already processing
+                    let recent_blocks = self.recent_blocks.lock().unwrap();
+                    to_request.extend(r.iter().filter(|h| !recent_blocks.contains(*h)));
+                    drop(recent_blocks);
+                    continue;
+                }
+                _ => unreachable!(),
+            }
 
-                    if !to_request.is_empty() {
-                        to_request.sort();
-                        to_request.dedup();
-                        peer.write(Message::GetBlocks(to_request));
+            // check sortition proof and content semantics
+            let sortition_proof = validation::check_sortition_proof(&block, &self.config);
+            match sortition_proof {
+                BlockResult::Pass => {}
+                _ => {
+                    warn!("Ignoring invalid block {:.8}: {}", hash, sortition_proof);
+                    if received.contains(&hash) {
+                        self.server
+                            .report(peer.addr(), Misbehavior::InvalidSortitionProof);
                     }
+                    self.reject_block(&hash);
+                    continue;
                 }
-                Message::Bootstrap(after) => {
-                    debug!("Asked for all blocks after {}", &after);
-                    /*
-                     * TODO: recover this message
-                    for batch in self.blockdb.blocks_after(&after, 500) {
-                        peer.write(Message::Blocks(batch));
+            }
+            let difficulty =
+                validation::check_difficulty(&block, &self.chain, &self.blockdb, &self.config);
+            match difficulty {
+                BlockResult::Pass => {}
+                _ => {
+                    warn!("Ignoring invalid block {:.8}: {}", hash, difficulty);
+                    if received.contains(&hash) {
+                        self.server.report(peer.addr(), Misbehavior::InvalidPoW);
                     }
-                    */
+                    self.reject_block(&hash);
+                    continue;
+                }
+            }
+            let timestamp = validation::check_timestamp(
+                &block,
+                &self.blockdb,
+                &self.config,
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis(),
+            );
+            match timestamp {
+                BlockResult::Pass => {}
+                BlockResult::FutureTimestamp => {
+                    // our clock may be behind, so the block is not rejected
+                    self.hold_early_block(block, encoded_blocks.remove(&hash), peer.addr());
+                    continue;
+                }
+                _ => {
+                    warn!("Ignoring invalid block {:.8}: {}", hash, timestamp);
+                    self.reject_block(&hash);
+                    continue;
+                }
+            }
+            let content_semantic =
+                validation::check_content_semantic(&block, &self.chain, &self.blockdb);
+            match content_semantic {
+                BlockResult::Pass => {}
+                _ => {
+                    warn!("Ignoring invalid block {:.8}: {}", hash, content_semantic);
+                    self.reject_block(&hash);
+                    continue;
+                }
+            }
+
+            // the block is valid, so store it into database. blocks resolved from the buffer are
+            // encoded again
+            match encoded_blocks.remove(&hash) {
+                Some(encoded_block) => self.blockdb.insert_encoded(&hash, &encoded_block).unwrap(),
+                None => self.blockdb.insert(&block).unwrap(),
+            };
+            // now that this block is stored, remove the reference
+            self.release_blocks(&[hash]);
+
+            debug!("Processing block {:.8}", hash);
+            new_validated_block(
+                &block,
+                &self.mempool,
+                &self.blockdb,
+                &self.chain,
+                &self.server,
+            );
+            processed.push(hash);
+            context_update_sig.push(match &block.content {
+                Content::Proposer(_) => ContextUpdateSignal::NewProposerBlock,
+                Content::Voter(c) => ContextUpdateSignal::NewVoterBlock(c.chain_number),
+                Content::Transaction(_) => ContextUpdateSignal::NewTransactionBlock,
+            });
+            let mut buffer = self.buffer.lock().unwrap();
+            let mut resolved_by_current = buffer.satisfy(hash);
+            PERFORMANCE_COUNTER.record_buffer(buffer.len(), buffer.num_dependencies());
+            drop(buffer);
+            if !resolved_by_current.is_empty() {
+                debug!(
+                    "Resolved dependency for {} buffered blocks",
+                    resolved_by_current.len()
+                );
+            }
+            for b in resolved_by_current.drain(..) {
+                to_process.push(b);
+            }
+        }
+        // tell the miner to update the context
+        for sig in context_update_sig {
+            self.context_update_chan.send(sig).unwrap();
+        }
+
+        // tell peers about the new blocks. only valid blocks are announced, since peers can only
+        // get the blocks in our database
+        // TODO: we will do this only in a reasonable network topology
+        if !processed.is_empty() {
+            self.server.broadcast(Message::NewBlockHashes(processed));
+        }
+
+        if !to_request.is_empty() {
+            to_request.sort();
+            to_request.dedup();
+            let now = time::Instant::now();
+            let mut requested_blocks = self.requested_blocks.lock().unwrap();
+            for hash in &to_request {
+                requested_blocks.insert(*hash, peer.addr(), now);
+            }
+            drop(requested_blocks);
+            peer.write(self.get_blocks(to_request));
+        }
+    }
+
+    /// Ask the peer for the given blocks in full, e.g. when a compact block cannot be rebuilt, and
+    /// record the requests so that they are retried and expire like the others.
+    fn request_full_blocks(&self, peer: &mut peer::Handle, hashes: Vec<H256>) {
+        let now = time::Instant::now();
+        let mut requested_blocks = self.requested_blocks.lock().unwrap();
+        for hash in &hashes {
+            requested_blocks.insert(*hash, peer.addr(), now);
+        }
+        drop(requested_blocks);
+        peer.write(Message::GetBlocks(hashes));
+    }
+
+    /// Get the message that asks for the given blocks, in the compact form if enabled.
+    fn get_blocks(&self, hashes: Vec<H256>) -> Message {
+        if self.compact_blocks {
+            Message::GetCompactBlocks(hashes)
+        } else {
+            Message::GetBlocks(hashes)
+        }
+    }
+
+    /// Rebuild a compact block from the transactions in the memory pool. Returns the block if all
+    /// transactions are found, otherwise asks the peer for the missing ones.
+    fn receive_compact_block(
+        &self,
+        peer: &mut peer::Handle,
+        compact: CompactBlock,
+    ) -> Option<(Block, Vec<u8>)> {
+        let hash = compact.header.hash();
+        if self.blockdb.contains(&hash).unwrap()
+            || self.recent_blocks.lock().unwrap().contains(&hash)
+            || self.rejected_blocks.lock().unwrap().contains(&hash)
+            || self.partial_blocks.lock().unwrap().contains_key(&hash)
+        {
+            return None;
+        }
+
+        // check POW before doing any work for the block
+        let placeholder = Block::from_header(
+            compact.header,
+            Content::Transaction(Default::default()),
+            vec![],
+        );
+        let pow_check = validation::check_pow_sortition_id(&placeholder, &self.config);
+        match pow_check {
+            BlockResult::Pass => {}
+            _ => {
+                warn!(
+                    "Ignoring compact block {:.8} from peer {}: {}",
+                    hash,
+                    peer.addr(),
+                    pow_check
+                );
+                self.requested_blocks.lock().unwrap().remove(&hash);
+                self.server.report(peer.addr(), Misbehavior::InvalidPoW);
+                self.rejected_blocks.lock().unwrap().insert(hash);
+                return None;
+            }
+        }
+
+        // look up the transactions in the memory pool
+        let mempool = self.mempool.lock().unwrap();
+        let mut transactions = Vec::with_capacity(compact.short_ids.len());
+        let mut missing = vec![];
+        for (index, id) in compact.short_ids.iter().enumerate() {
+            match mempool.get_by_short_id(*id) {
+                Some(entry) => transactions.push(Some(entry.transaction.clone())),
+                None => {
+                    transactions.push(None);
+                    missing.push(index as u32);
                 }
             }
         }
+        drop(mempool);
+        PERFORMANCE_COUNTER.record_receive_compact_block(missing.len());
+
+        if missing.is_empty() {
+            let transactions = transactions.into_iter().map(|t| t.unwrap()).collect();
+            return self.rebuild_compact_block(peer, compact, transactions);
+        }
+        debug!(
+            "Missing {} transactions for compact block {:.8}",
+            missing.len(),
+            hash
+        );
+        let mut partial_blocks = self.partial_blocks.lock().unwrap();
+        if partial_blocks.len() >= MAX_PARTIAL_BLOCKS {
+            drop(partial_blocks);
+            self.request_full_blocks(peer, vec![hash]);
+            return None;
+        }
+        partial_blocks.insert(
+            hash,
+            PartialBlock {
+                compact,
+                transactions,
+                peer: peer.addr(),
+            },
+        );
+        drop(partial_blocks);
+        // track the block as requested from this peer, so that another peer is asked for the full
+        // block if the transactions do not arrive in time
+        self.requested_blocks
+            .lock()
+            .unwrap()
+            .insert(hash, peer.addr(), time::Instant::now());
+        peer.write(Message::GetBlockTransactions(hash, missing));
+        None
+    }
+
+    /// Build the block from a compact block and its transactions, and check it against the content
+    /// Merkle root in the header. If the check fails, e.g. because short IDs collide, ask the peer
+    /// for the full block.
+    fn rebuild_compact_block(
+        &self,
+        peer: &mut peer::Handle,
+        compact: CompactBlock,
+        transactions: Vec<Transaction>,
+    ) -> Option<(Block, Vec<u8>)> {
+        let block = compact.into_block(transactions);
+        let sortition_proof = validation::check_sortition_proof(&block, &self.config);
+        match sortition_proof {
+            BlockResult::Pass => {
+                let encoded_block = bincode::serialize(&block).unwrap();
+                Some((block, encoded_block))
+            }
+            _ => {
+                let hash = block.hash();
+                debug!(
+                    "Failed to rebuild compact block {:.8}: {}",
+                    hash, sortition_proof
+                );
+                PERFORMANCE_COUNTER.record_fail_compact_block();
+                self.request_full_blocks(peer, vec![hash]);
+                None
+            }
+        }
     }
 }
@@ -0,0 +1,341 @@
+//! Wiring of the parts of a full node: the ledger manager, the P2P server, the initial block
+//! download, the transaction relay, the worker and the miner. The P2P server runs over the given
+//! transport, so that several nodes can run in one process on an in-memory network.
+
+use crate::blockchain::BlockChain;
+use crate::blockdb::BlockDatabase;
+use crate::config::BlockchainConfig;
+use crate::ledger_manager::LedgerManager;
+use crate::miner;
+use crate::miner::memory_pool::MemoryPool;
+use crate::network::address_book::AddressBook;
+use crate::network::buffer::BufferLimits;
+use crate::network::lane::{self, LaneSizes};
+use crate::network::message::FrameLimits;
+use crate::network::peer::WriteQueueConfig;
+use crate::network::relay;
+use crate::network::server;
+use crate::network::sync;
+use crate::network::transport::Transport;
+use crate::network::worker;
+use crate::transaction::Address;
+use crate::utxodb::UtxoDatabase;
+use crate::wallet::Wallet;
+use crossbeam::channel;
+use log::debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Settings of the parts of a node.
+pub struct NodeConfig {
+    /// Address of the P2P server.
+    pub p2p_addr: SocketAddr,
+    pub address_book: AddressBook,
+    /// Number of outgoing peers that the P2P server tries to keep.
+    pub outgoing_peers: usize,
+    pub network_id: u32,
+    pub frame_limits: FrameLimits,
+    pub write_queue_config: WriteQueueConfig,
+    pub lane_sizes: LaneSizes,
+    pub buffer_limits: BufferLimits,
+    /// Number of threads processing the P2P messages.
+    pub p2p_workers: usize,
+    /// Number of threads executing the transactions in the ledger.
+    pub execution_workers: usize,
+    /// Size of the buffer of the ledger diffs waiting to be executed.
+    pub execution_buffer: usize,
+    pub compact_blocks: bool,
+    /// Whether to download the blocks from the peers that we connect to before mining.
+    pub bootstrap: bool,
+    pub headers_first: bool,
+    /// Address that the mining rewards go to.
+    pub miner_addr: Address,
+}
+
+/// A running node.
+pub struct Node {
+    pub blockdb: Arc<BlockDatabase>,
+    pub blockchain: Arc<BlockChain>,
+    pub utxodb: Arc<UtxoDatabase>,
+    pub wallet: Arc<Wallet>,
+    pub mempool: Arc<Mutex<MemoryPool>>,
+    pub server: server::Handle,
+    pub sync: sync::Handle,
+    pub relay: relay::Handle,
+    pub miner: miner::Handle,
+}
+
+impl Node {
+    /// Connect to the peer at the given address, and download blocks from it if we are still
+    /// catching up.
+    pub fn connect(&self, addr: SocketAddr) -> std::io::Result<()> {
+        let handle = self.server.connect(addr)?;
+        self.sync.add_peer(handle);
+        Ok(())
+    }
+}
+
+/// Start a node on the given databases, with the P2P server running over the given transport. The
+/// miner starts paused once the node has caught up with its peers.
+pub fn start(
+    blockdb: &Arc<BlockDatabase>,
+    blockchain: &Arc<BlockChain>,
+    utxodb: &Arc<UtxoDatabase>,
+    wallet: &Arc<Wallet>,
+    mempool: &Arc<Mutex<MemoryPool>>,
+    config: NodeConfig,
+    blockchain_config: BlockchainConfig,
+    transport: Arc<dyn Transport>,
+) -> std::io::Result<Node> {
+    // start thread to update ledger
+    let ledger_manager = LedgerManager::new(blockdb, blockchain, utxodb, wallet, mempool);
+    ledger_manager.start(config.execution_buffer, config.execution_workers);
+    debug!(
+        "Initialized ledger manager with buffer size {} and {} workers",
+        config.execution_buffer, config.execution_workers
+    );
+
+    // create channels between server and worker, worker and miner, miner and worker
+    let (msg_tx, msg_rx) = lane::new(&config.lane_sizes);
+    let (ctx_tx, ctx_rx) = channel::unbounded();
+    let ctx_tx_miner = ctx_tx.clone();
+
+    // start the p2p server
+    let (server_ctx, server) = server::new(
+        config.p2p_addr,
+        msg_tx,
+        config.address_book,
+        config.outgoing_peers,
+        blockchain,
+        blockchain_config.clone(),
+        config.network_id,
+        config.frame_limits,
+        config.write_queue_config,
+        transport,
+    )?;
+    server_ctx.start()?;
+
+    // start the initial block download
+    let (sync_ctx, sync) = sync::new(blockdb, config.bootstrap, config.headers_first);
+    sync_ctx.start();
+
+    // start relaying transactions to the peers
+    let (relay_ctx, relay) = relay::new(&server);
+    relay_ctx.start();
+
+    // start the worker
+    let worker_ctx = worker::new(
+        config.p2p_workers,
+        msg_rx,
+        blockchain,
+        blockdb,
+        utxodb,
+        wallet,
+        mempool,
+        ctx_tx,
+        &server,
+        &sync,
+        &relay,
+        config.compact_blocks,
+        config.buffer_limits,
+        blockchain_config.clone(),
+    );
+    worker_ctx.start();
+
+    // start the miner
+    let (miner_ctx, miner) = miner::new(
+        mempool,
+        blockchain,
+        blockdb,
+        ctx_rx,
+        &ctx_tx_miner,
+        &server,
+        config.miner_addr,
+        blockchain_config,
+    );
+    // do not mine until we have caught up with the peers, since the blocks we mine would
+    // otherwise be built on a stale view of the blockchain
+    let sync_wait = sync.clone();
+    thread::spawn(move || {
+        sync_wait.wait_synced();
+        miner_ctx.start();
+    });
+
+    Ok(Node {
+        blockdb: Arc::clone(blockdb),
+        blockchain: Arc::clone(blockchain),
+        utxodb: Arc::clone(utxodb),
+        wallet: Arc::clone(wallet),
+        mempool: Arc::clone(mempool),
+        server,
+        sync,
+        relay,
+        miner,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{start, Node, NodeConfig};
+    use crate::blockchain::confirmation::MajorityDeep;
+    use crate::blockchain::BlockChain;
+    use crate::blockdb::BlockDatabase;
+    use crate::config::BlockchainConfig;
+    use crate::crypto::hash::H256;
+    use crate::miner::memory_pool::MemoryPool;
+    use crate::network::address_book::AddressBook;
+    use crate::network::memory::{LinkConfig, MemoryNetwork};
+    use crate::network::peer::{SlowPeerPolicy, WriteQueueConfig};
+    use crate::transaction::CoinId;
+    use crate::utxodb::UtxoDatabase;
+    use crate::wallet::Wallet;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time;
+
+    fn node(
+        network: &MemoryNetwork,
+        name: &str,
+        addr: SocketAddr,
+        config: &BlockchainConfig,
+    ) -> Node {
+        let path = |db: &str| format!("/tmp/prism_test_node_{}_{}.rocksdb", name, db);
+        let blockdb = Arc::new(BlockDatabase::new(path("blockdb"), config.clone()).unwrap());
+        let blockchain = Arc::new(BlockChain::new(path("blockchain"), config.clone()).unwrap());
+        let utxodb = Arc::new(UtxoDatabase::new(path("utxodb")).unwrap());
+        let wallet = Arc::new(Wallet::new(path("wallet")).unwrap());
+        let miner_addr = wallet.generate_keypair().unwrap();
+        let mempool = MemoryPool::new(1000000, time::Duration::from_secs(3600));
+        let mempool = Arc::new(Mutex::new(mempool));
+        let address_book_path = format!("/tmp/prism_test_node_{}_addrbook.txt", name);
+        let _ = std::fs::remove_file(&address_book_path);
+        let node_config = NodeConfig {
+            p2p_addr: addr,
+            address_book: AddressBook::load(&address_book_path).unwrap(),
+            outgoing_peers: 0,
+            network_id: 0,
+            frame_limits: Default::default(),
+            write_queue_config: WriteQueueConfig {
+                max_queued_bytes: 134217728,
+                slow_peer_policy: SlowPeerPolicy::Drop,
+            },
+            lane_sizes: Default::default(),
+            buffer_limits: Default::default(),
+            p2p_workers: 2,
+            execution_workers: 2,
+            execution_buffer: 100,
+            compact_blocks: true,
+            bootstrap: false,
+            headers_first: true,
+            miner_addr,
+        };
+        start(
+            &blockdb,
+            &blockchain,
+            &utxodb,
+            &wallet,
+            &mempool,
+            node_config,
+            config.clone(),
+            Arc::new(network.transport(addr.ip())),
+        )
+        .unwrap()
+    }
+
+    /// Advance the clock of the network, giving the nodes some time to react, until the given
+    /// condition holds.
+    fn advance_until<F: FnMut() -> bool>(network: &MemoryNetwork, mut condition: F) {
+        for _ in 0..2000 {
+            if condition() {
+                return;
+            }
+            network.advance(time::Duration::from_millis(10));
+            thread::sleep(time::Duration::from_millis(5));
+        }
+        panic!("condition does not hold after advancing the network for 20 seconds");
+    }
+
+    /// Mine one block on the given node, and wait until it is in the database.
+    fn mine_one(network: &MemoryNetwork, node: &Node) {
+        let num_blocks = node.blockdb.num_blocks();
+        node.miner.step();
+        advance_until(network, || node.blockdb.num_blocks() > num_blocks);
+    }
+
+    fn leader(node: &Node, level: usize) -> Option<H256> {
+        node.blockchain
+            .proposer_leaders()
+            .unwrap()
+            .get(level)
+            .cloned()
+    }
+
+    fn voter_level(node: &Node) -> u64 {
+        node.blockchain.voter_bottom_tip().unwrap()[0].2
+    }
+
+    fn reward_coin(leader: H256) -> CoinId {
+        CoinId {
+            hash: leader,
+            index: 0,
+        }
+    }
+
+    #[test]
+    fn fork_and_deconfirmation() {
+        // one voter chain, and a leader as soon as it has a vote, so that every fork of the voter
+        // chain decides the leaders
+        let mut config = BlockchainConfig::new(1, 64000, 38, 0.1, 0.1, 0.4, 20.0);
+        config.confirmation_policy = Arc::new(MajorityDeep::new(1));
+        let link = LinkConfig {
+            latency: time::Duration::from_millis(50),
+            bandwidth: Some(10000000),
+            drop_rate: 0.0,
+        };
+        let network = MemoryNetwork::with_virtual_clock(link.clone(), 0);
+        let a_addr: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let b_addr: SocketAddr = "10.0.0.2:6000".parse().unwrap();
+        let a = node(&network, "a", a_addr, &config);
+        let b = node(&network, "b", b_addr, &config);
+
+        // the handshake needs the clock to move
+        let a_server = a.server.clone();
+        let connecting = thread::spawn(move || a_server.connect(b_addr).map(|_| ()));
+        advance_until(&network, || !a.server.peers().is_empty());
+        connecting.join().unwrap().unwrap();
+
+        // partition the nodes, and let each of them confirm its own leader on level 1
+        let partitioned = LinkConfig {
+            drop_rate: 1.0,
+            ..Default::default()
+        };
+        network.set_link(a_addr.ip(), b_addr.ip(), partitioned);
+        while leader(&a, 1).is_none() {
+            mine_one(&network, &a);
+        }
+        let a_leader = leader(&a, 1).unwrap();
+        advance_until(&network, || {
+            a.utxodb.contains(&reward_coin(a_leader)).unwrap()
+        });
+        while leader(&b, 1).is_none() || voter_level(&b) <= voter_level(&a) + 1 {
+            mine_one(&network, &b);
+        }
+        let b_leader = leader(&b, 1).unwrap();
+        assert_ne!(a_leader, b_leader);
+
+        // heal the partition, and mine on B until it announces a voter block. A then fetches the
+        // longer voter chain of B, which votes for another leader on level 1
+        network.set_link(a_addr.ip(), b_addr.ip(), link);
+        let level = voter_level(&b);
+        while voter_level(&b) == level {
+            mine_one(&network, &b);
+        }
+        advance_until(&network, || {
+            leader(&a, 1) == Some(b_leader)
+                && a.utxodb.contains(&reward_coin(b_leader)).unwrap()
+                && !a.utxodb.contains(&reward_coin(a_leader)).unwrap()
+        });
+    }
+}
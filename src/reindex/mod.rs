@@ -0,0 +1,337 @@
+use crate::block::Block;
+use crate::blockchain::BlockChain;
+use crate::blockdb::BlockDatabase;
+use crate::config::BlockchainConfig;
+use crate::crypto::hash::Hashable;
+use crate::ledger_manager;
+use crate::network::buffer::{BlockBuffer, BufferLimits};
+use crate::utxodb::{LedgerWatermark, UtxoDatabase};
+use crate::validation::{self, BlockResult};
+use crate::wallet::Wallet;
+use log::{debug, info, warn};
+use std::time::{self, SystemTime};
+
+/// Number of blocks to read from the block database at a time.
+const BLOCK_BATCH_SIZE: u64 = 1000;
+
+/// Insert all blocks in the block database into a newly created blockchain, in the order that
+/// they arrived, and update the ledger. Blocks whose parent or references are not inserted yet are
+/// buffered until their dependencies are, in the same way as the network worker does. Returns
+/// the number of blocks inserted.
+pub fn rebuild_blockchain(
+    blockdb: &BlockDatabase,
+    chain: &BlockChain,
+    config: &BlockchainConfig,
+) -> Result<u64, rocksdb::Error> {
+    let mut buffer = BlockBuffer::new(BufferLimits::unbounded());
+    let mut num_inserted: u64 = 0;
+    let mut num_invalid: u64 = 0;
+
+    // the genesis blocks come first in the block database, and they are already in the blockchain
+    let last_genesis = config.voter_genesis[config.voter_chains as usize - 1];
+    for batch in blockdb.blocks_after(&last_genesis, BLOCK_BATCH_SIZE) {
+        let mut to_process: Vec<Block> = batch;
+        // process the batch in arrival order
+        to_process.reverse();
+        while let Some(block) = to_process.pop() {
+            // check data availability
+            match validation::check_data_availability(&block, chain, blockdb) {
+                BlockResult::Pass => {}
+                BlockResult::MissingReferences(r) => {
+                    debug!(
+                        "Missing {} referred blocks for block {:.8}",
+                        r.len(),
+                        block.hash()
+                    );
+                    buffer.insert(block, &r, None, time::Instant::now());
+                    continue;
+                }
+                _ => unreachable!(),
+            }
+
+            // check sortition proof and content semantics. older versions stored blocks before
+            // these checks, so the block database may contain invalid blocks
+            let sortition_proof = validation::check_sortition_proof(&block, config);
+            match sortition_proof {
+                BlockResult::Pass => {}
+                _ => {
+                    warn!(
+                        "Ignoring invalid block {:.8}: {}",
+                        block.hash(),
+                        sortition_proof
+                    );
+                    num_invalid += 1;
+                    buffer.drop_dependency(&block.hash());
+                    continue;
+                }
+            }
+            let difficulty = validation::check_difficulty(&block, chain, blockdb, config);
+            match difficulty {
+                BlockResult::Pass => {}
+                _ => {
+                    warn!("Ignoring invalid block {:.8}: {}", block.hash(), difficulty);
+                    num_invalid += 1;
+                    buffer.drop_dependency(&block.hash());
+                    continue;
+                }
+            }
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            let timestamp = validation::check_timestamp(&block, blockdb, config, now);
+            match timestamp {
+                BlockResult::Pass => {}
+                _ => {
+                    warn!("Ignoring invalid block {:.8}: {}", block.hash(), timestamp);
+                    num_invalid += 1;
+                    buffer.drop_dependency(&block.hash());
+                    continue;
+                }
+            }
+            let content_semantic = validation::check_content_semantic(&block, chain, blockdb);
+            match content_semantic {
+                BlockResult::Pass => {}
+                _ => {
+                    warn!(
+                        "Ignoring invalid block {:.8}: {}",
+                        block.hash(),
+                        content_semantic
+                    );
+                    num_invalid += 1;
+                    buffer.drop_dependency(&block.hash());
+                    continue;
+                }
+            }
+
+            chain.insert_block(&block)?;
+            num_inserted += 1;
+            // blocks resolved by the current one go before the rest of the batch
+            to_process.extend(buffer.satisfy(block.hash()));
+        }
+        debug!("Reindexed {} blocks", num_inserted);
+    }
+
+    let num_buffered = buffer.len();
+    if num_buffered != 0 {
+        warn!(
+            "{} blocks are not inserted since their dependencies are missing",
+            num_buffered
+        );
+    }
+    if num_invalid != 0 {
+        warn!("{} invalid blocks are ignored", num_invalid);
+    }
+
+    chain.update_ledger()?;
+    Ok(num_inserted)
+}
+
+/// Apply the ledger changes recorded in the blockchain to a newly created UTXO database and a
+/// wallet without coins (see `Wallet::clear_coins`), and checkpoint the UTXO database so that the node can be resumed from it. Returns the
+/// number of transaction blocks applied.
+pub fn rebuild_utxo(
+    blockdb: &BlockDatabase,
+    chain: &BlockChain,
+    utxodb: &UtxoDatabase,
+    wallet: &Wallet,
+) -> Result<u64, rocksdb::Error> {
+    let mut watermark = LedgerWatermark::default();
+    let mut num_applied: u64 = 0;
+    while let Some(diff) = chain.ledger_diff(watermark.ledger_diff_seq + 1)? {
+        // roll back removed transactions and rewards in reverse order, then apply the added ones
+        for job in ledger_manager::jobs(blockdb, chain, &diff) {
+            let (added, removed) = job.execute(utxodb)?;
+            wallet.apply_diff(&added, &removed).unwrap();
+        }
+        num_applied += diff.added.len() as u64;
+        watermark = LedgerWatermark {
+            ledger_diff_seq: watermark.ledger_diff_seq + 1,
+            proposer_ledger_tip: diff.proposer_ledger_tip,
+            last_transaction_block: diff.last_transaction_block,
+        };
+    }
+    utxodb.checkpoint(&watermark)?;
+    chain.prune_ledger_diffs(watermark.ledger_diff_seq)?;
+    info!(
+        "Rebuilt UTXO database up to proposer level {}",
+        watermark.proposer_ledger_tip
+    );
+    Ok(num_applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rebuild_blockchain, rebuild_utxo};
+    use crate::block::header::Header;
+    use crate::block::{proposer, transaction, voter, Block, Content};
+    use crate::blockchain::confirmation::MajorityDeep;
+    use crate::blockchain::BlockChain;
+    use crate::blockdb::BlockDatabase;
+    use crate::config::{BlockchainConfig, DEFAULT_DIFFICULTY, PROPOSER_BLOCK_REWARD};
+    use crate::crypto::hash::{Hashable, H256};
+    use crate::crypto::merkle::MerkleTree;
+    use crate::transaction::{Address, CoinId, Output};
+    use crate::utxodb::UtxoDatabase;
+    use crate::wallet::Wallet;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    /// Mine a block with the given content, trying nonces until the sortition gives the block the
+    /// type of the content.
+    fn mine(
+        config: &BlockchainConfig,
+        parent: H256,
+        timestamp: u128,
+        miner: Address,
+        content: Content,
+    ) -> Block {
+        let mut contents = vec![
+            Content::Proposer(proposer::Content {
+                transaction_refs: vec![],
+                proposer_refs: vec![],
+            }),
+            Content::Transaction(transaction::Content {
+                transactions: vec![],
+            }),
+        ];
+        for chain in 0..config.voter_chains {
+            contents.push(Content::Voter(voter::Content {
+                chain_number: chain,
+                voter_parent: config.voter_genesis[chain as usize],
+                votes: vec![],
+            }));
+        }
+        let index = match &content {
+            Content::Proposer(_) => 0,
+            Content::Transaction(_) => 1,
+            Content::Voter(c) => 2 + c.chain_number as usize,
+        };
+        contents[index] = content;
+        let tree = MerkleTree::new(&contents);
+        for nonce in 0.. {
+            let header = Header::new(
+                parent,
+                timestamp,
+                nonce,
+                tree.root(),
+                [0; 32],
+                *DEFAULT_DIFFICULTY,
+                miner,
+            );
+            if config.sortition_hash(&header.hash(), &header.difficulty) == Some(index as u16) {
+                return Block::from_header(header, contents[index].clone(), tree.proof(index));
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn reindex_matches_fresh_run() {
+        let mut config = BlockchainConfig::new(1, 64000, 38, 0.1, 0.1, 0.4, 20.0);
+        config.confirmation_policy = Arc::new(MajorityDeep::new(1));
+        let blockdb =
+            BlockDatabase::new("/tmp/prism_test_reindex_blockdb.rocksdb", config.clone()).unwrap();
+        let wallet = Wallet::new("/tmp/prism_test_reindex_wallet.rocksdb").unwrap();
+        let addr = wallet.generate_keypair().unwrap();
+
+        // a proposer block referring to a transaction block, and a vote that makes it the leader
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let genesis = config.proposer_genesis;
+        let tx_block = mine(
+            &config,
+            genesis,
+            now - 3000,
+            addr,
+            Content::Transaction(transaction::Content {
+                transactions: vec![],
+            }),
+        );
+        let proposer_block = mine(
+            &config,
+            genesis,
+            now - 2000,
+            addr,
+            Content::Proposer(proposer::Content {
+                transaction_refs: vec![tx_block.hash()],
+                proposer_refs: vec![],
+            }),
+        );
+        let voter_block = mine(
+            &config,
+            proposer_block.hash(),
+            now - 1000,
+            addr,
+            Content::Voter(voter::Content {
+                chain_number: 0,
+                voter_parent: config.voter_genesis[0],
+                votes: vec![proposer_block.hash()],
+            }),
+        );
+        for block in &[&tx_block, &proposer_block, &voter_block] {
+            blockdb.insert(block).unwrap();
+        }
+
+        // a fresh run, which pays both blocks to our wallet
+        let chain = BlockChain::new(
+            "/tmp/prism_test_reindex_chain_fresh.rocksdb",
+            config.clone(),
+        )
+        .unwrap();
+        assert_eq!(rebuild_blockchain(&blockdb, &chain, &config).unwrap(), 3);
+        let utxodb = UtxoDatabase::new("/tmp/prism_test_reindex_utxodb_fresh.rocksdb").unwrap();
+        assert_eq!(rebuild_utxo(&blockdb, &chain, &utxodb, &wallet).unwrap(), 1);
+        let fresh_utxo = utxodb.snapshot().unwrap();
+        let fresh_coins = wallet.number_of_coins();
+        let fresh_balance = wallet.balance().unwrap();
+        assert_eq!(fresh_coins, 2);
+
+        // adding a coin that is already in the wallet does not count it twice
+        let leader_reward = (
+            CoinId {
+                hash: proposer_block.hash(),
+                index: 0,
+            },
+            Output {
+                recipient: addr,
+                value: PROPOSER_BLOCK_REWARD,
+            },
+        );
+        wallet.apply_diff(&[leader_reward], &[]).unwrap();
+        assert_eq!(wallet.number_of_coins(), fresh_coins);
+
+        // a coin of a transaction that has left the ledger since
+        let stale = (
+            CoinId {
+                hash: [7u8; 32].into(),
+                index: 0,
+            },
+            Output {
+                recipient: addr,
+                value: 100,
+            },
+        );
+        wallet.apply_diff(&[stale], &[]).unwrap();
+        drop(wallet);
+
+        // reindex into new databases, keeping the key pairs of the wallet
+        let wallet = Wallet::load("/tmp/prism_test_reindex_wallet.rocksdb").unwrap();
+        assert_eq!(wallet.number_of_coins(), fresh_coins + 1);
+        wallet.clear_coins().unwrap();
+        assert_eq!(wallet.addresses().unwrap(), vec![addr]);
+        let chain = BlockChain::new(
+            "/tmp/prism_test_reindex_chain_reindexed.rocksdb",
+            config.clone(),
+        )
+        .unwrap();
+        assert_eq!(rebuild_blockchain(&blockdb, &chain, &config).unwrap(), 3);
+        let utxodb = UtxoDatabase::new("/tmp/prism_test_reindex_utxodb_reindexed.rocksdb").unwrap();
+        assert_eq!(rebuild_utxo(&blockdb, &chain, &utxodb, &wallet).unwrap(), 1);
+        assert_eq!(utxodb.snapshot().unwrap(), fresh_utxo);
+        assert_eq!(wallet.number_of_coins(), fresh_coins);
+        assert_eq!(wallet.balance().unwrap(), fresh_balance);
+    }
+}
@@ -33,7 +33,6 @@ pub struct Input {
 }
 
 /// An output of a transaction.
-// TODO: coinbase output (transaction fee). Maybe we don't need that in this case.
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Output {
     /// The amount of this output.
@@ -56,6 +55,22 @@ pub struct Transaction {
     pub hash: RefCell<Option<H256>>,
 }
 
+impl Transaction {
+    /// Get the fee of this transaction, which is the input value not spent on outputs, or None if
+    /// the outputs are worth more than the inputs.
+    pub fn fee(&self) -> Option<u64> {
+        let mut input_value: u64 = 0;
+        for input in &self.input {
+            input_value = input_value.checked_add(input.value)?;
+        }
+        let mut output_value: u64 = 0;
+        for output in &self.output {
+            output_value = output_value.checked_add(output.value)?;
+        }
+        input_value.checked_sub(output_value)
+    }
+}
+
 impl PayloadSize for Transaction {
     /// Return the size in bytes
     fn size(&self) -> usize {
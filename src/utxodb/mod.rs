@@ -5,6 +5,83 @@ use bincode::{deserialize, serialize};
 use rocksdb::*;
 use std::collections::HashSet;
 
+// Key of the ledger watermark. Coin ids are serialized into 36 bytes, so this key never collides
+// with a coin.
+const LEDGER_WATERMARK_KEY: [u8; 32] = [0xff; 32];
+
+// Key of the marker of the initial fund. It is 34 bytes long, so it never collides with a coin,
+// a record or the watermark.
+const FUNDED_KEY: [u8; 34] = [0xff; 34];
+
+// Key prefixes of the records about transactions and blocks, which are followed by the hash. The
+// records are kept in the same column family as the coins and the watermark, so that a flush
+// never persists one without the others. The keys are 33 bytes long, so they never collide with a
+// coin or the watermark.
+const EXECUTION_PREFIX: u8 = 0; // transaction hash to the position of the applied copy
+const STATUS_PREFIX: u8 = 1; // transaction hash to the status of all confirmed copies
+const REWARD_PREFIX: u8 = 2; // block hash to the position of the reference that pays the reward
+const RECORD_KEY_LENGTH: usize = 33;
+
+/// The position in the ledger that the UTXO set reflects.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct LedgerWatermark {
+    /// Sequence number of the last ledger diff that has been applied.
+    pub ledger_diff_seq: u64,
+    /// The level of the proposer ledger tip after applying that diff.
+    pub proposer_ledger_tip: u64,
+    /// The last transaction block that has been applied.
+    pub last_transaction_block: H256,
+}
+
+/// The position of a copy of a transaction in the ledger. A transaction block may be referred to
+/// more than once in the ledger, so the block alone does not identify the copy.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedgerPosition {
+    /// The level of the proposer leader that confirms the transaction block.
+    pub level: u64,
+    /// The index of the reference to the transaction block among the ones confirmed on the level.
+    pub reference: u32,
+    /// The transaction block that contains the copy.
+    pub block: H256,
+    /// The index of the copy in the transaction block.
+    pub index: u32,
+}
+
+/// The reference in the ledger that pays the reward of a block. A transaction block may be referred
+/// to more than once in the ledger, and only the first reference pays the reward.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RewardPosition {
+    /// The level of the proposer leader that confirms the block.
+    pub level: u64,
+    /// The index of the reference to a transaction block among the ones confirmed on the level,
+    /// or 0 for a proposer leader.
+    pub reference: u32,
+}
+
+/// The outcome of executing a copy of a transaction confirmed in the ledger.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Execution {
+    /// The transaction is applied to the UTXO set.
+    Applied,
+    /// Another copy of the transaction is applied, so this one is skipped.
+    Duplicate,
+    /// Some inputs are not unspent, e.g. they are spent by an earlier transaction in the ledger.
+    DoubleSpend,
+    /// The signers are not the owners of the inputs.
+    BadAuthorization,
+    /// The input values do not match the coins, or the outputs are worth more than the inputs.
+    InvalidValue,
+}
+
+/// The status of a copy of a transaction confirmed in the ledger.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionStatus {
+    /// The position of the copy in the ledger.
+    pub position: LedgerPosition,
+    /// The outcome of executing the copy.
+    pub execution: Execution,
+}
+
 pub struct UtxoDatabase {
     pub db: rocksdb::DB, // coin id to output
 }
@@ -12,7 +89,6 @@ pub struct UtxoDatabase {
 impl UtxoDatabase {
     /// Open the database at the given path, and create a new one if one is missing.
     fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, rocksdb::Error> {
-        let cfs = vec![];
         let mut opts = Options::default();
         opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(32));
         opts.set_allow_concurrent_memtable_write(false);
@@ -25,12 +101,11 @@ impl UtxoDatabase {
         // https://github.com/facebook/rocksdb/blob/671d15cbdd3839acb54cb21a2aa82efca4917155/options/options.cc#L509
         opts.optimize_for_point_lookup(512);
         opts.create_if_missing(true);
-        opts.create_missing_column_families(true);
         opts.increase_parallelism(16);
         opts.set_max_background_flushes(2);
         opts.set_max_write_buffer_number(32);
 
-        let db = DB::open_cf_descriptors(&opts, path, cfs)?;
+        let db = DB::open(&opts, path)?;
         Ok(Self { db })
     }
 
@@ -42,6 +117,12 @@ impl UtxoDatabase {
         Ok(db)
     }
 
+    /// Open the existing database at the given path.
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self, rocksdb::Error> {
+        let db = Self::open(&path)?;
+        Ok(db)
+    }
+
     /// Check whether the given coin is in the UTXO set.
     pub fn contains(&self, coin: &CoinId) -> Result<bool, rocksdb::Error> {
         let result = self.db.get_pinned(serialize(&coin).unwrap())?;
@@ -51,6 +132,14 @@ impl UtxoDatabase {
         }
     }
 
+    /// Get the given coin if it is in the UTXO set.
+    pub fn get(&self, coin: &CoinId) -> Result<Option<Output>, rocksdb::Error> {
+        match self.db.get_pinned(serialize(&coin).unwrap())? {
+            Some(d) => Ok(Some(deserialize(&d).unwrap())),
+            None => Ok(None),
+        }
+    }
+
     pub fn snapshot(&self) -> Result<Vec<u8>, rocksdb::Error> {
         let mut iter_opt = rocksdb::ReadOptions::default();
         iter_opt.set_prefix_same_as_start(false);
@@ -61,6 +150,12 @@ impl UtxoDatabase {
         let mut inited = false;
         let mut checksum: Vec<u8> = vec![];
         for (k, _) in iter {
+            if k.as_ref() == LEDGER_WATERMARK_KEY
+                || k.as_ref() == FUNDED_KEY
+                || k.as_ref().len() == RECORD_KEY_LENGTH
+            {
+                continue;
+            }
             if !inited {
                 checksum = vec![0; k.as_ref().len()];
                 inited = true;
@@ -74,13 +169,99 @@ impl UtxoDatabase {
         Ok(checksum)
     }
 
+    /// Get the position in the ledger of the copy of the given transaction that is applied, if any.
+    pub fn execution(&self, hash: &H256) -> Result<Option<LedgerPosition>, rocksdb::Error> {
+        match self.db.get_pinned(record_key(EXECUTION_PREFIX, hash))? {
+            Some(d) => Ok(Some(deserialize(&d).unwrap())),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the status of all copies of the given transaction that are confirmed in the ledger, in
+    /// the order that they are executed.
+    pub fn status(&self, hash: &H256) -> Result<Vec<TransactionStatus>, rocksdb::Error> {
+        match self.db.get_pinned(record_key(STATUS_PREFIX, hash))? {
+            Some(d) => Ok(deserialize(&d).unwrap()),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Record the status of a copy of the given transaction in the batch, replacing the one at the
+    /// same position.
+    fn put_status(
+        &self,
+        batch: &mut WriteBatch,
+        hash: H256,
+        status: TransactionStatus,
+    ) -> Result<(), rocksdb::Error> {
+        let mut statuses = self.status(&hash)?;
+        statuses.retain(|s| s.position != status.position);
+        statuses.push(status);
+        batch.put(
+            record_key(STATUS_PREFIX, &hash),
+            serialize(&statuses).unwrap(),
+        )
+    }
+
+    /// Remove the status of the copy of the given transaction at the given position in the batch.
+    fn delete_status(
+        &self,
+        batch: &mut WriteBatch,
+        hash: H256,
+        position: LedgerPosition,
+    ) -> Result<(), rocksdb::Error> {
+        let mut statuses = self.status(&hash)?;
+        statuses.retain(|s| s.position != position);
+        if statuses.is_empty() {
+            batch.delete(record_key(STATUS_PREFIX, &hash))
+        } else {
+            batch.put(
+                record_key(STATUS_PREFIX, &hash),
+                serialize(&statuses).unwrap(),
+            )
+        }
+    }
+
+    /// Record that the copy of the given transaction is not applied to the UTXO set.
+    fn skip_transaction(
+        &self,
+        hash: H256,
+        status: TransactionStatus,
+    ) -> Result<(Execution, Vec<(CoinId, Output)>, Vec<CoinId>), rocksdb::Error> {
+        let mut batch = rocksdb::WriteBatch::default();
+        self.put_status(&mut batch, hash, status)?;
+        self.db.write_without_wal(batch)?;
+        Ok((status.execution, vec![], vec![]))
+    }
+
+    /// Apply the copy of the given transaction at the given position of the ledger to the UTXO
+    /// set, and pay its fee, which is the input value not spent on outputs, to the given miner.
+    /// The fee coin follows the outputs of the transaction. The transaction is skipped if another
+    /// copy of it is applied, or if it is invalid. The outcome is recorded in the status of the
+    /// transaction. Returns the outcome, and the added and removed coins.
     pub fn add_transaction(
         &self,
         t: &Transaction,
         hash: H256,
-    ) -> Result<(Vec<(CoinId, Output)>, Vec<CoinId>), rocksdb::Error> {
+        position: LedgerPosition,
+        miner: Address,
+    ) -> Result<(Execution, Vec<(CoinId, Output)>, Vec<CoinId>), rocksdb::Error> {
         let mut added_coins: Vec<(CoinId, Output)> = vec![];
         let mut removed_coins: Vec<CoinId> = vec![];
+        let status = |execution| TransactionStatus {
+            position,
+            execution,
+        };
+
+        // the same transaction may be confirmed in several transaction blocks, and only the first
+        // copy is applied. when replaying the ledger after a crash, the copy may be the same one
+        match self.execution(&hash)? {
+            Some(p) if p == position => {
+                return self.skip_transaction(hash, status(Execution::Applied))
+            }
+            Some(_) => return self.skip_transaction(hash, status(Execution::Duplicate)),
+            None => {}
+        }
 
         // use batch for the transaction
         let mut batch = rocksdb::WriteBatch::default();
@@ -95,10 +276,10 @@ impl UtxoDatabase {
                     let coin_data: Output = deserialize(&d).unwrap();
                     owners.insert(coin_data.recipient);
                     if coin_data.value != input.value {
-                        return Ok((vec![], vec![]));
+                        return self.skip_transaction(hash, status(Execution::InvalidValue));
                     }
                 }
-                None => return Ok((vec![], vec![])),
+                None => return self.skip_transaction(hash, status(Execution::DoubleSpend)),
             }
             removed_coins.push(input.coin);
             batch.delete(&id_ser)?;
@@ -109,9 +290,15 @@ impl UtxoDatabase {
             .map(|x| ring::digest::digest(&ring::digest::SHA256, &x.pubkey).into())
             .collect();
         if signed_users != owners {
-            return Ok((vec![], vec![]));
+            return self.skip_transaction(hash, status(Execution::BadAuthorization));
         }
 
+        // the transaction must not spend more than its inputs
+        let fee = match t.fee() {
+            Some(f) => f,
+            None => return self.skip_transaction(hash, status(Execution::InvalidValue)),
+        };
+
         // now that we have confirmed that all inputs are unspent, we will add the outputs and
         // commit to database
         for (idx, output) in t.output.iter().enumerate() {
@@ -122,46 +309,78 @@ impl UtxoDatabase {
             batch.put(serialize(&id).unwrap(), serialize(&output).unwrap())?;
             added_coins.push((id, *output));
         }
+        if fee != 0 {
+            let id = CoinId {
+                hash,
+                index: t.output.len() as u32,
+            };
+            let output = Output {
+                value: fee,
+                recipient: miner,
+            };
+            batch.put(serialize(&id).unwrap(), serialize(&output).unwrap())?;
+            added_coins.push((id, output));
+        }
+        batch.put(
+            record_key(EXECUTION_PREFIX, &hash),
+            serialize(&position).unwrap(),
+        )?;
+        self.put_status(&mut batch, hash, status(Execution::Applied))?;
         // write the transaction as a batch
-        // TODO: we don't write to wal here, so should the program crash, the db will be in
-        // an inconsistent state. The solution here is to manually flush the memtable to
-        // the disk at certain time, and manually log the state (e.g. voter tips, etc.)
+        // we don't write to wal here. should the program crash, the ledger manager replays the
+        // ledger diffs after the last checkpointed watermark, and it is fine to add or remove a
+        // transaction twice since we record the copy that is applied.
         self.db.write_without_wal(batch)?;
 
         if !t.input.is_empty() {
             PERFORMANCE_COUNTER.record_confirm_transaction(&t);
         }
 
-        Ok((added_coins, removed_coins))
+        Ok((Execution::Applied, added_coins, removed_coins))
     }
 
+    /// Roll back the copy of the given transaction at the given position of the ledger, including
+    /// the coin that pays its fee, and remove its status. Only the status is removed if the copy
+    /// was not applied.
     pub fn remove_transaction(
         &self,
         t: &Transaction,
         hash: H256,
+        position: LedgerPosition,
     ) -> Result<(Vec<(CoinId, Output)>, Vec<CoinId>), rocksdb::Error> {
         let mut added_coins: Vec<(CoinId, Output)> = vec![];
         let mut removed_coins: Vec<CoinId> = vec![];
 
         // use batch when committing
         let mut batch = rocksdb::WriteBatch::default();
+        self.delete_status(&mut batch, hash, position)?;
+
+        if self.execution(&hash)? != Some(position) {
+            self.db.write_without_wal(batch)?;
+            return Ok((vec![], vec![]));
+        }
 
-        // check whether the outputs of this transaction are there. if so, this transaction was
-        // valid when it was originally added
+        // remove the outputs of this transaction. they are all there, since the transactions that
+        // spend them come later in the ledger and are rolled back first
         for (idx, _out) in t.output.iter().enumerate() {
             let id = CoinId {
                 hash,
                 index: idx as u32,
             };
-            let id_ser = serialize(&id).unwrap();
-            if self.db.get_pinned(&id_ser)?.is_none() {
-                return Ok((vec![], vec![]));
-            }
-            batch.delete(&id_ser)?;
+            batch.delete(&serialize(&id).unwrap())?;
             removed_coins.push(id);
         }
+        if let Some(fee) = t.fee() {
+            if fee != 0 {
+                let id = CoinId {
+                    hash,
+                    index: t.output.len() as u32,
+                };
+                batch.delete(&serialize(&id).unwrap())?;
+                removed_coins.push(id);
+            }
+        }
 
-        // now that we have checked that this transaction was valid when originally added, we will
         // add back the input and commit to database
         for input in &t.input {
             let out = Output {
@@ -171,10 +390,11 @@ impl UtxoDatabase {
             batch.put(serialize(&input.coin).unwrap(), serialize(&out).unwrap())?;
             added_coins.push((input.coin, out));
         }
+        batch.delete(record_key(EXECUTION_PREFIX, &hash))?;
         // write the transaction as a batch
-        // TODO: we don't write to wal here, so should the program crash, the db will be in
-        // an inconsistent state. The solution here is to manually flush the memtable to
-        // the disk at certain time, and manually log the state (e.g. voter tips, etc.)
+        // we don't write to wal here. should the program crash, the ledger manager replays the
+        // ledger diffs after the last checkpointed watermark, and it is fine to add or remove a
+        // transaction twice since we record the copy that is applied.
         self.db.write_without_wal(batch)?;
 
         // TODO: it's a hack. The purpose is to ignore ICO transaction
@@ -185,6 +405,96 @@ impl UtxoDatabase {
         Ok((added_coins, removed_coins))
     }
 
+    /// Get the position of the reference that pays the reward of the given block, if any.
+    pub fn reward(&self, block: &H256) -> Result<Option<RewardPosition>, rocksdb::Error> {
+        match self.db.get_pinned(record_key(REWARD_PREFIX, block))? {
+            Some(d) => Ok(Some(deserialize(&d).unwrap())),
+            None => Ok(None),
+        }
+    }
+
+    /// Pay the reward of the block with the given hash for the reference at the given position.
+    /// The reward is the only coin produced by the block, so it is identified by the block hash.
+    /// Nothing is paid if the reward is paid for another reference, or for the same one before a
+    /// crash, in which case the coin may have been spent since.
+    pub fn add_reward(
+        &self,
+        block: H256,
+        output: Output,
+        position: RewardPosition,
+    ) -> Result<(Vec<(CoinId, Output)>, Vec<CoinId>), rocksdb::Error> {
+        if self.reward(&block)?.is_some() {
+            return Ok((vec![], vec![]));
+        }
+        let id = CoinId {
+            hash: block,
+            index: 0,
+        };
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put(serialize(&id).unwrap(), serialize(&output).unwrap())?;
+        batch.put(
+            record_key(REWARD_PREFIX, &block),
+            serialize(&position).unwrap(),
+        )?;
+        self.db.write_without_wal(batch)?;
+        Ok((vec![(id, output)], vec![]))
+    }
+
+    /// Take back the reward of the block with the given hash, if it is paid for the reference at
+    /// the given position.
+    pub fn remove_reward(
+        &self,
+        block: H256,
+        position: RewardPosition,
+    ) -> Result<(Vec<(CoinId, Output)>, Vec<CoinId>), rocksdb::Error> {
+        if self.reward(&block)? != Some(position) {
+            return Ok((vec![], vec![]));
+        }
+        let id = CoinId {
+            hash: block,
+            index: 0,
+        };
+        // the coin is there, since the transactions that spend it come later in the ledger and
+        // are rolled back first
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.delete(&serialize(&id).unwrap())?;
+        batch.delete(record_key(REWARD_PREFIX, &block))?;
+        self.db.write_without_wal(batch)?;
+        Ok((vec![], vec![id]))
+    }
+
+    /// Get the ledger watermark of the last checkpoint.
+    pub fn watermark(&self) -> Result<Option<LedgerWatermark>, rocksdb::Error> {
+        match self.db.get_pinned(&LEDGER_WATERMARK_KEY)? {
+            Some(d) => Ok(Some(deserialize(&d).unwrap())),
+            None => Ok(None),
+        }
+    }
+
+    /// Write the ledger watermark and flush the database to the disk. The caller must make sure
+    /// that no transaction is being added or removed, so that the flushed UTXO set is exactly the
+    /// one described by the watermark.
+    pub fn checkpoint(&self, watermark: &LedgerWatermark) -> Result<(), rocksdb::Error> {
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put(&LEDGER_WATERMARK_KEY, serialize(watermark).unwrap())?;
+        self.db.write_without_wal(batch)?;
+        self.flush()
+    }
+
+    /// Check whether the initial fund has been added, see `set_funded`.
+    pub fn funded(&self) -> Result<bool, rocksdb::Error> {
+        Ok(self.db.get_pinned(&FUNDED_KEY)?.is_some())
+    }
+
+    /// Record that the initial fund has been added, and flush the database, so that the marker is
+    /// never persisted without the coins of the fund.
+    pub fn set_funded(&self) -> Result<(), rocksdb::Error> {
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put(&FUNDED_KEY, &[])?;
+        self.db.write_without_wal(batch)?;
+        self.flush()
+    }
+
     pub fn flush(&self) -> Result<(), rocksdb::Error> {
         let mut flush_opt = rocksdb::FlushOptions::default();
         flush_opt.set_wait(true);
@@ -192,5 +502,190 @@ impl UtxoDatabase {
     }
 }
 
+/// Get the key of the record with the given prefix about the given transaction or block.
+fn record_key(prefix: u8, hash: &H256) -> Vec<u8> {
+    let mut key = Vec::with_capacity(RECORD_KEY_LENGTH);
+    key.push(prefix);
+    key.extend_from_slice(hash.as_ref());
+    key
+}
+
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::{Execution, LedgerPosition, RewardPosition, TransactionStatus, UtxoDatabase};
+    use crate::crypto::hash::H256;
+    use crate::transaction::{Address, Authorization, CoinId, Input, Output, Transaction};
+    use bincode::serialize;
+
+    #[test]
+    fn fee_reward_and_duplicate() {
+        let utxodb =
+            UtxoDatabase::new("/tmp/prism_test_utxodb_fee_reward_and_duplicate.rocksdb").unwrap();
+        let pubkey = vec![1, 2, 3];
+        let owner: Address = ring::digest::digest(&ring::digest::SHA256, &pubkey).into();
+        let miner: Address = [9u8; 32].into();
+        let alice: Address = [1u8; 32].into();
+
+        let coin = CoinId {
+            hash: [7u8; 32].into(),
+            index: 0,
+        };
+        let output = Output {
+            value: 100,
+            recipient: owner,
+        };
+        utxodb
+            .db
+            .put(serialize(&coin).unwrap(), serialize(&output).unwrap())
+            .unwrap();
+        let transaction = |value: u64| Transaction {
+            input: vec![Input {
+                coin,
+                value: 100,
+                owner,
+            }],
+            output: vec![Output {
+                value,
+                recipient: alice,
+            }],
+            authorization: vec![Authorization {
+                pubkey: pubkey.clone(),
+                signature: vec![],
+            }],
+            hash: Default::default(),
+        };
+        let hash: H256 = [8u8; 32].into();
+        let fee_coin = CoinId { hash, index: 1 };
+        let position = LedgerPosition {
+            level: 3,
+            reference: 0,
+            block: [6u8; 32].into(),
+            index: 0,
+        };
+
+        // a transaction must not spend more than its inputs
+        let (execution, added, removed) = utxodb
+            .add_transaction(&transaction(101), hash, position, miner)
+            .unwrap();
+        assert_eq!(execution, Execution::InvalidValue);
+        assert!(added.is_empty() && removed.is_empty());
+        assert!(utxodb.contains(&coin).unwrap());
+
+        // the rest of the inputs goes to the miner, and comes back on rollback
+        let (execution, added, removed) = utxodb
+            .add_transaction(&transaction(90), hash, position, miner)
+            .unwrap();
+        assert_eq!(execution, Execution::Applied);
+        assert_eq!(added.len(), 2);
+        assert_eq!(
+            added[1],
+            (
+                fee_coin,
+                Output {
+                    value: 10,
+                    recipient: miner
+                }
+            )
+        );
+        assert_eq!(removed, vec![coin]);
+        assert_eq!(utxodb.execution(&hash).unwrap(), Some(position));
+
+        // another copy of the transaction is skipped, and rolling it back does nothing. the copy
+        // may be in the same transaction block, referred to again on another level
+        let duplicate = LedgerPosition {
+            level: 4,
+            ..position
+        };
+        let (execution, added, _) = utxodb
+            .add_transaction(&transaction(90), hash, duplicate, miner)
+            .unwrap();
+        assert_eq!(execution, Execution::Duplicate);
+        assert!(added.is_empty());
+        assert_eq!(
+            utxodb.status(&hash).unwrap(),
+            vec![
+                TransactionStatus {
+                    position,
+                    execution: Execution::Applied
+                },
+                TransactionStatus {
+                    position: duplicate,
+                    execution: Execution::Duplicate
+                }
+            ]
+        );
+        let (added, removed) = utxodb
+            .remove_transaction(&transaction(90), hash, duplicate)
+            .unwrap();
+        assert!(added.is_empty() && removed.is_empty());
+        assert!(utxodb.contains(&fee_coin).unwrap());
+        assert_eq!(utxodb.status(&hash).unwrap().len(), 1);
+
+        let (added, removed) = utxodb
+            .remove_transaction(&transaction(90), hash, position)
+            .unwrap();
+        assert_eq!(added, vec![(coin, output)]);
+        assert_eq!(removed, vec![CoinId { hash, index: 0 }, fee_coin]);
+        assert!(!utxodb.contains(&fee_coin).unwrap());
+        assert_eq!(utxodb.execution(&hash).unwrap(), None);
+        assert!(utxodb.status(&hash).unwrap().is_empty());
+
+        // block rewards are identified by the block hash, and only paid once
+        let block: H256 = [5u8; 32].into();
+        let reward = Output {
+            value: 50,
+            recipient: miner,
+        };
+        let first = RewardPosition {
+            level: 3,
+            reference: 1,
+        };
+        let second = RewardPosition {
+            level: 4,
+            reference: 0,
+        };
+        let reward_coin = CoinId {
+            hash: block,
+            index: 0,
+        };
+        let (added, _) = utxodb.add_reward(block, reward, first).unwrap();
+        assert_eq!(added, vec![(reward_coin, reward)]);
+        let (added, _) = utxodb.add_reward(block, reward, second).unwrap();
+        assert!(added.is_empty());
+        let (_, removed) = utxodb.remove_reward(block, second).unwrap();
+        assert!(removed.is_empty());
+        assert!(utxodb.contains(&reward_coin).unwrap());
+
+        // replaying the ledger does not bring back a reward that has been spent
+        utxodb.db.delete(serialize(&reward_coin).unwrap()).unwrap();
+        let (added, _) = utxodb.add_reward(block, reward, first).unwrap();
+        assert!(added.is_empty());
+        assert!(!utxodb.contains(&reward_coin).unwrap());
+        utxodb
+            .db
+            .put(
+                serialize(&reward_coin).unwrap(),
+                serialize(&reward).unwrap(),
+            )
+            .unwrap();
+
+        let (_, removed) = utxodb.remove_reward(block, first).unwrap();
+        assert_eq!(removed, vec![reward_coin]);
+        assert_eq!(utxodb.reward(&block).unwrap(), None);
+        let (_, removed) = utxodb.remove_reward(block, first).unwrap();
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn funded_marker() {
+        let path = "/tmp/prism_test_utxodb_funded_marker.rocksdb";
+        let utxodb = UtxoDatabase::new(path).unwrap();
+        assert!(!utxodb.funded().unwrap());
+        let snapshot = utxodb.snapshot().unwrap();
+        utxodb.set_funded().unwrap();
+        assert_eq!(utxodb.snapshot().unwrap(), snapshot);
+        drop(utxodb);
+        let utxodb = UtxoDatabase::load(path).unwrap();
+        assert!(utxodb.funded().unwrap());
+    }
+}
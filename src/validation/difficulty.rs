@@ -0,0 +1,198 @@
+use crate::block::header::Header;
+use crate::blockchain::BlockChain;
+use crate::blockdb::BlockDatabase;
+use crate::config::{BlockchainConfig, DEFAULT_DIFFICULTY};
+use crate::crypto::hash::H256;
+use bigint::uint::U256;
+
+/// Maximum factor by which the difficulty changes in one adjustment.
+const MAX_ADJUSTMENT: u128 = 4;
+
+/// Get the difficulty of the blocks mined on the given proposer block.
+///
+/// The difficulty changes once every `config.difficulty_epoch` proposer levels, based on the
+/// timestamps of the proposer blocks in the last epoch. Since the sortition splits the blocks
+/// among the types by fixed ratios, bringing the proposer block rate to `proposer_mining_rate`
+/// brings the total block rate to the one implied by the config as well.
+pub fn expected_difficulty(
+    parent: &H256,
+    blockchain: &BlockChain,
+    blockdb: &BlockDatabase,
+    config: &BlockchainConfig,
+) -> H256 {
+    expected_difficulty_with(parent, config, |hash| {
+        stored_proposer(hash, blockchain, blockdb)
+    })
+    .unwrap()
+}
+
+/// Same as `expected_difficulty`, but looks up the header and the level of proposer blocks with
+/// the given function. Returns None if a proposer block the difficulty depends on is unknown.
+pub fn expected_difficulty_with<F>(
+    parent: &H256,
+    config: &BlockchainConfig,
+    lookup: F,
+) -> Option<H256>
+where
+    F: Fn(&H256) -> Option<(Header, u64)>,
+{
+    if *parent == config.proposer_genesis {
+        return Some(config.initial_difficulty);
+    }
+    let (parent_header, level) = lookup(parent)?;
+    let epoch = config.difficulty_epoch;
+    if epoch == 0 {
+        return Some(parent_header.difficulty);
+    }
+    // the difficulty only changes at the first level of each epoch
+    if (level + 1) % epoch != 0 {
+        return Some(parent_header.difficulty);
+    }
+
+    // the timestamp of the genesis block is not meaningful, so the first epoch is measured from
+    // the block at level 1
+    let start_level = std::cmp::max(level.saturating_sub(epoch), 1);
+    let intervals = level.saturating_sub(start_level);
+    if intervals == 0 {
+        return Some(parent_header.difficulty);
+    }
+    let mut start_header = parent_header;
+    for _ in 0..intervals {
+        start_header = lookup(&start_header.parent)?.0;
+    }
+    let actual = parent_header
+        .timestamp
+        .saturating_sub(start_header.timestamp);
+    let expected = (intervals as f64 * 1000.0 / f64::from(config.proposer_mining_rate)) as u128;
+    Some(retarget(&parent_header.difficulty, actual, expected))
+}
+
+/// Get the header and the level of a proposer block in the blockchain.
+pub fn stored_proposer(
+    hash: &H256,
+    blockchain: &BlockChain,
+    blockdb: &BlockDatabase,
+) -> Option<(Header, u64)> {
+    if !blockchain.contains_proposer(hash).unwrap() {
+        return None;
+    }
+    let block = blockdb.get(hash).unwrap()?;
+    Some((block.header, blockchain.proposer_level(hash).unwrap()))
+}
+
+/// Scale the difficulty by the ratio of the actual time of an epoch to the expected time, both in
+/// milliseconds. The change is bounded by `MAX_ADJUSTMENT` either way.
+fn retarget(difficulty: &H256, actual: u128, expected: u128) -> H256 {
+    let expected = std::cmp::max(expected, 1);
+    let actual = std::cmp::min(
+        std::cmp::max(actual, expected / MAX_ADJUSTMENT),
+        expected * MAX_ADJUSTMENT,
+    );
+    let difficulty = U256::from_big_endian(difficulty.as_ref());
+    let actual = U256::from(actual as u64);
+    let expected = U256::from(expected as u64);
+    // the difficulty is split so that the multiplication does not overflow unless the result does
+    let (high, overflow) = (difficulty / expected).overflowing_mul(actual);
+    let low = (difficulty % expected) * actual / expected;
+    let (new_difficulty, carry) = high.overflowing_add(low);
+    if overflow || carry {
+        return *DEFAULT_DIFFICULTY;
+    }
+    let mut raw: [u8; 32] = [0; 32];
+    new_difficulty.to_big_endian(&mut raw);
+    raw.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expected_difficulty, retarget};
+    use crate::block::tests::proposer_block;
+    use crate::blockchain::BlockChain;
+    use crate::blockdb::BlockDatabase;
+    use crate::config::{BlockchainConfig, DEFAULT_DIFFICULTY};
+    use crate::crypto::hash::{Hashable, H256};
+    use crate::validation::{check_difficulty, BlockResult};
+
+    fn difficulty(value: u64) -> H256 {
+        let mut raw: [u8; 32] = [0; 32];
+        raw[24..32].copy_from_slice(&value.to_be_bytes());
+        raw.into()
+    }
+
+    #[test]
+    fn bounded_adjustment() {
+        // blocks came twice as fast as expected, so they should be twice as hard
+        assert_eq!(retarget(&difficulty(1000), 500, 1000), difficulty(500));
+        assert_eq!(retarget(&difficulty(1000), 3000, 1000), difficulty(3000));
+        // the change is bounded
+        assert_eq!(retarget(&difficulty(1000), 1, 1000), difficulty(250));
+        assert_eq!(retarget(&difficulty(1000), 100_000, 1000), difficulty(4000));
+        // the difficulty never exceeds the maximum
+        assert_eq!(
+            retarget(&DEFAULT_DIFFICULTY, 2000, 1000),
+            *DEFAULT_DIFFICULTY
+        );
+        assert_eq!(
+            retarget(&DEFAULT_DIFFICULTY, 1000, 1000),
+            *DEFAULT_DIFFICULTY
+        );
+    }
+
+    #[test]
+    fn epoch_boundary() {
+        let mut config = BlockchainConfig::new(10, 64000, 80000, 0.1, 0.1, 0.4, 20.0);
+        config.difficulty_epoch = 4;
+        let blockdb = BlockDatabase::new(
+            "/tmp/prism_test_validation_difficulty_blockdb.rocksdb",
+            config.clone(),
+        )
+        .unwrap();
+        let blockchain = BlockChain::new(
+            "/tmp/prism_test_validation_difficulty_blockchain.rocksdb",
+            config.clone(),
+        )
+        .unwrap();
+
+        // proposer blocks on levels 1 to 3, mined twice as fast as the config expects
+        let mut parent = config.proposer_genesis;
+        for level in 1..=3 {
+            assert_eq!(
+                expected_difficulty(&parent, &blockchain, &blockdb, &config),
+                *DEFAULT_DIFFICULTY
+            );
+            let block = proposer_block(parent, level * 5000, vec![], vec![]);
+            blockdb.insert(&block).unwrap();
+            blockchain.insert_block(&block).unwrap();
+            parent = block.hash();
+        }
+
+        // level 4 starts a new epoch, measured from level 1 since the genesis has no real time
+        let expected = (2.0 * 1000.0 / f64::from(config.proposer_mining_rate)) as u128;
+        let retargeted = retarget(&DEFAULT_DIFFICULTY, 10000, expected);
+        assert!(retargeted < *DEFAULT_DIFFICULTY);
+        assert_eq!(
+            expected_difficulty(&parent, &blockchain, &blockdb, &config),
+            retargeted
+        );
+
+        let block = proposer_block(parent, 20000, vec![], vec![]);
+        assert!(matches!(
+            check_difficulty(&block, &blockchain, &blockdb, &config),
+            BlockResult::WrongDifficulty
+        ));
+        let mut block = proposer_block(parent, 20000, vec![], vec![]);
+        block.header.difficulty = retargeted;
+        assert!(matches!(
+            check_difficulty(&block, &blockchain, &blockdb, &config),
+            BlockResult::Pass
+        ));
+
+        // the rest of the epoch keeps the new difficulty
+        blockdb.insert(&block).unwrap();
+        blockchain.insert_block(&block).unwrap();
+        assert_eq!(
+            expected_difficulty(&block.hash(), &blockchain, &blockdb, &config),
+            retargeted
+        );
+    }
+}
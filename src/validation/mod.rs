@@ -1,14 +1,24 @@
+mod difficulty;
 mod proposer_block;
+mod timestamp;
 mod transaction;
 mod voter_block;
-use crate::block::{Block, Content};
+use crate::block::header::Header;
+use crate::block::{Block, Content, ProvenHeader};
 use crate::blockchain::BlockChain;
 use crate::blockdb::BlockDatabase;
 use crate::config::*;
 use crate::crypto::hash::{Hashable, H256};
 use crate::crypto::merkle::verify;
+use crate::miner::memory_pool::MemoryPool;
+use crate::transaction::{Address, Transaction};
+use crate::utxodb::UtxoDatabase;
+use std::collections::{HashMap, HashSet};
 extern crate bigint;
 
+pub use difficulty::expected_difficulty;
+pub use timestamp::MAX_FUTURE_TIME;
+
 /// The result of block validation.
 #[derive(Debug)]
 pub enum BlockResult {
@@ -20,6 +30,13 @@ pub enum BlockResult {
     WrongSortitionId,
     /// The content Merkle proof is incorrect.
     WrongSortitionProof,
+    /// The difficulty is not the one expected from the parent.
+    WrongDifficulty,
+    /// The timestamp is earlier than the median of the recent proposer blocks.
+    WrongTimestamp,
+    /// The timestamp is too far ahead of the local time. Unlike the other results, the block may
+    /// pass later.
+    FutureTimestamp,
     /// Some references are missing.
     MissingReferences(Vec<H256>),
     /// Proposer Ref level > parent
@@ -41,6 +58,9 @@ impl std::fmt::Display for BlockResult {
             BlockResult::WrongPoW => write!(f, "PoW larger than difficulty"),
             BlockResult::WrongSortitionId => write!(f, "Sortition id is not same as content type"),
             BlockResult::WrongSortitionProof => write!(f, "Sortition Merkle proof is incorrect"),
+            BlockResult::WrongDifficulty => write!(f, "difficulty does not match the parent"),
+            BlockResult::WrongTimestamp => write!(f, "timestamp earlier than recent blocks"),
+            BlockResult::FutureTimestamp => write!(f, "timestamp too far in the future"),
             BlockResult::MissingReferences(_) => write!(f, "referred blocks not in system"),
             BlockResult::WrongProposerRef => {
                 write!(f, "referred proposer blocks level larger than parent")
@@ -57,6 +77,103 @@ impl std::fmt::Display for BlockResult {
     }
 }
 
+/// The result of validating a transaction before it enters the memory pool.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionResult {
+    /// The validation passes.
+    Pass,
+    /// The transaction is already in the memory pool.
+    Duplicate,
+    /// An input is spent by another transaction in the memory pool.
+    DoubleSpend,
+    EmptyTransaction,
+    ZeroValue,
+    InsufficientInput,
+    /// An input coin is neither unspent nor produced by a transaction in the memory pool.
+    MissingInput,
+    /// The value or the owner of an input does not match the coin.
+    WrongInput,
+    /// The signers are not exactly the owners of the inputs.
+    WrongAuthorization,
+    WrongSignature,
+    /// The memory pool is full, and the transaction does not pay enough to replace others.
+    MempoolFull,
+}
+
+impl std::fmt::Display for TransactionResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransactionResult::Pass => write!(f, "validation passed"),
+            TransactionResult::Duplicate => write!(f, "already in the memory pool"),
+            TransactionResult::DoubleSpend => write!(f, "input spent in the memory pool"),
+            TransactionResult::EmptyTransaction => write!(f, "empty transaction input or output"),
+            TransactionResult::ZeroValue => {
+                write!(f, "transaction input or output value contains a zero")
+            }
+            TransactionResult::InsufficientInput => write!(f, "insufficient input"),
+            TransactionResult::MissingInput => write!(f, "input coin does not exist"),
+            TransactionResult::WrongInput => write!(f, "input value or owner mismatch"),
+            TransactionResult::WrongAuthorization => write!(f, "signers are not the owners"),
+            TransactionResult::WrongSignature => write!(f, "signature mismatch"),
+            TransactionResult::MempoolFull => write!(f, "memory pool full"),
+        }
+    }
+}
+
+/// Check the parts of a transaction that do not depend on the ledger, including the signatures.
+pub fn check_transaction(transaction: &Transaction) -> TransactionResult {
+    if !transaction::check_non_empty(&transaction) {
+        return TransactionResult::EmptyTransaction;
+    }
+    if !transaction::check_non_zero(&transaction) {
+        return TransactionResult::ZeroValue;
+    }
+    if !transaction::check_sufficient_input(&transaction) {
+        return TransactionResult::InsufficientInput;
+    }
+    if !transaction::check_signature(&transaction) {
+        return TransactionResult::WrongSignature;
+    }
+    TransactionResult::Pass
+}
+
+/// Check that the inputs of a transaction are unspent coins, or outputs of transactions in the
+/// memory pool, that they are not spent by other transactions in the memory pool, and that the
+/// transaction is authorized by their owners.
+pub fn check_transaction_inputs(
+    transaction: &Transaction,
+    utxodb: &UtxoDatabase,
+    mempool: &MemoryPool,
+) -> TransactionResult {
+    if mempool.contains(&transaction.hash()) {
+        return TransactionResult::Duplicate;
+    }
+    if mempool.is_double_spend(&transaction.input) {
+        return TransactionResult::DoubleSpend;
+    }
+    let mut owners: HashSet<Address> = HashSet::new();
+    for input in &transaction.input {
+        let coin = match utxodb.get(&input.coin).unwrap() {
+            Some(output) => output,
+            None => match mempool
+                .get(&input.coin.hash)
+                .and_then(|e| e.transaction.output.get(input.coin.index as usize))
+            {
+                Some(output) => *output,
+                None => return TransactionResult::MissingInput,
+            },
+        };
+        if coin.value != input.value || coin.recipient != input.owner {
+            return TransactionResult::WrongInput;
+        }
+        owners.insert(coin.recipient);
+    }
+    if !transaction::check_authorization(&transaction, &owners) {
+        return TransactionResult::WrongAuthorization;
+    }
+    TransactionResult::Pass
+}
+
 // check PoW and sortition id
 pub fn check_pow_sortition_id(block: &Block, config: &BlockchainConfig) -> BlockResult {
     let sortition_id = config.sortition_hash(&block.hash(), &block.header.difficulty);
@@ -93,6 +210,71 @@ pub fn check_sortition_proof(block: &Block, config: &BlockchainConfig) -> BlockR
     }
     BlockResult::Pass
 }
+/// Check the PoW and the sortition proof of a block given its header, before downloading its
+/// content. The sortition id decides the type of the block, which is checked against the content
+/// once it arrives.
+pub fn check_header(header: &ProvenHeader, config: &BlockchainConfig) -> BlockResult {
+    let sortition_id = match config.sortition_hash(&header.hash(), &header.header.difficulty) {
+        Some(sortition_id) => sortition_id,
+        None => return BlockResult::WrongPoW,
+    };
+    if !verify(
+        &header.header.content_merkle_root,
+        &header.content_hash,
+        &header.sortition_proof,
+        sortition_id as usize,
+        (config.voter_chains + FIRST_VOTER_INDEX) as usize,
+    ) {
+        return BlockResult::WrongSortitionProof;
+    }
+    BlockResult::Pass
+}
+
+/// Validate a batch of headers received during sync, in order, before downloading any content.
+/// Besides passing `check_header`, each header must carry the difficulty expected from its
+/// parent, which is either in the blockchain or earlier in the batch. If the proposer blocks the
+/// difficulty depends on are unknown (e.g. their content was not received in time), the check is
+/// left to `check_difficulty` once the block arrives.
+pub fn check_headers(
+    headers: &[ProvenHeader],
+    blockchain: &BlockChain,
+    blockdb: &BlockDatabase,
+    config: &BlockchainConfig,
+) -> Result<Vec<H256>, BlockResult> {
+    // the proposer headers of the batch and their levels
+    let mut skeleton: HashMap<H256, (Header, u64)> = HashMap::new();
+    let mut hashes = Vec::with_capacity(headers.len());
+    for header in headers {
+        match check_header(header, config) {
+            BlockResult::Pass => {}
+            result => return Err(result),
+        }
+        let hash = header.hash();
+        let parent = &header.header.parent;
+        let lookup = |hash: &H256| match skeleton.get(hash) {
+            Some(proposer) => Some(*proposer),
+            None => difficulty::stored_proposer(hash, blockchain, blockdb),
+        };
+        if let Some(expected) = difficulty::expected_difficulty_with(parent, config, &lookup) {
+            if header.header.difficulty != expected {
+                return Err(BlockResult::WrongDifficulty);
+            }
+        }
+        if config.sortition_hash(&hash, &header.header.difficulty) == Some(PROPOSER_INDEX) {
+            let parent_level = if *parent == config.proposer_genesis {
+                Some(0)
+            } else {
+                lookup(parent).map(|(_, level)| level)
+            };
+            if let Some(parent_level) = parent_level {
+                skeleton.insert(hash, (header.header, parent_level + 1));
+            }
+        }
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
+
 /// Validate a block that already passes pow and sortition test. See if parents/refs are missing.
 pub fn check_data_availability(
     block: &Block,
@@ -138,6 +320,39 @@ pub fn check_data_availability(
     }
 }
 
+/// Check that the difficulty of a block is the one expected from its parent. The parent must be
+/// available.
+pub fn check_difficulty(
+    block: &Block,
+    blockchain: &BlockChain,
+    blockdb: &BlockDatabase,
+    config: &BlockchainConfig,
+) -> BlockResult {
+    let expected = expected_difficulty(&block.header.parent, blockchain, blockdb, config);
+    if block.header.difficulty != expected {
+        return BlockResult::WrongDifficulty;
+    }
+    BlockResult::Pass
+}
+
+/// Check that the timestamp of a block is not too far ahead of the local time, and not earlier
+/// than the median timestamp of the recent proposer blocks up to its parent. Times are UNIX
+/// timestamps in milliseconds. The parent must be available.
+pub fn check_timestamp(
+    block: &Block,
+    blockdb: &BlockDatabase,
+    config: &BlockchainConfig,
+    now: u128,
+) -> BlockResult {
+    if block.header.timestamp > now + timestamp::MAX_FUTURE_TIME {
+        return BlockResult::FutureTimestamp;
+    }
+    if block.header.timestamp < timestamp::median_time_past(&block.header.parent, blockdb, config) {
+        return BlockResult::WrongTimestamp;
+    }
+    BlockResult::Pass
+}
+
 /// Check block content semantic
 pub fn check_content_semantic(
     block: &Block,
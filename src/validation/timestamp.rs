@@ -0,0 +1,76 @@
+use crate::blockdb::BlockDatabase;
+use crate::config::BlockchainConfig;
+use crate::crypto::hash::H256;
+
+/// How far the timestamp of a block may be ahead of the local time, in milliseconds.
+pub const MAX_FUTURE_TIME: u128 = 15_000;
+/// Number of recent proposer blocks whose median timestamp a new block must not precede.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Get the median timestamp of the given proposer block and its closest ancestors.
+pub fn median_time_past(parent: &H256, blockdb: &BlockDatabase, config: &BlockchainConfig) -> u128 {
+    let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+    let mut hash = *parent;
+    loop {
+        let block = blockdb.get(&hash).unwrap().unwrap();
+        timestamps.push(block.header.timestamp);
+        if timestamps.len() == MEDIAN_TIME_SPAN || hash == config.proposer_genesis {
+            break;
+        }
+        hash = block.header.parent;
+    }
+    timestamps.sort();
+    timestamps[timestamps.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{median_time_past, MAX_FUTURE_TIME, MEDIAN_TIME_SPAN};
+    use crate::block::tests::proposer_block;
+    use crate::blockdb::BlockDatabase;
+    use crate::config::BlockchainConfig;
+    use crate::crypto::hash::Hashable;
+    use crate::validation::{check_timestamp, BlockResult};
+
+    #[test]
+    fn median_and_future_limit() {
+        let config = BlockchainConfig::new(10, 64000, 80000, 0.1, 0.1, 0.4, 20.0);
+        let blockdb = BlockDatabase::new(
+            "/tmp/prism_test_validation_timestamp.rocksdb",
+            config.clone(),
+        )
+        .unwrap();
+
+        // a chain of proposer blocks, one of which claims a time far ahead of the others
+        let mut parent = config.proposer_genesis;
+        for i in 1..=MEDIAN_TIME_SPAN as u128 + 4 {
+            let timestamp = if i == 10 { 1_000_000 } else { i * 1000 };
+            let block = proposer_block(parent, timestamp, vec![], vec![]);
+            blockdb.insert(&block).unwrap();
+            parent = block.hash();
+        }
+        // the median of the last 11 timestamps, which the outlier hardly moves
+        assert_eq!(median_time_past(&parent, &blockdb, &config), 11000);
+
+        let now = 20000;
+        let block = proposer_block(parent, 11000, vec![], vec![]);
+        assert!(matches!(
+            check_timestamp(&block, &blockdb, &config, now),
+            BlockResult::Pass
+        ));
+        let block = proposer_block(parent, 10999, vec![], vec![]);
+        assert!(matches!(
+            check_timestamp(&block, &blockdb, &config, now),
+            BlockResult::WrongTimestamp
+        ));
+        let block = proposer_block(parent, now + MAX_FUTURE_TIME + 1, vec![], vec![]);
+        assert!(matches!(
+            check_timestamp(&block, &blockdb, &config, now),
+            BlockResult::FutureTimestamp
+        ));
+
+        // near the genesis block, there are fewer ancestors to take the median of
+        let block = proposer_block(config.proposer_genesis, 0, vec![], vec![]);
+        assert_eq!(median_time_past(&block.header.parent, &blockdb, &config), 0);
+    }
+}
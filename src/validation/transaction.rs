@@ -1,7 +1,8 @@
-use crate::transaction::Transaction;
+use crate::transaction::{Address, Transaction};
 
 use ed25519_dalek::PublicKey;
 use ed25519_dalek::Signature;
+use std::collections::HashSet;
 
 /// Checks that input and output are non-empty
 pub fn check_non_empty(transaction: &Transaction) -> bool {
@@ -16,9 +17,36 @@ pub fn check_non_zero(transaction: &Transaction) -> bool {
 
 /// Checks if input_sum >= output_sum
 pub fn check_sufficient_input(transaction: &Transaction) -> bool {
-    let input_sum: u64 = transaction.input.iter().map(|x| x.value).sum();
-    let output_sum: u64 = transaction.output.iter().map(|x| x.value).sum();
-    input_sum >= output_sum
+    transaction.fee().is_some()
+}
+
+/// Checks that the transaction is signed by exactly the owners of the inputs
+pub fn check_authorization(transaction: &Transaction, owners: &HashSet<Address>) -> bool {
+    let signed_users: HashSet<Address> = transaction
+        .authorization
+        .iter()
+        .map(|x| ring::digest::digest(&ring::digest::SHA256, &x.pubkey).into())
+        .collect();
+    signed_users == *owners
+}
+
+/// Checks the signatures of a single transaction. Unlike check_signature_batch, malformed keys
+/// and signatures fail the check instead of panicking, since the transaction may come from anyone.
+pub fn check_signature(transaction: &Transaction) -> bool {
+    let raw_inputs = bincode::serialize(&transaction.input).unwrap();
+    let raw_outputs = bincode::serialize(&transaction.output).unwrap();
+    let raw = [&raw_inputs[..], &raw_outputs[..]].concat();
+    transaction.authorization.iter().all(|a| {
+        let public_key = match PublicKey::from_bytes(&a.pubkey) {
+            Ok(k) => k,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_bytes(&a.signature) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        public_key.verify(&raw, &signature).is_ok()
+    })
 }
 
 pub fn check_signature_batch(transactions: &[Transaction]) -> bool {
@@ -4,7 +4,8 @@ use ed25519_dalek::Keypair;
 use rand::rngs::OsRng;
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
 
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
@@ -77,6 +78,33 @@ impl Wallet {
         Self::open(path)
     }
 
+    /// Open the existing wallet at the given path, and load the key pairs and the number of coins
+    /// from the database.
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let wallet = Self::open(path)?;
+        let keypair_cf = wallet.db.cf_handle(KEYPAIR_CF).unwrap();
+        let coin_cf = wallet.db.cf_handle(COIN_CF).unwrap();
+
+        let mut keypairs = wallet.keypairs.lock().unwrap();
+        for (k, v) in wallet
+            .db
+            .iterator_cf(keypair_cf, rocksdb::IteratorMode::Start)?
+        {
+            let addr_bytes: [u8; 32] = (&k[0..32]).try_into().unwrap();
+            let addr: Address = addr_bytes.into();
+            let keypair = Keypair::from_bytes(&v).unwrap();
+            keypairs.insert(addr, keypair);
+        }
+        drop(keypairs);
+
+        let num_coins = wallet
+            .db
+            .iterator_cf(coin_cf, rocksdb::IteratorMode::Start)?
+            .count();
+        wallet.counter.store(num_coins, Ordering::Relaxed);
+        Ok(wallet)
+    }
+
     pub fn number_of_coins(&self) -> usize {
         self.counter.load(Ordering::Relaxed)
     }
@@ -114,22 +142,48 @@ impl Wallet {
         false
     }
 
+    /// Add the given coins that we own, and remove the given coins. Coins that are already in the
+    /// wallet are not added again, so that replaying a part of the ledger does not count them
+    /// twice.
     pub fn apply_diff(&self, add: &[(CoinId, Output)], remove: &[CoinId]) -> Result<()> {
         let mut batch = rocksdb::WriteBatch::default();
         let cf = self.db.cf_handle(COIN_CF).unwrap();
+        let mut added: HashSet<CoinId> = HashSet::new();
         for coin in add {
             if self.contains_keypair(&coin.1.recipient) {
                 let key = serialize(&coin.0).unwrap();
+                if added.contains(&coin.0) || self.db.get_pinned_cf(cf, &key)?.is_some() {
+                    continue;
+                }
                 let val = serialize(&coin.1).unwrap();
                 batch.put_cf(cf, &key, &val)?;
-                self.counter.fetch_add(1, Ordering::Relaxed);
+                added.insert(coin.0);
             }
         }
+        let mut num_removed = 0;
         for coin in remove {
             let key = serialize(&coin).unwrap();
+            if added.remove(coin) || self.db.get_pinned_cf(cf, &key)?.is_some() {
+                num_removed += 1;
+            }
             batch.delete_cf(cf, &key)?;
         }
         self.db.write(batch)?;
+        self.counter.fetch_add(added.len(), Ordering::Relaxed);
+        self.counter.fetch_sub(num_removed, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remove all coins, but keep the key pairs, e.g. before the coins are rebuilt from the
+    /// ledger.
+    pub fn clear_coins(&self) -> Result<()> {
+        let cf = self.db.cf_handle(COIN_CF).unwrap();
+        let mut batch = rocksdb::WriteBatch::default();
+        for (k, _) in self.db.iterator_cf(cf, rocksdb::IteratorMode::Start)? {
+            batch.delete_cf(cf, &k)?;
+        }
+        self.db.write(batch)?;
+        self.counter.store(0, Ordering::Relaxed);
         Ok(())
     }
 
@@ -227,8 +281,6 @@ impl Wallet {
             }
             drop(keypairs);
         }
-        self.counter
-            .fetch_sub(unsigned.input.len(), Ordering::Relaxed);
         Ok(Transaction {
             authorization,
             ..unsigned